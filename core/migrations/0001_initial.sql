@@ -0,0 +1,4 @@
+-- Tables as they were before migrations existed, so old databases are picked up as version 1
+CREATE TABLE IF NOT EXISTS log (id INTEGER PRIMARY KEY AUTOINCREMENT, timestamp DATETIME, redirect TEXT, url TEXT, ip TEXT);
+CREATE TABLE IF NOT EXISTS redirects (id INTEGER PRIMARY KEY AUTOINCREMENT, url TEXT, redirect TEXT, comment TEXT);
+CREATE TABLE IF NOT EXISTS tokens (id INTEGER PRIMARY KEY AUTOINCREMENT, token TEXT, expiration DATETIME);
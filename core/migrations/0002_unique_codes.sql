@@ -0,0 +1,4 @@
+-- Codes could be added twice before this. Visitors always got the first one, so only it is kept.
+DELETE FROM redirects
+WHERE redirect IS NOT NULL AND id NOT IN (SELECT MIN(id) FROM redirects GROUP BY redirect);
+CREATE UNIQUE INDEX IF NOT EXISTS redirects_redirect ON redirects (redirect);
@@ -0,0 +1,2 @@
+-- RFC 3339 time after which a link stops redirecting, NULL for never
+ALTER TABLE redirects ADD COLUMN expires_at TEXT;
@@ -0,0 +1,3 @@
+-- How many times a link can be followed, NULL for no limit, and how many times it has been
+ALTER TABLE redirects ADD COLUMN max_visits INTEGER;
+ALTER TABLE redirects ADD COLUMN uses INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- RFC 3339 time before which a link doesn't redirect yet, NULL for right away
+ALTER TABLE redirects ADD COLUMN not_before TEXT;
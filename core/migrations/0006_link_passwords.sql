@@ -0,0 +1,4 @@
+-- Salted hash of a link's password, NULL if it doesn't have one
+ALTER TABLE redirects ADD COLUMN password_hash TEXT;
+-- Wrong passwords per link and IP, counted from window_start
+CREATE TABLE IF NOT EXISTS password_attempts (redirect TEXT NOT NULL, ip TEXT NOT NULL, count INTEGER NOT NULL, window_start TEXT NOT NULL, PRIMARY KEY (redirect, ip));
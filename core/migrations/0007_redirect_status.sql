@@ -0,0 +1,2 @@
+-- HTTP status a link redirects with, NULL for the server default
+ALTER TABLE redirects ADD COLUMN redirect_status INTEGER;
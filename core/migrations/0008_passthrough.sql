@@ -0,0 +1,3 @@
+-- Whether a link passes the visitor's query string and extra path on to its destination
+ALTER TABLE redirects ADD COLUMN forward_query INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE redirects ADD COLUMN forward_path INTEGER NOT NULL DEFAULT 0;
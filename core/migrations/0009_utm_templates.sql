@@ -0,0 +1,5 @@
+-- Named UTM parameters added to a link's destination as it redirects
+CREATE TABLE IF NOT EXISTS utm_templates (name TEXT PRIMARY KEY, source TEXT, medium TEXT, campaign TEXT, term TEXT, content TEXT);
+ALTER TABLE redirects ADD COLUMN utm_template TEXT;
+-- The template a visit was tagged with, so visits can be broken down by template
+ALTER TABLE log ADD COLUMN utm_template TEXT;
@@ -0,0 +1,7 @@
+-- Links that split their visitors between several destinations by weight
+CREATE TABLE IF NOT EXISTS destinations (id INTEGER PRIMARY KEY AUTOINCREMENT, redirect TEXT NOT NULL, url TEXT NOT NULL, weight INTEGER NOT NULL);
+CREATE INDEX IF NOT EXISTS destinations_redirect ON destinations (redirect);
+-- Whether a visitor keeps getting the same destination
+ALTER TABLE redirects ADD COLUMN sticky INTEGER NOT NULL DEFAULT 0;
+-- The destination a visit was sent to, NULL for links with just a url
+ALTER TABLE log ADD COLUMN destination INTEGER;
@@ -0,0 +1,2 @@
+-- Ordered device, browser and language rules as JSON, NULL if the link has none
+ALTER TABLE redirects ADD COLUMN targeting TEXT;
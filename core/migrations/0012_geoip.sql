@@ -0,0 +1,6 @@
+-- Where each visit came from, NULL when it couldn't be looked up
+ALTER TABLE log ADD COLUMN country TEXT;
+ALTER TABLE log ADD COLUMN region TEXT;
+ALTER TABLE log ADD COLUMN city TEXT;
+ALTER TABLE log ADD COLUMN asn INTEGER;
+CREATE INDEX IF NOT EXISTS log_country ON log (country);
@@ -0,0 +1,9 @@
+-- What each visitor's request said about them, NULL when a header was missing
+ALTER TABLE log ADD COLUMN referrer TEXT;
+ALTER TABLE log ADD COLUMN user_agent TEXT;
+ALTER TABLE log ADD COLUMN accept_language TEXT;
+ALTER TABLE log ADD COLUMN host TEXT;
+-- Parsed from user_agent
+ALTER TABLE log ADD COLUMN browser TEXT;
+ALTER TABLE log ADD COLUMN os TEXT;
+ALTER TABLE log ADD COLUMN device TEXT;
@@ -0,0 +1,3 @@
+-- human, bot or preview. Visits logged before this are assumed to be people.
+ALTER TABLE log ADD COLUMN kind TEXT NOT NULL DEFAULT 'human';
+CREATE INDEX IF NOT EXISTS log_redirect_kind ON log (redirect, kind);
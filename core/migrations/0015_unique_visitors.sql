@@ -0,0 +1,4 @@
+-- Daily hash of the visitor's IP and User-Agent, and the UTC day it was made on
+ALTER TABLE log ADD COLUMN visitor TEXT;
+ALTER TABLE log ADD COLUMN day TEXT;
+CREATE INDEX IF NOT EXISTS log_redirect_day ON log (redirect, day);
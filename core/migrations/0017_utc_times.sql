@@ -0,0 +1,6 @@
+-- Token expirations were written with the server's UTC offset, they're stored in UTC like everything else now
+UPDATE tokens SET expiration = strftime('%Y-%m-%dT%H:%M:%SZ', expiration)
+WHERE strftime('%Y-%m-%dT%H:%M:%SZ', expiration) IS NOT NULL;
+-- When a link was made and last edited, left NULL for links made before they were kept
+ALTER TABLE redirects ADD COLUMN created_at TEXT;
+ALTER TABLE redirects ADD COLUMN updated_at TEXT;
@@ -0,0 +1,2 @@
+-- The random salt fingerprints are made with, only the current UTC day's is kept
+CREATE TABLE IF NOT EXISTS visitor_salts (day TEXT PRIMARY KEY, salt TEXT NOT NULL);
@@ -0,0 +1,478 @@
+// Jackson Coxson
+// Just enough User-Agent and Accept-Language parsing to tell visitors apart.
+// Checks run in order because every browser claims to be a few others.
+
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Headers are cut to this many bytes before they're logged
+const MAX_HEADER_LENGTH: usize = 1024;
+
+/// as_str and FromStr with the same names serde uses, for storing the enums as text
+macro_rules! names {
+    ($name:ident { $($variant:ident => $text:literal),* $(,)? }) => {
+        impl $name {
+            pub fn as_str(self) -> &'static str {
+                match self {
+                    $(Self::$variant => $text,)*
+                }
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ();
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                match s {
+                    $($text => Ok(Self::$variant),)*
+                    _ => Err(()),
+                }
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Os {
+    Ios,
+    Android,
+    Windows,
+    Macos,
+    Linux,
+    ChromeOs,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Browser {
+    Chrome,
+    Safari,
+    Firefox,
+    Edge,
+    Opera,
+    SamsungInternet,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Device {
+    Desktop,
+    Mobile,
+    Tablet,
+    Other,
+}
+
+names!(Os {
+    Ios => "ios",
+    Android => "android",
+    Windows => "windows",
+    Macos => "macos",
+    Linux => "linux",
+    ChromeOs => "chrome_os",
+    Other => "other",
+});
+
+names!(Browser {
+    Chrome => "chrome",
+    Safari => "safari",
+    Firefox => "firefox",
+    Edge => "edge",
+    Opera => "opera",
+    SamsungInternet => "samsung_internet",
+    Other => "other",
+});
+
+names!(Device {
+    Desktop => "desktop",
+    Mobile => "mobile",
+    Tablet => "tablet",
+    Other => "other",
+});
+
+/// Who followed a link. Bots and previews are logged but left out of visits unless asked for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClickKind {
+    #[default]
+    Human,
+    /// Crawlers, scripts, link checkers and anything without a User-Agent
+    Bot,
+    /// Chat apps unfurling a link and browsers prefetching it
+    Preview,
+}
+
+names!(ClickKind {
+    Human => "human",
+    Bot => "bot",
+    Preview => "preview",
+});
+
+/// Headers browsers send when they fetch a page before anyone clicked it
+pub const PREFETCH_HEADERS: &[&str] = &["sec-purpose", "purpose", "x-purpose", "x-moz"];
+
+/// Apps that fetch links to show a preview of them, checked before BOT_PATTERNS
+const PREVIEW_PATTERNS: &[&str] = &[
+    "slackbot-linkexpanding",
+    "slack-imgproxy",
+    "discordbot",
+    "twitterbot",
+    "facebookexternalhit",
+    "facebot",
+    "linkedinbot",
+    "whatsapp",
+    "telegrambot",
+    "skypeuripreview",
+    "redditbot",
+    "mastodon",
+    "embedly",
+    "iframely",
+    "preview",
+];
+
+/// Markers only crawlers and scripts put in their User-Agent, along with a "bot" word
+const BOT_PATTERNS: &[&str] = &[
+    "crawl",
+    "spider",
+    "slurp",
+    "headless",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "go-http-client",
+];
+
+/// "bot" as a word or ending a name like Googlebot/2.1, but not inside one like Cubot
+fn names_a_bot(ua: &str) -> bool {
+    ua.match_indices("bot").any(|(i, _)| {
+        let before = ua[..i].chars().next_back();
+        let after = ua[i + 3..].chars().next();
+        let word = |c: Option<char>| c.is_some_and(|c| c.is_ascii_alphanumeric());
+        matches!(after, Some('/' | ';')) || (!word(before) && !word(after))
+    })
+}
+
+/// What a User-Agent header says about the visitor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserAgent {
+    pub os: Os,
+    pub browser: Browser,
+    pub device: Device,
+    /// Crawlers, link previews and scripts, including anything without a User-Agent
+    pub bot: bool,
+}
+
+impl UserAgent {
+    pub fn parse(header: &str) -> Self {
+        let ua = header.to_ascii_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|p| ua.contains(p));
+
+        let os = if has(&["iphone", "ipad", "ipod"]) {
+            Os::Ios
+        } else if has(&["android"]) {
+            Os::Android
+        } else if has(&["cros"]) {
+            Os::ChromeOs
+        } else if has(&["windows"]) {
+            Os::Windows
+        } else if has(&["mac os x", "macintosh"]) {
+            Os::Macos
+        } else if has(&["linux"]) {
+            Os::Linux
+        } else {
+            Os::Other
+        };
+
+        let browser = if has(&["edg/", "edge/", "edga/", "edgios/"]) {
+            Browser::Edge
+        } else if has(&["opr/", "opera"]) {
+            Browser::Opera
+        } else if has(&["samsungbrowser"]) {
+            Browser::SamsungInternet
+        } else if has(&["firefox/", "fxios/"]) {
+            Browser::Firefox
+        } else if has(&["chrome/", "crios/", "chromium/"]) {
+            Browser::Chrome
+        } else if has(&["safari/"]) {
+            Browser::Safari
+        } else {
+            Browser::Other
+        };
+
+        // Android tablets leave Mobile out of their User-Agent
+        let device = if has(&["ipad", "tablet"]) || (os == Os::Android && !has(&["mobile"])) {
+            Device::Tablet
+        } else if has(&["mobi", "iphone", "ipod"]) || os == Os::Android {
+            Device::Mobile
+        } else if matches!(os, Os::Windows | Os::Macos | Os::Linux | Os::ChromeOs) {
+            Device::Desktop
+        } else {
+            Device::Other
+        };
+
+        Self {
+            os,
+            browser,
+            device,
+            bot: ua.trim().is_empty()
+                || has(PREVIEW_PATTERNS)
+                || has(BOT_PATTERNS)
+                || names_a_bot(&ua),
+        }
+    }
+}
+
+/// Sorts a click by its method, User-Agent and the values of any PREFETCH_HEADERS it had
+pub fn classify<'a>(
+    head: bool,
+    user_agent: Option<&str>,
+    purposes: impl IntoIterator<Item = &'a str>,
+) -> ClickKind {
+    let ua = user_agent.unwrap_or_default().to_ascii_lowercase();
+    if PREVIEW_PATTERNS.iter().any(|p| ua.contains(p)) {
+        return ClickKind::Preview;
+    }
+    let prefetch = purposes.into_iter().any(|purpose| {
+        let purpose = purpose.to_ascii_lowercase();
+        purpose.contains("prefetch") || purpose.contains("preview")
+    });
+    if prefetch {
+        ClickKind::Preview
+    } else if head || UserAgent::parse(&ua).bot {
+        // People don't send HEAD requests, link checkers do
+        ClickKind::Bot
+    } else {
+        ClickKind::Human
+    }
+}
+
+/// What a visitor's request said about them, kept with every logged visit
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub accept_language: Option<String>,
+    /// The Host the link was followed on
+    pub host: Option<String>,
+    /// Parsed from user_agent, None if there wasn't one
+    pub browser: Option<Browser>,
+    pub os: Option<Os>,
+    pub device: Option<Device>,
+}
+
+impl ClientInfo {
+    pub fn new(
+        referrer: Option<&str>,
+        user_agent: Option<&str>,
+        accept_language: Option<&str>,
+        host: Option<&str>,
+    ) -> Self {
+        let header = |value: Option<&str>| {
+            let value = value?.trim();
+            if value.is_empty() {
+                return None;
+            }
+            let mut end = value.len().min(MAX_HEADER_LENGTH);
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            Some(value[..end].to_string())
+        };
+        let user_agent = header(user_agent);
+        let parsed = user_agent.as_deref().map(UserAgent::parse);
+        Self {
+            referrer: header(referrer),
+            accept_language: header(accept_language),
+            host: header(host),
+            browser: parsed.map(|p| p.browser),
+            os: parsed.map(|p| p.os),
+            device: parsed.map(|p| p.device),
+            user_agent,
+        }
+    }
+}
+
+/// The language the visitor wants most from an Accept-Language header, lowercased like en-us
+pub fn preferred_language(header: &str) -> Option<String> {
+    let mut best: Option<(f32, &str)> = None;
+    for range in header.split(',') {
+        let mut parts = range.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .map(|q| q.trim().parse::<f32>().unwrap_or(0.0))
+            .unwrap_or(1.0);
+        if tag.is_empty() || tag == "*" || quality <= 0.0 {
+            continue;
+        }
+        // Ties go to whichever came first
+        if best.is_none_or(|(q, _)| quality > q) {
+            best = Some((quality, tag));
+        }
+    }
+    best.map(|(_, tag)| tag.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_agents() {
+        for (ua, os, browser, device) in [
+            (
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1",
+                Os::Ios,
+                Browser::Safari,
+                Device::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36",
+                Os::Android,
+                Browser::Chrome,
+                Device::Mobile,
+            ),
+            (
+                "Mozilla/5.0 (Linux; Android 13; SM-X700) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+                Os::Android,
+                Browser::Chrome,
+                Device::Tablet,
+            ),
+            (
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.2478.51",
+                Os::Windows,
+                Browser::Edge,
+                Device::Desktop,
+            ),
+            (
+                "Mozilla/5.0 (Macintosh; Intel Mac OS X 14.4; rv:125.0) Gecko/20100101 Firefox/125.0",
+                Os::Macos,
+                Browser::Firefox,
+                Device::Desktop,
+            ),
+            (
+                "Mozilla/5.0 (X11; CrOS x86_64 14541.0.0) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36",
+                Os::ChromeOs,
+                Browser::Chrome,
+                Device::Desktop,
+            ),
+        ] {
+            assert_eq!(
+                UserAgent::parse(ua),
+                UserAgent {
+                    os,
+                    browser,
+                    device,
+                    bot: false
+                },
+                "{ua}"
+            );
+        }
+
+        for bot in [
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            "curl/8.5.0",
+            "",
+        ] {
+            assert!(UserAgent::parse(bot).bot, "{bot}");
+        }
+    }
+
+    #[test]
+    fn client_info() {
+        let info = ClientInfo::new(
+            Some("https://news.ycombinator.com/"),
+            Some("Mozilla/5.0 (iPad; CPU OS 17_4 like Mac OS X) Version/17.4 Safari/604.1"),
+            Some(" "),
+            Some("example.com"),
+        );
+        assert_eq!(
+            info.referrer.as_deref(),
+            Some("https://news.ycombinator.com/")
+        );
+        assert_eq!(info.accept_language, None);
+        assert_eq!(info.os, Some(Os::Ios));
+        assert_eq!(info.device, Some(Device::Tablet));
+        assert_eq!(
+            ClientInfo::new(None, None, None, None),
+            ClientInfo::default()
+        );
+
+        let long = "é".repeat(MAX_HEADER_LENGTH);
+        let info = ClientInfo::new(Some(&long), None, None, None);
+        assert_eq!(info.referrer.unwrap().len(), MAX_HEADER_LENGTH);
+
+        for device in [
+            Device::Desktop,
+            Device::Mobile,
+            Device::Tablet,
+            Device::Other,
+        ] {
+            assert_eq!(device.as_str().parse(), Ok(device));
+            assert_eq!(
+                serde_json::to_string(&device).unwrap(),
+                format!("\"{}\"", device.as_str())
+            );
+        }
+        assert_eq!(Browser::SamsungInternet.as_str(), "samsung_internet");
+        assert_eq!("chrome_os".parse(), Ok(Os::ChromeOs));
+    }
+
+    #[test]
+    fn clicks() {
+        const CHROME: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+        assert_eq!(classify(false, Some(CHROME), []), ClickKind::Human);
+        assert_eq!(classify(true, Some(CHROME), []), ClickKind::Bot);
+        assert_eq!(
+            classify(false, Some(CHROME), ["prefetch;prerender"]),
+            ClickKind::Preview
+        );
+        assert_eq!(
+            classify(false, Some(CHROME), ["Preview"]),
+            ClickKind::Preview
+        );
+        for ua in [
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            "Mozilla/5.0 (compatible; Discordbot/2.0; +https://discordapp.com)",
+            // iMessage
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_11_1) AppleWebKit/601.2.4 (KHTML, like Gecko) Version/9.0.1 Safari/601.2.4 facebookexternalhit/1.1 Facebot Twitterbot/1.0",
+            "WhatsApp/2.23.20.0",
+        ] {
+            assert_eq!(classify(false, Some(ua), []), ClickKind::Preview, "{ua}");
+        }
+        for ua in [
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+            "curl/8.5.0",
+            "Mozilla/5.0 (compatible; Some Bot)",
+        ] {
+            assert_eq!(classify(false, Some(ua), []), ClickKind::Bot, "{ua}");
+        }
+        // A phone brand, not a crawler
+        assert_eq!(
+            classify(
+                false,
+                Some("Mozilla/5.0 (Linux; Android 11; Cubot X50) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36"),
+                []
+            ),
+            ClickKind::Human
+        );
+        assert_eq!(classify(false, None, []), ClickKind::Bot);
+        assert_eq!("preview".parse(), Ok(ClickKind::Preview));
+    }
+
+    #[test]
+    fn languages() {
+        assert_eq!(
+            preferred_language("fr-CH, fr;q=0.9, en;q=0.8").as_deref(),
+            Some("fr-ch")
+        );
+        assert_eq!(preferred_language("en;q=0.5, de").as_deref(), Some("de"));
+        assert_eq!(preferred_language("*, en;q=0").as_deref(), None);
+        assert_eq!(preferred_language("").as_deref(), None);
+    }
+}
@@ -0,0 +1,126 @@
+// Jackson Coxson
+// Login sessions, shared so both front-ends hand out and accept the same tokens
+
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info};
+
+use crate::{
+    store::{LinkStore, StoreError},
+    times::timestamp,
+};
+
+const TOKEN_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+const TOKEN_LENGTH: usize = 32;
+
+/// How long a login lasts
+pub const SESSION_HOURS: i64 = 1;
+
+pub fn generate_token(random: &mut impl FnMut() -> u32) -> String {
+    // 62 doesn't divide 2^32 evenly, but the bias is far too small to matter here
+    (0..TOKEN_LENGTH)
+        .map(|_| TOKEN_CHARS[random() as usize % TOKEN_CHARS.len()] as char)
+        .collect()
+}
+
+/// Creates a session and returns its token
+pub async fn login<S: LinkStore + ?Sized>(
+    store: &S,
+    random: &mut (impl FnMut() -> u32 + Send),
+) -> Result<String, StoreError> {
+    let token = generate_token(random);
+    let expires = Utc::now() + Duration::hours(SESSION_HOURS);
+    store.insert_token(&token, &timestamp(expires)).await?;
+    Ok(token)
+}
+
+/// Pulls the session token out of the X-Token header, or the cookie if there is none
+pub fn find_token(header: Option<&str>, cookies: Option<&str>) -> Option<String> {
+    // API clients can send the token directly instead of as a cookie
+    if let Some(token) = header {
+        return Some(token.trim().to_string());
+    }
+
+    for cookie in cookies?.split(';') {
+        if let Some(token) = cookie.trim().strip_prefix("X-Token=") {
+            return Some(token.replace(" SameSite", "").trim().to_string());
+        }
+    }
+    None
+}
+
+pub async fn check_token<S: LinkStore + ?Sized>(store: &S, token: &str) -> bool {
+    let expires = match store.token_expiration(token).await {
+        Ok(Some(e)) => e,
+        Ok(None) => return false,
+        Err(e) => {
+            error!("Failed to read token: {e}");
+            return false;
+        }
+    };
+
+    let expires = match DateTime::parse_from_rfc3339(&expires) {
+        Ok(e) => e,
+        Err(_) => {
+            error!("Timestamp was unparse-able for token {token}");
+            return false;
+        }
+    };
+
+    let now = Utc::now();
+    if expires > now {
+        if let Err(e) = store.remove_expired_tokens(&timestamp(now)).await {
+            error!("Failed to delete expired tokens: {e}");
+        }
+        true
+    } else {
+        info!("Expired token was used");
+        if let Err(e) = store.remove_token(token).await {
+            error!("Failed to delete token: {e}");
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    #[test]
+    fn tokens() {
+        assert_eq!(
+            find_token(Some(" abc "), Some("X-Token=def")),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            find_token(None, Some("theme=dark; X-Token=def")),
+            Some("def".to_string())
+        );
+        assert_eq!(find_token(None, Some("theme=dark")), None);
+        assert_eq!(find_token(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn sessions() {
+        let store = MemoryStore::new();
+        let token = login(&store, &mut rand_ish()).await.unwrap();
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert!(check_token(&store, &token).await);
+        assert!(!check_token(&store, "nope").await);
+
+        store
+            .insert_token("old", "2000-01-01T00:00:00Z")
+            .await
+            .unwrap();
+        assert!(!check_token(&store, "old").await);
+        assert_eq!(store.token_expiration("old").await, Ok(None));
+    }
+
+    fn rand_ish() -> impl FnMut() -> u32 + Send {
+        let mut n = 0u32;
+        move || {
+            n = n.wrapping_mul(1103515245).wrapping_add(12345);
+            n
+        }
+    }
+}
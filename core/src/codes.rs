@@ -0,0 +1,256 @@
+// Jackson Coxson
+
+use std::fmt::Display;
+
+use log::warn;
+
+/// Codes that can't be requested because they'd be confused with the admin routes
+const DEFAULT_RESERVED: &[&str] = &[
+    "admin",
+    "api",
+    "base",
+    "login",
+    "logout",
+    "r",
+    "scripts.js",
+    "styles.css",
+];
+
+const DEFAULT_ALPHABET: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// How many times a random code is retried at one length before trying a longer one
+const ATTEMPTS_PER_LENGTH: usize = 3;
+
+/// Total attempts before giving up on generating a code
+pub const MAX_ATTEMPTS: usize = 12;
+
+/// Rules that a requested (vanity) code has to follow
+#[derive(Clone, Debug)]
+pub struct CodePolicy {
+    /// Characters a code may contain. None allows ASCII letters, digits, '-' and '_'
+    pub allowed_chars: Option<String>,
+    pub min_length: usize,
+    pub max_length: usize,
+    pub reserved: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CodeError {
+    TooShort(usize),
+    TooLong(usize),
+    InvalidCharacter(char),
+    Reserved,
+}
+
+impl Display for CodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodeError::TooShort(min) => write!(f, "Code must be at least {min} characters"),
+            CodeError::TooLong(max) => write!(f, "Code must be at most {max} characters"),
+            CodeError::InvalidCharacter(c) => write!(f, "Code cannot contain '{c}'"),
+            CodeError::Reserved => write!(f, "Code is reserved"),
+        }
+    }
+}
+
+impl Default for CodePolicy {
+    fn default() -> Self {
+        Self {
+            allowed_chars: None,
+            min_length: 3,
+            max_length: 32,
+            reserved: DEFAULT_RESERVED.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl CodePolicy {
+    /// Builds the policy from config variables, falling back to the defaults.
+    /// Takes a lookup so both std::env and the worker's env can be used.
+    ///
+    /// VANITY_CHARS - every character allowed in a code
+    /// VANITY_MIN_LENGTH / VANITY_MAX_LENGTH - length bounds
+    /// RESERVED_CODES - comma separated list of codes that can't be requested
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+        let allowed_chars = var("VANITY_CHARS").filter(|c| !c.is_empty());
+        let min_length = var("VANITY_MIN_LENGTH")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default.min_length);
+        let max_length = var("VANITY_MAX_LENGTH")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(default.max_length);
+        let reserved = match var("RESERVED_CODES") {
+            Some(r) => r
+                .split(',')
+                .map(|s| s.trim().to_lowercase())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            None => default.reserved,
+        };
+
+        Self {
+            allowed_chars,
+            min_length,
+            max_length,
+            reserved,
+        }
+    }
+
+    pub fn validate(&self, code: &str) -> Result<(), CodeError> {
+        let length = code.chars().count();
+        if length < self.min_length {
+            return Err(CodeError::TooShort(self.min_length));
+        }
+        if length > self.max_length {
+            return Err(CodeError::TooLong(self.max_length));
+        }
+
+        for c in code.chars() {
+            let allowed = match &self.allowed_chars {
+                Some(chars) => chars.contains(c),
+                None => c.is_ascii_alphanumeric() || c == '-' || c == '_',
+            };
+            if !allowed {
+                return Err(CodeError::InvalidCharacter(c));
+            }
+        }
+
+        let lower = code.to_lowercase();
+        if self.reserved.iter().any(|r| r.to_lowercase() == lower) {
+            return Err(CodeError::Reserved);
+        }
+        Ok(())
+    }
+}
+
+/// Generates random codes for redirects that weren't given one
+#[derive(Clone, Debug)]
+pub struct CodeGenerator {
+    pub alphabet: Vec<char>,
+    pub min_length: usize,
+}
+
+impl Default for CodeGenerator {
+    fn default() -> Self {
+        Self {
+            alphabet: DEFAULT_ALPHABET.chars().collect(),
+            min_length: 4,
+        }
+    }
+}
+
+impl CodeGenerator {
+    /// CODE_ALPHABET - characters generated codes are made of
+    /// CODE_MIN_LENGTH - length of generated codes while the keyspace is mostly empty
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+        let mut alphabet = match var("CODE_ALPHABET") {
+            Some(a) => a.chars().filter(|c| !c.is_whitespace()).collect(),
+            None => default.alphabet.clone(),
+        };
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        if alphabet.len() < 2 {
+            warn!("CODE_ALPHABET needs at least two characters, using the default");
+            alphabet = default.alphabet;
+        }
+        let min_length = var("CODE_MIN_LENGTH")
+            .and_then(|v| v.parse().ok())
+            .filter(|l| *l > 0)
+            .unwrap_or(default.min_length);
+
+        Self {
+            alphabet,
+            min_length,
+        }
+    }
+
+    /// The length to start generating at so that the keyspace stays at most a quarter full.
+    /// `existing` is the number of codes already in use.
+    pub fn length_for(&self, existing: usize) -> usize {
+        let mut length = self.min_length;
+        let mut keyspace = (self.alphabet.len() as u128).saturating_pow(length as u32);
+        while (existing as u128).saturating_mul(4) >= keyspace {
+            length += 1;
+            keyspace = keyspace.saturating_mul(self.alphabet.len() as u128);
+        }
+        length
+    }
+
+    /// The length to use for a retry, growing after every few collisions
+    pub fn length_for_attempt(&self, start: usize, attempt: usize) -> usize {
+        start + attempt / ATTEMPTS_PER_LENGTH
+    }
+
+    /// Creates a code of `length` characters. `random` provides uniformly distributed u32s.
+    pub fn generate(&self, length: usize, random: &mut impl FnMut() -> u32) -> String {
+        let n = self.alphabet.len() as u32;
+        // Throw away the top of the range so every character is equally likely
+        let limit = u32::MAX - u32::MAX % n;
+        (0..length)
+            .map(|_| loop {
+                let r = random();
+                if r < limit {
+                    break self.alphabet[(r % n) as usize];
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vanity() {
+        let policy = CodePolicy::default();
+        assert_eq!(policy.validate("spring-sale"), Ok(()));
+        assert_eq!(policy.validate("ab"), Err(CodeError::TooShort(3)));
+        assert_eq!(
+            policy.validate("spring sale"),
+            Err(CodeError::InvalidCharacter(' '))
+        );
+        assert_eq!(policy.validate("Admin"), Err(CodeError::Reserved));
+    }
+
+    #[test]
+    fn from_vars() {
+        let policy = CodePolicy::from_vars(|key| match key {
+            "VANITY_CHARS" => Some("abc".to_string()),
+            "VANITY_MAX_LENGTH" => Some("4".to_string()),
+            "RESERVED_CODES" => Some("abc, cab".to_string()),
+            _ => None,
+        });
+        assert_eq!(policy.validate("abca"), Ok(()));
+        assert_eq!(policy.validate("abcab"), Err(CodeError::TooLong(4)));
+        assert_eq!(
+            policy.validate("abd"),
+            Err(CodeError::InvalidCharacter('d'))
+        );
+        assert_eq!(policy.validate("cab"), Err(CodeError::Reserved));
+    }
+
+    #[test]
+    fn generator() {
+        let generator = CodeGenerator::from_vars(|key| match key {
+            "CODE_ALPHABET" => Some("ab".to_string()),
+            "CODE_MIN_LENGTH" => Some("2".to_string()),
+            _ => None,
+        });
+        // 2^2 = 4 codes, so one existing code is already a quarter full
+        assert_eq!(generator.length_for(0), 2);
+        assert_eq!(generator.length_for(1), 3);
+        assert_eq!(generator.length_for(2), 4);
+        assert_eq!(generator.length_for_attempt(2, 2), 2);
+        assert_eq!(generator.length_for_attempt(2, 3), 3);
+
+        let mut counter = 0;
+        let code = generator.generate(4, &mut || {
+            counter += 1;
+            counter
+        });
+        assert_eq!(code, "baba");
+    }
+}
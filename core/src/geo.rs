@@ -0,0 +1,63 @@
+// Jackson Coxson
+// Where a visit came from. The server looks it up in a local MaxMind database and the worker
+// gets it from Cloudflare, so this only describes the result.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    links::{LinkError, StatsOptions},
+    store::LinkStore,
+};
+
+/// Anything the lookup couldn't tell is left as None
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2, like US
+    pub country: Option<String>,
+    /// The subdivision code inside the country, like CA for California
+    pub region: Option<String>,
+    pub city: Option<String>,
+    /// Autonomous system number of the visitor's network
+    pub asn: Option<u32>,
+}
+
+/// How many logged visits came from a country
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CountryVisits {
+    /// None for visits that couldn't be located
+    pub country: Option<String>,
+    pub visits: usize,
+}
+
+/// Checks a two letter country code and uppercases it
+pub fn check_country(country: &str) -> Result<String, LinkError> {
+    let country = country.trim().to_ascii_uppercase();
+    if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(LinkError::InvalidRule(format!(
+            "{country} isn't a two letter country code"
+        )));
+    }
+    Ok(country)
+}
+
+/// Visits per country for one link, or every link
+pub async fn country_visits<S: LinkStore + ?Sized>(
+    store: &S,
+    code: Option<&str>,
+    options: &StatsOptions,
+) -> Result<Vec<CountryVisits>, LinkError> {
+    Ok(store.country_visits(code, options.include_bots).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countries() {
+        assert_eq!(check_country(" de "), Ok("DE".to_string()));
+        for bad in ["", "DEU", "D1"] {
+            assert!(matches!(check_country(bad), Err(LinkError::InvalidRule(_))));
+        }
+    }
+}
@@ -0,0 +1,18 @@
+// Jackson Coxson
+// Platform-neutral core of Riplakish, shared by the native server and the Cloudflare worker
+
+pub mod agents;
+pub mod auth;
+pub mod codes;
+pub mod geo;
+pub mod links;
+pub mod migrations;
+pub mod passwords;
+pub mod resolve;
+pub mod series;
+pub mod store;
+pub mod targeting;
+pub mod times;
+pub mod urls;
+pub mod utm;
+pub mod visitors;
@@ -0,0 +1,822 @@
+// Jackson Coxson
+
+use std::{collections::HashMap, fmt::Display};
+
+use chrono::Utc;
+use chrono_tz::Tz;
+use log::{error, info};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{
+    codes::{self, CodeError, CodeGenerator, CodePolicy},
+    passwords,
+    resolve::{self, LinkState},
+    store::{DatabaseLog, DatabaseStats, LinkChanges, LinkStore, NewLinkRow, Rules},
+    targeting::{self, TargetingRule},
+    times::{self, timestamp, DisplayZone},
+    urls::{UrlError, UrlPolicy},
+    utm, visitors,
+};
+
+/// Everything that decides what a new or updated link may look like
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    pub codes: CodePolicy,
+    pub generator: CodeGenerator,
+    pub urls: UrlPolicy,
+}
+
+impl Policies {
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            codes: CodePolicy::from_vars(&var),
+            generator: CodeGenerator::from_vars(&var),
+            urls: UrlPolicy::from_vars(&var),
+        }
+    }
+}
+
+/// A link as the API shows it, along with whether it's redirecting right now
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Link {
+    #[serde(flatten)]
+    pub stats: DatabaseStats,
+    pub state: LinkState,
+    /// None if there's no max_visits
+    pub remaining_uses: Option<u64>,
+    pub password_protected: bool,
+}
+
+impl From<DatabaseStats> for Link {
+    fn from(stats: DatabaseStats) -> Self {
+        let remaining_uses = stats
+            .rules
+            .max_visits
+            .map(|max| max.saturating_sub(stats.uses));
+        let state = match stats.rules.state(Utc::now()) {
+            LinkState::Active if remaining_uses == Some(0) => LinkState::UsedUp,
+            state => state,
+        };
+        Self {
+            password_protected: stats.rules.password_hash.is_some(),
+            stats,
+            state,
+            remaining_uses,
+        }
+    }
+}
+
+/// Query string of the stats and link routes
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct StatsOptions {
+    /// Count bots and link previews in visits, unique_visitors and the country and template stats too
+    #[serde(default)]
+    pub include_bots: bool,
+    /// Only count unique visitors from the last this many days, today included
+    #[serde(default)]
+    pub unique_days: Option<u32>,
+}
+
+impl StatsOptions {
+    /// Whether unique_visitors needs counting again instead of the store's all time count
+    fn recount_visitors(&self) -> bool {
+        self.include_bots || self.unique_days.is_some()
+    }
+
+    async fn unique_visitors<S: LinkStore + ?Sized>(
+        &self,
+        store: &S,
+        code: Option<&str>,
+    ) -> Result<HashMap<String, usize>, LinkError> {
+        let since = self
+            .unique_days
+            .map(|days| visitors::window_start(Utc::now(), days));
+        Ok(store
+            .unique_visitors(code, since.as_deref(), self.include_bots)
+            .await?
+            .into_iter()
+            .map(|v| (v.code, v.visitors))
+            .collect())
+    }
+}
+
+impl Link {
+    pub fn with_options(mut self, options: StatsOptions) -> Self {
+        if options.include_bots {
+            self.stats.visits += self.stats.bot_visits + self.stats.preview_visits;
+        }
+        self
+    }
+
+    /// Shows every time on the link in tz instead of UTC
+    pub fn localize(mut self, tz: Tz) -> Self {
+        let stats = &mut self.stats;
+        for time in [
+            &mut stats.created_at,
+            &mut stats.updated_at,
+            &mut stats.rules.expires_at,
+            &mut stats.rules.not_before,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *time = times::localize(time, tz);
+        }
+        self
+    }
+}
+
+/// Body of POST /api/v2/links
+#[derive(Debug, Default, Deserialize)]
+pub struct NewLink {
+    pub url: String,
+    pub code: Option<String>,
+    pub comment: Option<String>,
+    /// RFC 3339, any offset
+    pub expires_at: Option<String>,
+    pub max_visits: Option<u64>,
+    /// RFC 3339, any offset
+    pub not_before: Option<String>,
+    /// Visitors have to enter this before being redirected
+    pub password: Option<String>,
+    /// 301, 302, 303, 307 or 308
+    pub redirect_status: Option<u16>,
+    #[serde(default)]
+    pub forward_query: bool,
+    #[serde(default)]
+    pub forward_path: bool,
+    /// Name of a UTM template to add to the destination
+    pub utm_template: Option<String>,
+    /// Splits visitors between these instead of sending them all to url
+    #[serde(default)]
+    pub destinations: Vec<NewDestination>,
+    /// Keep each visitor on the destination they got first
+    #[serde(default)]
+    pub sticky: bool,
+    /// Checked in order, visitors that match none go to url or destinations
+    #[serde(default)]
+    pub targeting: Vec<TargetingRule>,
+}
+
+/// One destination of a split link
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct NewDestination {
+    pub url: String,
+    /// Share of visits, 70 and 30 send 70% of visitors to the first
+    pub weight: u32,
+}
+
+/// Body of PATCH /api/v2/links/:code, missing fields are left alone.
+/// Rules can be removed by setting them to null.
+#[derive(Debug, Default, Deserialize)]
+pub struct LinkUpdate {
+    pub url: Option<String>,
+    pub comment: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    pub expires_at: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub max_visits: Option<Option<u64>>,
+    #[serde(default, deserialize_with = "present")]
+    pub not_before: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub password: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    pub redirect_status: Option<Option<u16>>,
+    pub forward_query: Option<bool>,
+    pub forward_path: Option<bool>,
+    #[serde(default, deserialize_with = "present")]
+    pub utm_template: Option<Option<String>>,
+    /// Replaces every destination, an empty list goes back to just url
+    pub destinations: Option<Vec<NewDestination>>,
+    pub sticky: Option<bool>,
+    /// Replaces every targeting rule, an empty list removes them
+    pub targeting: Option<Vec<TargetingRule>>,
+}
+
+/// Tells a null field apart from a missing one
+fn present<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Body of every v2 error response
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub error: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LinkError {
+    InvalidCode(CodeError),
+    InvalidUrl(UrlError),
+    /// A rule that doesn't make sense, like an unparseable time
+    InvalidRule(String),
+    /// A query string that can't be answered, like an unknown timezone
+    InvalidQuery(String),
+    /// The code is already used by another redirect
+    Conflict,
+    NotFound,
+    TemplateNotFound,
+    Failed,
+}
+
+impl LinkError {
+    pub fn status(&self) -> u16 {
+        match self {
+            LinkError::InvalidCode(_)
+            | LinkError::InvalidUrl(_)
+            | LinkError::InvalidRule(_)
+            | LinkError::InvalidQuery(_) => 400,
+            LinkError::NotFound | LinkError::TemplateNotFound => 404,
+            LinkError::Conflict => 409,
+            LinkError::Failed => 500,
+        }
+    }
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::InvalidCode(e) => write!(f, "{e}"),
+            LinkError::InvalidUrl(e) => write!(f, "{e}"),
+            LinkError::InvalidRule(e) => write!(f, "{e}"),
+            LinkError::InvalidQuery(e) => write!(f, "{e}"),
+            LinkError::Conflict => write!(f, "Code is already in use"),
+            LinkError::NotFound => write!(f, "Link not found"),
+            LinkError::TemplateNotFound => write!(f, "Template not found"),
+            LinkError::Failed => write!(f, "Internal error"),
+        }
+    }
+}
+
+impl From<LinkError> for ApiError {
+    fn from(value: LinkError) -> Self {
+        Self {
+            error: value.to_string(),
+        }
+    }
+}
+
+/// Inserts a redirect with the requested code, or a freshly generated one.
+/// Returns the code that was used.
+pub async fn create_redirect<S: LinkStore + ?Sized>(
+    store: &S,
+    policies: &Policies,
+    url: &str,
+    code: Option<String>,
+    random: &mut (impl FnMut() -> u32 + Send),
+) -> Result<String, LinkError> {
+    let link = NewLinkRow {
+        url: url.to_string(),
+        ..Default::default()
+    };
+    insert(store, policies, link, code, random).await
+}
+
+/// Checks the link's URL and writes all of it under the requested code or a generated one
+async fn insert<S: LinkStore + ?Sized>(
+    store: &S,
+    policies: &Policies,
+    mut link: NewLinkRow,
+    code: Option<String>,
+    random: &mut (impl FnMut() -> u32 + Send),
+) -> Result<String, LinkError> {
+    link.url = policies
+        .urls
+        .validate(&link.url)
+        .map_err(LinkError::InvalidUrl)?;
+    let now = timestamp(Utc::now());
+
+    if let Some(code) = code {
+        policies
+            .codes
+            .validate(&code)
+            .map_err(LinkError::InvalidCode)?;
+        store.insert_link_row(&code, &link, &now).await?;
+        return Ok(code);
+    }
+
+    let generator = &policies.generator;
+    let start = generator.length_for(store.count_links().await?);
+    for attempt in 0..codes::MAX_ATTEMPTS {
+        let length = generator.length_for_attempt(start, attempt);
+        let code = generator.generate(length, random);
+        info!("Attempting to insert {} with code {code}", link.url);
+        match store
+            .insert_link_row(&code, &link, &now)
+            .await
+            .map_err(LinkError::from)
+        {
+            Ok(()) => return Ok(code),
+            Err(LinkError::Conflict) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    error!("Ran out of attempts to generate a unique code");
+    Err(LinkError::Failed)
+}
+
+/// Checks an RFC 3339 time and stores it in UTC
+fn normalize_time(field: &str, time: &str) -> Result<String, LinkError> {
+    resolve::parse_time(time.trim())
+        .map(timestamp)
+        .ok_or_else(|| LinkError::InvalidRule(format!("{field} must be an RFC 3339 time")))
+}
+
+fn check_max_visits(max_visits: u64) -> Result<u64, LinkError> {
+    if max_visits == 0 {
+        return Err(LinkError::InvalidRule(
+            "max_visits must be at least 1".to_string(),
+        ));
+    }
+    Ok(max_visits)
+}
+
+fn check_redirect_status(status: u16) -> Result<u16, LinkError> {
+    if !resolve::REDIRECT_STATUSES.contains(&status) {
+        return Err(LinkError::InvalidRule(
+            "redirect_status must be 301, 302, 303, 307 or 308".to_string(),
+        ));
+    }
+    Ok(status)
+}
+
+fn check_destinations(
+    policies: &Policies,
+    destinations: Vec<NewDestination>,
+) -> Result<Vec<NewDestination>, LinkError> {
+    destinations
+        .into_iter()
+        .map(|d| {
+            if d.weight == 0 {
+                return Err(LinkError::InvalidRule(
+                    "destination weights must be at least 1".to_string(),
+                ));
+            }
+            Ok(NewDestination {
+                url: policies
+                    .urls
+                    .validate(&d.url)
+                    .map_err(LinkError::InvalidUrl)?,
+                weight: d.weight,
+            })
+        })
+        .collect()
+}
+
+fn hash_password(
+    password: &str,
+    random: &mut (impl FnMut() -> u32 + Send),
+) -> Result<String, LinkError> {
+    if password.is_empty() {
+        return Err(LinkError::InvalidRule(
+            "password can't be empty".to_string(),
+        ));
+    }
+    Ok(passwords::hash_password(password, random))
+}
+
+/// Checks the rules make sense together
+fn check_window(rules: &Rules) -> Result<(), LinkError> {
+    let time = |time: &Option<String>| time.as_deref().and_then(resolve::parse_time);
+    if let (Some(start), Some(end)) = (time(&rules.not_before), time(&rules.expires_at)) {
+        if start >= end {
+            return Err(LinkError::InvalidRule(
+                "not_before must be before expires_at".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub async fn create_link<S: LinkStore + ?Sized>(
+    store: &S,
+    policies: &Policies,
+    new_link: NewLink,
+    random: &mut (impl FnMut() -> u32 + Send),
+) -> Result<Link, LinkError> {
+    let rules = Rules {
+        expires_at: new_link
+            .expires_at
+            .map(|t| normalize_time("expires_at", &t))
+            .transpose()?,
+        max_visits: new_link.max_visits.map(check_max_visits).transpose()?,
+        not_before: new_link
+            .not_before
+            .map(|t| normalize_time("not_before", &t))
+            .transpose()?,
+        password_hash: new_link
+            .password
+            .map(|p| hash_password(&p, random))
+            .transpose()?,
+        redirect_status: new_link
+            .redirect_status
+            .map(check_redirect_status)
+            .transpose()?,
+        forward_query: new_link.forward_query,
+        forward_path: new_link.forward_path,
+        utm_template: new_link.utm_template,
+        sticky: new_link.sticky,
+        targeting: targeting::check_rules(&policies.urls, new_link.targeting)?,
+    };
+    check_window(&rules)?;
+    if let Some(name) = &rules.utm_template {
+        utm::check_exists(store, name).await?;
+    }
+    let link = NewLinkRow {
+        url: new_link.url,
+        comment: new_link.comment,
+        rules,
+        destinations: check_destinations(policies, new_link.destinations)?,
+    };
+    let code = insert(store, policies, link, new_link.code, random).await?;
+    get_link(store, &code).await
+}
+
+pub async fn get_link<S: LinkStore + ?Sized>(store: &S, code: &str) -> Result<Link, LinkError> {
+    Ok(store
+        .get_link(code)
+        .await?
+        .ok_or(LinkError::NotFound)?
+        .into())
+}
+
+pub async fn list_links<S: LinkStore + ?Sized>(
+    store: &S,
+    options: StatsOptions,
+    zone: &DisplayZone,
+) -> Result<Vec<Link>, LinkError> {
+    let tz = zone.zone()?;
+    let mut links = store.list_links().await?;
+    if options.recount_visitors() {
+        let visitors = options.unique_visitors(store, None).await?;
+        for link in &mut links {
+            link.unique_visitors = visitors.get(&link.code).copied().unwrap_or_default();
+        }
+    }
+    Ok(links
+        .into_iter()
+        .map(|stats| Link::from(stats).with_options(options).localize(tz))
+        .collect())
+}
+
+/// A link as the stats routes show it
+pub async fn link_stats<S: LinkStore + ?Sized>(
+    store: &S,
+    code: &str,
+    options: StatsOptions,
+    zone: &DisplayZone,
+) -> Result<Link, LinkError> {
+    let tz = zone.zone()?;
+    let mut link = get_link(store, code).await?;
+    if options.recount_visitors() {
+        let visitors = options.unique_visitors(store, Some(code)).await?;
+        link.stats.unique_visitors = visitors.get(code).copied().unwrap_or_default();
+    }
+    Ok(link.with_options(options).localize(tz))
+}
+
+/// Every logged visit to a link, oldest first
+pub async fn logs<S: LinkStore + ?Sized>(
+    store: &S,
+    code: &str,
+    zone: &DisplayZone,
+) -> Result<Vec<DatabaseLog>, LinkError> {
+    let tz = zone.zone()?;
+    Ok(store
+        .get_logs(code)
+        .await?
+        .into_iter()
+        .map(|log| log.localize(tz))
+        .collect())
+}
+
+pub async fn update_link<S: LinkStore + ?Sized>(
+    store: &S,
+    policies: &Policies,
+    code: &str,
+    update: LinkUpdate,
+    random: &mut (impl FnMut() -> u32 + Send),
+) -> Result<Link, LinkError> {
+    let current = get_link(store, code).await?.stats.rules;
+
+    // Everything is checked before anything is written
+    let url = update
+        .url
+        .map(|url| policies.urls.validate(&url).map_err(LinkError::InvalidUrl))
+        .transpose()?;
+    let mut rules = current.clone();
+    if let Some(expires_at) = update.expires_at {
+        rules.expires_at = expires_at
+            .map(|t| normalize_time("expires_at", &t))
+            .transpose()?;
+    }
+    if let Some(max_visits) = update.max_visits {
+        rules.max_visits = max_visits.map(check_max_visits).transpose()?;
+    }
+    if let Some(not_before) = update.not_before {
+        rules.not_before = not_before
+            .map(|t| normalize_time("not_before", &t))
+            .transpose()?;
+    }
+    if let Some(password) = update.password {
+        rules.password_hash = password.map(|p| hash_password(&p, random)).transpose()?;
+    }
+    if let Some(status) = update.redirect_status {
+        rules.redirect_status = status.map(check_redirect_status).transpose()?;
+    }
+    if let Some(forward_query) = update.forward_query {
+        rules.forward_query = forward_query;
+    }
+    if let Some(forward_path) = update.forward_path {
+        rules.forward_path = forward_path;
+    }
+    if let Some(template) = update.utm_template {
+        if let Some(name) = &template {
+            utm::check_exists(store, name).await?;
+        }
+        rules.utm_template = template;
+    }
+    if let Some(sticky) = update.sticky {
+        rules.sticky = sticky;
+    }
+    if let Some(rules_list) = update.targeting {
+        rules.targeting = targeting::check_rules(&policies.urls, rules_list)?;
+    }
+    check_window(&rules)?;
+    let destinations = update
+        .destinations
+        .map(|d| check_destinations(policies, d))
+        .transpose()?;
+
+    let changes = LinkChanges {
+        url,
+        comment: update.comment,
+        rules: (rules != current).then_some(rules),
+        destinations,
+    };
+    if changes != LinkChanges::default() {
+        store
+            .update_link_row(code, &changes, &timestamp(Utc::now()))
+            .await?;
+    }
+    get_link(store, code).await
+}
+
+pub async fn delete_link<S: LinkStore + ?Sized>(store: &S, code: &str) -> Result<(), LinkError> {
+    get_link(store, code).await?;
+    store.remove_link(code).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        agents::ClickKind,
+        store::{memory::MemoryStore, Visit},
+        visitors::Fingerprint,
+    };
+
+    #[tokio::test]
+    async fn create() {
+        let store = MemoryStore::new();
+        let policies = Policies::default();
+
+        // Always rolls the same code, so the second one only fits once the length grows
+        let mut same = || 7;
+        let first = create_redirect(&store, &policies, "google.com", None, &mut same)
+            .await
+            .unwrap();
+        let second = create_redirect(&store, &policies, "google.com", None, &mut same)
+            .await
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(
+            store.get_target(&first).await.unwrap().unwrap().url,
+            "https://google.com/"
+        );
+
+        let link = create_link(
+            &store,
+            &policies,
+            NewLink {
+                url: "https://example.com".to_string(),
+                code: Some("example".to_string()),
+                comment: Some("hello".to_string()),
+                expires_at: Some("2024-05-01T14:00:00+02:00".to_string()),
+                max_visits: Some(5),
+                not_before: None,
+                password: Some("hunter2".to_string()),
+                redirect_status: Some(301),
+                forward_query: true,
+                forward_path: false,
+                utm_template: None,
+                destinations: vec![NewDestination {
+                    url: "b.com".to_string(),
+                    weight: 2,
+                }],
+                sticky: true,
+                targeting: vec![],
+            },
+            &mut same,
+        )
+        .await
+        .unwrap();
+        assert_eq!(link.stats.code, "example");
+        assert_eq!(link.stats.comment, "hello");
+        assert_eq!(
+            link.stats.rules.expires_at.as_deref(),
+            Some("2024-05-01T12:00:00Z")
+        );
+        assert_eq!(link.state, LinkState::Expired);
+        assert_eq!(link.remaining_uses, Some(5));
+        assert!(link.password_protected);
+        assert_eq!(link.stats.rules.redirect_status, Some(301));
+        assert!(link.stats.rules.forward_query);
+        assert!(link.stats.rules.sticky);
+        assert_eq!(link.stats.destinations[0].destination.url, "https://b.com/");
+        assert!(!serde_json::to_string(&link).unwrap().contains("hunter2"));
+        assert!(link.stats.created_at.is_some());
+        assert_eq!(link.stats.updated_at, link.stats.created_at);
+
+        let zurich = DisplayZone {
+            tz: Some("Europe/Zurich".to_string()),
+        };
+        let link = link_stats(&store, "example", StatsOptions::default(), &zurich)
+            .await
+            .unwrap();
+        assert_eq!(
+            link.stats.rules.expires_at.as_deref(),
+            Some("2024-05-01T14:00:00+02:00")
+        );
+        let nowhere = DisplayZone {
+            tz: Some("Nowhere".to_string()),
+        };
+        assert!(matches!(
+            list_links(&store, StatsOptions::default(), &nowhere).await,
+            Err(LinkError::InvalidQuery(_))
+        ));
+
+        assert_eq!(
+            create_redirect(
+                &store,
+                &policies,
+                "google.com",
+                Some("example".to_string()),
+                &mut same
+            )
+            .await,
+            Err(LinkError::Conflict)
+        );
+        assert!(matches!(
+            create_redirect(&store, &policies, "javascript:alert(1)", None, &mut same).await,
+            Err(LinkError::InvalidUrl(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bots() {
+        let store = MemoryStore::new();
+        store
+            .insert_link("asdf", "https://a.com/", "2024-05-01T00:00:00Z")
+            .await
+            .unwrap();
+        let visit = |kind| Visit {
+            kind,
+            visitor: Some(Fingerprint {
+                day: visitors::day(Utc::now()),
+                hash: format!("{kind:?}"),
+            }),
+            ..Visit::new("asdf", "https://a.com/", "127.0.0.1")
+        };
+        store
+            .log_visits(vec![
+                visit(ClickKind::Human),
+                visit(ClickKind::Bot),
+                visit(ClickKind::Preview),
+            ])
+            .await
+            .unwrap();
+
+        let utc = DisplayZone::default();
+        let links = list_links(&store, StatsOptions::default(), &utc)
+            .await
+            .unwrap();
+        assert_eq!(
+            (links[0].stats.visits, links[0].stats.unique_visitors),
+            (1, 1)
+        );
+        let options = StatsOptions {
+            include_bots: true,
+            unique_days: Some(1),
+        };
+        let links = list_links(&store, options, &utc).await.unwrap();
+        assert_eq!(
+            (links[0].stats.visits, links[0].stats.unique_visitors),
+            (3, 3)
+        );
+        let link = link_stats(&store, "asdf", options, &utc).await.unwrap();
+        assert_eq!((link.stats.visits, link.stats.unique_visitors), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn update() {
+        let store = MemoryStore::new();
+        let policies = Policies::default();
+        let mut same = || 7;
+        store
+            .insert_link("asdf", "https://google.com/", "2024-05-01T00:00:00Z")
+            .await
+            .unwrap();
+
+        let update = |url: &str| LinkUpdate {
+            url: Some(url.to_string()),
+            ..Default::default()
+        };
+        let link = update_link(&store, &policies, "asdf", update("example.com"), &mut same)
+            .await
+            .unwrap();
+        assert_eq!(link.stats.url, "https://example.com/");
+        assert_eq!(
+            link.stats.created_at.as_deref(),
+            Some("2024-05-01T00:00:00Z")
+        );
+        assert_ne!(link.stats.updated_at, link.stats.created_at);
+
+        // Missing leaves the expiry alone, null removes it
+        let expires: LinkUpdate =
+            serde_json::from_str(r#"{"expires_at": "2999-01-01T00:00:00Z"}"#).unwrap();
+        let link = update_link(&store, &policies, "asdf", expires, &mut same)
+            .await
+            .unwrap();
+        assert_eq!(link.state, LinkState::Active);
+        let link = update_link(&store, &policies, "asdf", update("example.com"), &mut same)
+            .await
+            .unwrap();
+        assert!(link.stats.rules.expires_at.is_some());
+        let clear: LinkUpdate = serde_json::from_str(r#"{"expires_at": null}"#).unwrap();
+        let link = update_link(&store, &policies, "asdf", clear, &mut same)
+            .await
+            .unwrap();
+        assert_eq!(link.stats.rules.expires_at, None);
+
+        let once: LinkUpdate = serde_json::from_str(r#"{"max_visits": 1}"#).unwrap();
+        update_link(&store, &policies, "asdf", once, &mut same)
+            .await
+            .unwrap();
+        store.claim_visit("asdf").await.unwrap();
+        let link = get_link(&store, "asdf").await.unwrap();
+        assert_eq!(
+            (link.state, link.remaining_uses),
+            (LinkState::UsedUp, Some(0))
+        );
+
+        let launch: LinkUpdate =
+            serde_json::from_str(r#"{"max_visits": null, "not_before": "2998-01-01T00:00:00Z"}"#)
+                .unwrap();
+        let link = update_link(&store, &policies, "asdf", launch, &mut same)
+            .await
+            .unwrap();
+        assert_eq!(link.state, LinkState::Scheduled);
+
+        let split: LinkUpdate = serde_json::from_str(
+            r#"{"destinations": [{"url": "a.com", "weight": 1}, {"url": "b.com", "weight": 1}]}"#,
+        )
+        .unwrap();
+        let link = update_link(&store, &policies, "asdf", split, &mut same)
+            .await
+            .unwrap();
+        assert_eq!(link.stats.destinations.len(), 2);
+        let clear: LinkUpdate = serde_json::from_str(r#"{"destinations": []}"#).unwrap();
+        let link = update_link(&store, &policies, "asdf", clear, &mut same)
+            .await
+            .unwrap();
+        assert!(link.stats.destinations.is_empty());
+
+        for bad in [
+            r#"{"password": ""}"#,
+            r#"{"expires_at": "tomorrow"}"#,
+            r#"{"max_visits": 0}"#,
+            r#"{"redirect_status": 200}"#,
+            r#"{"utm_template": "nope"}"#,
+            r#"{"destinations": [{"url": "a.com", "weight": 0}]}"#,
+            r#"{"targeting": [{"url": "a.com"}]}"#,
+            r#"{"not_before": "2999-01-01T00:00:00Z", "expires_at": "2998-01-01T00:00:00Z"}"#,
+        ] {
+            let bad: LinkUpdate = serde_json::from_str(bad).unwrap();
+            assert!(matches!(
+                update_link(&store, &policies, "asdf", bad, &mut same).await,
+                Err(LinkError::InvalidRule(_))
+            ));
+        }
+        assert_eq!(
+            update_link(&store, &policies, "nope", update("example.com"), &mut same).await,
+            Err(LinkError::NotFound)
+        );
+
+        assert_eq!(delete_link(&store, "asdf").await, Ok(()));
+        assert_eq!(delete_link(&store, "asdf").await, Err(LinkError::NotFound));
+    }
+}
@@ -0,0 +1,163 @@
+// Jackson Coxson
+// Schema migrations. The SQL lives in core/migrations, SQLite applies it at startup
+// and D1 gets the same statements as wrangler migrations.
+
+/// Keeps track of which migrations a database has had applied
+pub const VERSION_TABLE: &str = "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);";
+pub const CURRENT_VERSION: &str =
+    "SELECT COALESCE(MAX(version), 0) AS version FROM schema_version;";
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every migration in the order it has to be applied. Only ever append to this.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "unique_codes",
+        sql: include_str!("../migrations/0002_unique_codes.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "link_expiry",
+        sql: include_str!("../migrations/0003_link_expiry.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "max_visits",
+        sql: include_str!("../migrations/0004_max_visits.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "not_before",
+        sql: include_str!("../migrations/0005_not_before.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "link_passwords",
+        sql: include_str!("../migrations/0006_link_passwords.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "redirect_status",
+        sql: include_str!("../migrations/0007_redirect_status.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "passthrough",
+        sql: include_str!("../migrations/0008_passthrough.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "utm_templates",
+        sql: include_str!("../migrations/0009_utm_templates.sql"),
+    },
+    Migration {
+        version: 10,
+        name: "destinations",
+        sql: include_str!("../migrations/0010_destinations.sql"),
+    },
+    Migration {
+        version: 11,
+        name: "targeting",
+        sql: include_str!("../migrations/0011_targeting.sql"),
+    },
+    Migration {
+        version: 12,
+        name: "geoip",
+        sql: include_str!("../migrations/0012_geoip.sql"),
+    },
+    Migration {
+        version: 13,
+        name: "client_info",
+        sql: include_str!("../migrations/0013_client_info.sql"),
+    },
+    Migration {
+        version: 14,
+        name: "click_kind",
+        sql: include_str!("../migrations/0014_click_kind.sql"),
+    },
+    Migration {
+        version: 15,
+        name: "unique_visitors",
+        sql: include_str!("../migrations/0015_unique_visitors.sql"),
+    },
+    Migration {
+        version: 16,
+        name: "log_timestamps",
+        sql: include_str!("../migrations/0016_log_timestamps.sql"),
+    },
+    Migration {
+        version: 17,
+        name: "utc_times",
+        sql: include_str!("../migrations/0017_utc_times.sql"),
+    },
+    Migration {
+        version: 18,
+        name: "visitor_salts",
+        sql: include_str!("../migrations/0018_visitor_salts.sql"),
+    },
+];
+
+/// Migrations a database at the given version still needs
+pub fn pending(current: u32) -> &'static [Migration] {
+    let applied = MIGRATIONS
+        .iter()
+        .take_while(|m| m.version <= current)
+        .count();
+    &MIGRATIONS[applied..]
+}
+
+impl Migration {
+    /// File name wrangler expects, like 0001_initial.sql
+    pub fn file_name(&self) -> String {
+        format!("{:04}_{}.sql", self.version, self.name)
+    }
+
+    /// The migration along with recording that it ran
+    pub fn script(&self) -> String {
+        format!(
+            "{VERSION_TABLE}\n{}\nINSERT INTO schema_version (version, name, applied_at) VALUES ({}, '{}', datetime('now'));\n",
+            self.sql.trim(),
+            self.version,
+            self.name
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1);
+            assert!(migration
+                .name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c == '_'));
+        }
+    }
+
+    #[test]
+    fn pending_migrations() {
+        assert_eq!(pending(0).len(), MIGRATIONS.len());
+        assert_eq!(pending(1)[0].version, 2);
+        assert!(pending(MIGRATIONS.len() as u32).is_empty());
+
+        let script = MIGRATIONS[1].script();
+        assert!(script.starts_with(VERSION_TABLE));
+        assert!(script.ends_with("VALUES (2, 'unique_codes', datetime('now'));\n"));
+        assert_eq!(MIGRATIONS[1].file_name(), "0002_unique_codes.sql");
+    }
+}
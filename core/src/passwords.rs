@@ -0,0 +1,105 @@
+// Jackson Coxson
+// Link passwords are kept as salted PBKDF2 hashes, never as the password itself.
+// Plain Rust so the worker can hash them too.
+
+use sha2::Sha256;
+
+const SCHEME: &str = "pbkdf2-sha256";
+/// Stored with each hash, so it can go up without breaking old passwords
+#[cfg(not(test))]
+const ROUNDS: u32 = 100_000;
+/// Unoptimized test builds would take seconds per hash
+#[cfg(test)]
+const ROUNDS: u32 = 1_000;
+const SALT_WORDS: usize = 4;
+
+/// Hashes a password as pbkdf2-sha256$rounds$salt$hash
+pub fn hash_password(password: &str, random: &mut (impl FnMut() -> u32 + Send)) -> String {
+    hash_with_rounds(password, random, ROUNDS)
+}
+
+fn hash_with_rounds(
+    password: &str,
+    random: &mut (impl FnMut() -> u32 + Send),
+    rounds: u32,
+) -> String {
+    let salt = (0..SALT_WORDS)
+        .flat_map(|_| random().to_le_bytes())
+        .collect::<Vec<u8>>();
+    let hash = derive(password, &salt, rounds);
+    format!("{SCHEME}${rounds}${}${}", hex(&salt), hex(&hash))
+}
+
+/// Whether a password matches a stored hash. Anything unparseable never matches.
+pub fn verify_password(password: &str, stored: &str) -> bool {
+    let parts = stored.split('$').collect::<Vec<&str>>();
+    let [scheme, rounds, salt, hash] = parts[..] else {
+        return false;
+    };
+    let (Ok(rounds), Some(salt), Some(hash)) = (rounds.parse::<u32>(), unhex(salt), unhex(hash))
+    else {
+        return false;
+    };
+    if scheme != SCHEME || rounds == 0 {
+        return false;
+    }
+
+    // Compare every byte so the time taken doesn't give away how much matched
+    let derived = derive(password, &salt, rounds);
+    derived.len() == hash.len()
+        && derived
+            .iter()
+            .zip(hash.iter())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn derive(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut out = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut out);
+    out
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords() {
+        let mut counter = 0;
+        let mut random = || {
+            counter += 1;
+            counter
+        };
+        let stored = hash_with_rounds("hunter2", &mut random, 10);
+        assert!(stored.starts_with("pbkdf2-sha256$10$"));
+        assert!(verify_password("hunter2", &stored));
+        assert!(!verify_password("hunter3", &stored));
+
+        // Same password, different salt
+        assert_ne!(hash_with_rounds("hunter2", &mut random, 10), stored);
+
+        for garbage in [
+            "",
+            "hunter2",
+            "pbkdf2-sha256$0$00$00",
+            "pbkdf2-sha256$10$zz$00",
+        ] {
+            assert!(!verify_password("hunter2", garbage));
+        }
+    }
+}
@@ -0,0 +1,1024 @@
+// Jackson Coxson
+// Decides what following a link does right now. The server and the worker both render the
+// Reply that comes out of here, so a link behaves the same wherever it's hosted.
+
+use chrono::{DateTime, Duration, Utc};
+use log::warn;
+use serde::Serialize;
+use url::Url;
+
+use crate::{
+    agents::ClickKind,
+    passwords,
+    store::{Destination, LinkStore, Rules, StoreResult, Target},
+    targeting,
+    times::timestamp,
+    urls,
+};
+
+/// Whether a link is currently redirecting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkState {
+    Active,
+    /// Before its not_before
+    Scheduled,
+    /// Past its expires_at
+    Expired,
+    /// Followed max_visits times
+    UsedUp,
+}
+
+impl LinkState {
+    fn status(self) -> u16 {
+        match self {
+            LinkState::Active => 200,
+            LinkState::Scheduled => 503,
+            LinkState::Expired | LinkState::UsedUp => 410,
+        }
+    }
+
+    /// Title and message for the branded page
+    fn describe(self) -> (&'static str, &'static str) {
+        match self {
+            LinkState::Active => ("Link active", "This link is active."),
+            LinkState::Scheduled => ("Coming soon", "This link isn't live yet, check back soon."),
+            LinkState::Expired => ("Link expired", "This link has expired."),
+            LinkState::UsedUp => ("Link used up", "This link can't be used any more."),
+        }
+    }
+}
+
+/// Parses a stored RFC 3339 time
+pub fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+impl Rules {
+    pub fn state(&self, now: DateTime<Utc>) -> LinkState {
+        let time = |time: &Option<String>| time.as_deref().and_then(parse_time);
+        if time(&self.not_before).is_some_and(|start| now < start) {
+            LinkState::Scheduled
+        } else if time(&self.expires_at).is_some_and(|expires| expires <= now) {
+            LinkState::Expired
+        } else {
+            LinkState::Active
+        }
+    }
+
+    /// Whether following the link always ends up in the same place. A UTM template can change,
+    /// and its values can too, such as {date}.
+    fn is_fixed(&self) -> bool {
+        self.expires_at.is_none()
+            && self.max_visits.is_none()
+            && self.not_before.is_none()
+            && self.password_hash.is_none()
+            && self.utm_template.is_none()
+    }
+}
+
+/// Statuses a link can redirect with
+pub const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
+
+/// Remembers which destination a visitor got from a sticky link
+pub const STICKY_COOKIE: &str = "riplakish_destination";
+const STICKY_MAX_AGE: u64 = 30 * 24 * 60 * 60;
+
+/// Where a link sends the visitor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub url: String,
+    /// The link's own status, None for the server default
+    pub status: Option<u16>,
+    /// Whether a browser could keep this redirect without missing a change in the link's rules
+    pub cacheable: bool,
+    /// The UTM template added to the URL, for the visit log
+    pub utm_template: Option<String>,
+    /// Which of the link's destinations was picked, for the visit log
+    pub destination: Option<u64>,
+    /// A Set-Cookie value that keeps the visitor on the same destination
+    pub cookie: Option<String>,
+}
+
+/// What following a link should do
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Redirect(Redirect),
+    NotFound,
+    /// The link exists but isn't redirecting right now
+    Unavailable(LinkState),
+    /// Show the password form, saying the last one was wrong if it was
+    PasswordRequired {
+        wrong: bool,
+    },
+    /// Too many wrong passwords from this visitor, even a right one isn't checked
+    TooManyAttempts,
+    /// The link works, but this request only looks at it so a limited link isn't given away.
+    /// Holds where it would have gone, so the visit can still be logged.
+    Preview(Redirect),
+}
+
+/// Who is following a link
+#[derive(Debug, Clone, Default)]
+pub struct Visitor {
+    pub ip: String,
+    /// Sent through the password form
+    pub password: Option<String>,
+    /// Anything after /r/{code}/, still percent-encoded
+    pub path: Option<String>,
+    /// The query string, without the ?
+    pub query: Option<String>,
+    /// The destination the sticky cookie says they got last time
+    pub destination: Option<u64>,
+    pub user_agent: Option<String>,
+    pub accept_language: Option<String>,
+    /// Two letter country code, if the runtime could locate the visitor
+    pub country: Option<String>,
+    /// Only people use up a limited link
+    pub kind: ClickKind,
+}
+
+/// Reads the sticky destination out of a Cookie header
+pub fn sticky_destination(cookies: &str) -> Option<u64> {
+    cookies.split(';').find_map(|cookie| {
+        let (name, value) = cookie.trim().split_once('=')?;
+        (name == STICKY_COOKIE).then(|| value.parse().ok())?
+    })
+}
+
+fn sticky_cookie(code: &str, destination: u64) -> String {
+    format!(
+        "{STICKY_COOKIE}={destination}; Path=/r/{code}; Max-Age={STICKY_MAX_AGE}; SameSite=Lax; HttpOnly"
+    )
+}
+
+/// Picks a destination by weight, or the one the visitor had before if it's still there
+fn pick<'a>(
+    destinations: &'a [Destination],
+    previous: Option<u64>,
+    random: &mut (impl FnMut() -> u32 + Send),
+) -> Option<&'a Destination> {
+    if let Some(previous) = destinations.iter().find(|d| Some(d.id) == previous) {
+        return Some(previous);
+    }
+    let total = destinations
+        .iter()
+        .map(|d| u64::from(d.weight))
+        .sum::<u64>();
+    if total == 0 {
+        return destinations.first();
+    }
+    let mut roll = u64::from(random()) % total;
+    destinations.iter().find(|d| {
+        let weight = u64::from(d.weight);
+        if roll < weight {
+            return true;
+        }
+        roll -= weight;
+        false
+    })
+}
+
+/// How many wrong passwords a visitor gets before being locked out of a link for a while
+#[derive(Debug, Clone)]
+pub struct AttemptLimit {
+    pub max_attempts: u64,
+    pub window: Duration,
+}
+
+impl Default for AttemptLimit {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            window: Duration::minutes(15),
+        }
+    }
+}
+
+impl AttemptLimit {
+    /// PASSWORD_MAX_ATTEMPTS and PASSWORD_LOCKOUT_SECS
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+        Self {
+            max_attempts: var("PASSWORD_MAX_ATTEMPTS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.max_attempts),
+            window: var("PASSWORD_LOCKOUT_SECS")
+                .and_then(|v| v.parse().ok())
+                .map(Duration::seconds)
+                .unwrap_or(default.window),
+        }
+    }
+}
+
+/// Decides from the link alone, without counting the visit
+pub fn resolve(target: Option<Target>, now: DateTime<Utc>) -> Outcome {
+    let target = match target {
+        Some(t) => t,
+        None => return Outcome::NotFound,
+    };
+    match target.rules.state(now) {
+        LinkState::Active => Outcome::Redirect(Redirect {
+            cacheable: target.rules.is_fixed()
+                && target.rules.targeting.is_empty()
+                && target.destinations.is_empty(),
+            status: target.rules.redirect_status,
+            url: target.url,
+            utm_template: None,
+            destination: None,
+            cookie: None,
+        }),
+        state => Outcome::Unavailable(state),
+    }
+}
+
+/// Looks a code up and decides what following it does right now.
+/// A link with max_visits has a use claimed here, so redirects should always go through this.
+/// Bots, previews and HEAD requests only look at a limited link, so they leave its uses alone.
+pub async fn follow<S: LinkStore + ?Sized>(
+    store: &S,
+    code: &str,
+    visitor: &Visitor,
+    limit: &AttemptLimit,
+    now: DateTime<Utc>,
+    random: &mut (impl FnMut() -> u32 + Send),
+) -> StoreResult<Outcome> {
+    let target = store.get_target(code).await?;
+    let (rules, destinations) = match &target {
+        Some(t) => (t.rules.clone(), t.destinations.clone()),
+        None => return Ok(Outcome::NotFound),
+    };
+    // Only links that forward their path have anything under them
+    if visitor.path.is_some() && !rules.forward_path {
+        return Ok(Outcome::NotFound);
+    }
+    let mut redirect = match resolve(target, now) {
+        Outcome::Redirect(r) => r,
+        outcome => return Ok(outcome),
+    };
+
+    if let Some(hash) = &rules.password_hash {
+        let password = match &visitor.password {
+            Some(p) => p,
+            None => return Ok(Outcome::PasswordRequired { wrong: false }),
+        };
+        // Every guess takes an attempt before it's checked, so parallel guesses can't get past
+        // the limit, and the right password gives its attempt back
+        let since = timestamp(now - limit.window);
+        let claimed = store
+            .claim_attempt(
+                code,
+                &visitor.ip,
+                &timestamp(now),
+                &since,
+                limit.max_attempts,
+            )
+            .await?;
+        if !claimed {
+            return Ok(Outcome::TooManyAttempts);
+        }
+        if !passwords::verify_password(password, hash) {
+            warn!("Wrong password for {code} from {}", visitor.ip);
+            return Ok(Outcome::PasswordRequired { wrong: true });
+        }
+        store.release_attempt(code, &visitor.ip).await?;
+        // Answering the form with a 307 or 308 would post the password on to the destination
+        redirect.status = Some(303);
+    }
+
+    // Only people use up a limited link, anything else gets a preview of it
+    let mut preview = false;
+    if let Some(max) = rules.max_visits {
+        if visitor.kind != ClickKind::Human {
+            let uses = store.get_link(code).await?.map_or(max, |l| l.uses);
+            if uses >= max {
+                return Ok(Outcome::Unavailable(LinkState::UsedUp));
+            }
+            preview = true;
+        } else if !store.claim_visit(code).await? {
+            return Ok(Outcome::Unavailable(LinkState::UsedUp));
+        }
+    }
+
+    let previous = visitor.destination.filter(|_| rules.sticky);
+    if let Some(rule) = targeting::find(&rules.targeting, visitor) {
+        redirect.url = rule.url.clone();
+    } else if let Some(destination) = pick(&destinations, previous, random) {
+        redirect.url = destination.url.clone();
+        redirect.destination = Some(destination.id);
+        if rules.sticky {
+            redirect.cookie = Some(sticky_cookie(code, destination.id));
+        }
+    }
+
+    // A template that has since been removed just doesn't add anything
+    let template = match &rules.utm_template {
+        Some(name) => store.get_template(name).await?,
+        None => None,
+    };
+    let tags = template.as_ref().map(|t| t.params.render(code, now));
+    redirect.url = forward(&redirect.url, &rules, visitor, tags.as_deref());
+    redirect.utm_template = template.map(|t| t.name);
+    Ok(if preview {
+        Outcome::Preview(redirect)
+    } else {
+        Outcome::Redirect(redirect)
+    })
+}
+
+/// Adds the link's UTM tags, then carries the visitor's path and query over if the link passes them on
+fn forward(destination: &str, rules: &Rules, visitor: &Visitor, tags: Option<&str>) -> String {
+    let path = visitor.path.as_deref().filter(|_| rules.forward_path);
+    let query = visitor.query.as_deref().filter(|_| rules.forward_query);
+    if path.is_none() && query.is_none() && tags.is_none() {
+        return destination.to_string();
+    }
+    let mut url = match Url::parse(destination) {
+        Ok(u) => u,
+        Err(_) => return destination.to_string(),
+    };
+    if let Some(path) = path {
+        urls::append_path(&mut url, path);
+    }
+    // Merged in order, so the visitor can't replace the link's own tags
+    for query in [tags, query].into_iter().flatten() {
+        urls::merge_query(&mut url, query);
+    }
+    url.to_string()
+}
+
+/// How redirects are sent
+#[derive(Debug, Clone)]
+pub struct Redirects {
+    /// For links without their own redirect_status
+    pub status: u16,
+    /// How long browsers may keep a permanent redirect. None makes every visit come back to be counted.
+    pub permanent_max_age: Option<u64>,
+}
+
+impl Default for Redirects {
+    fn default() -> Self {
+        Self {
+            status: 302,
+            permanent_max_age: None,
+        }
+    }
+}
+
+impl Redirects {
+    /// REDIRECT_STATUS - 301, 302 (the default), 303, 307 or 308
+    /// REDIRECT_CACHE_SECS - lets browsers cache 301 and 308 redirects, off by default
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+        Self {
+            status: var("REDIRECT_STATUS")
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|s| REDIRECT_STATUSES.contains(s))
+                .unwrap_or(default.status),
+            permanent_max_age: var("REDIRECT_CACHE_SECS")
+                .and_then(|v| v.trim().parse().ok())
+                .or(default.permanent_max_age),
+        }
+    }
+
+    pub fn reply(&self, redirect: Redirect) -> Reply {
+        let status = redirect.status.unwrap_or(self.status);
+        let permanent = status == 301 || status == 308;
+        Reply {
+            status,
+            location: Some(redirect.url),
+            body: String::new(),
+            html: false,
+            max_age: self
+                .permanent_max_age
+                .filter(|_| permanent && redirect.cacheable),
+            set_cookie: redirect.cookie,
+        }
+    }
+}
+
+/// A response for the server or worker to send as is
+#[derive(Debug, PartialEq, Eq)]
+pub struct Reply {
+    pub status: u16,
+    /// Set for redirects
+    pub location: Option<String>,
+    pub body: String,
+    /// Whether the body is HTML rather than plain text
+    pub html: bool,
+    /// How many seconds it can be cached for, None if it shouldn't be
+    pub max_age: Option<u64>,
+    /// For the Set-Cookie header
+    pub set_cookie: Option<String>,
+}
+
+impl Reply {
+    /// For the Cache-Control header
+    pub fn cache_control(&self) -> String {
+        match self.max_age {
+            Some(secs) => format!("public, max-age={secs}"),
+            None => "no-store".to_string(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::text(404, "404 Not Found\n-- Riplakish --")
+    }
+
+    /// The form a password protected link shows, posting back to the same URL
+    pub fn password_form(wrong: bool) -> Self {
+        let error = if wrong {
+            "<p style=\"color: #f44336\">That password isn't right.</p>\n"
+        } else {
+            ""
+        };
+        let form = format!(
+            "{error}<form method=\"post\">
+<input type=\"password\" name=\"password\" placeholder=\"Password\" autofocus required>
+<button type=\"submit\">Continue</button>
+</form>"
+        );
+        Self {
+            status: if wrong { 401 } else { 200 },
+            location: None,
+            body: page("Password required", &form),
+            html: true,
+            max_age: None,
+            set_cookie: None,
+        }
+    }
+
+    /// What a request that only looks at a limited link gets, without the destination
+    pub fn preview() -> Self {
+        Self {
+            status: 200,
+            location: None,
+            body: page(
+                "Riplakish link",
+                "Open this link in a browser to follow it.",
+            ),
+            html: true,
+            max_age: None,
+            set_cookie: None,
+        }
+    }
+
+    pub fn too_many_attempts() -> Self {
+        Self {
+            status: 429,
+            location: None,
+            body: page(
+                "Too many attempts",
+                "Too many wrong passwords, try again later.",
+            ),
+            html: true,
+            max_age: None,
+            set_cookie: None,
+        }
+    }
+
+    fn text(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            location: None,
+            body: body.to_string(),
+            html: false,
+            max_age: None,
+            set_cookie: None,
+        }
+    }
+}
+
+/// What to show instead of redirecting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fallback {
+    /// Just the status code and a line of text
+    Status,
+    /// A Riplakish page saying what happened
+    Page,
+    /// Send the visitor somewhere else
+    Url(String),
+}
+
+impl Fallback {
+    /// "page", a URL, or anything else for the bare status
+    fn from_value(value: Option<String>) -> Self {
+        match value.as_deref().map(str::trim) {
+            Some("page") => Fallback::Page,
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                Fallback::Url(url.to_string())
+            }
+            _ => Fallback::Status,
+        }
+    }
+}
+
+/// How links that exist but aren't redirecting respond
+#[derive(Debug, Clone)]
+pub struct Fallbacks {
+    pub scheduled: Fallback,
+    pub expired: Fallback,
+    pub used_up: Fallback,
+}
+
+impl Default for Fallbacks {
+    fn default() -> Self {
+        Self {
+            scheduled: Fallback::Status,
+            expired: Fallback::Status,
+            used_up: Fallback::Status,
+        }
+    }
+}
+
+impl Fallbacks {
+    /// SCHEDULED_RESPONSE - unavailable (the default), page, or a URL to send visitors to before launch
+    /// EXPIRED_RESPONSE - gone (the default), page, or a URL to send visitors to
+    /// USED_UP_RESPONSE - the same, defaults to whatever EXPIRED_RESPONSE is
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        Self {
+            scheduled: Fallback::from_value(var("SCHEDULED_RESPONSE")),
+            expired: Fallback::from_value(var("EXPIRED_RESPONSE")),
+            used_up: Fallback::from_value(var("USED_UP_RESPONSE").or(var("EXPIRED_RESPONSE"))),
+        }
+    }
+
+    pub fn reply(&self, state: LinkState) -> Reply {
+        let fallback = match state {
+            LinkState::Scheduled => &self.scheduled,
+            LinkState::Expired => &self.expired,
+            LinkState::UsedUp => &self.used_up,
+            LinkState::Active => &Fallback::Status,
+        };
+        let (title, message) = state.describe();
+        match fallback {
+            Fallback::Status => Reply::text(
+                state.status(),
+                &format!("{} {title}\n-- Riplakish --", state.status()),
+            ),
+            Fallback::Page => Reply {
+                status: state.status(),
+                location: None,
+                body: page(title, message),
+                html: true,
+                max_age: None,
+                set_cookie: None,
+            },
+            Fallback::Url(url) => Reply {
+                status: 302,
+                location: Some(url.clone()),
+                body: String::new(),
+                html: false,
+                max_age: None,
+                set_cookie: None,
+            },
+        }
+    }
+}
+
+fn page(title: &str, message: &str) -> String {
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width\"><title>{title}</title></head>
+<body style=\"background: #242424; color: #fff; font-family: sans-serif; text-align: center; padding-top: 20vh\">
+<h1>{title}</h1>
+<div>{message}</div>
+<p style=\"color: #4caf50\">-- Riplakish --</p>
+</body>
+</html>
+"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        store::memory::MemoryStore,
+        utm::{UtmParams, UtmTemplate},
+    };
+
+    fn target(expires_at: Option<&str>) -> Option<Target> {
+        Some(Target {
+            url: "https://a.com/".to_string(),
+            rules: Rules {
+                expires_at: expires_at.map(str::to_string),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn to_a(status: Option<u16>, cacheable: bool) -> Outcome {
+        Outcome::Redirect(Redirect {
+            url: "https://a.com/".to_string(),
+            status,
+            cacheable,
+            utm_template: None,
+            destination: None,
+            cookie: None,
+        })
+    }
+
+    fn scheduled(not_before: &str, expires_at: &str) -> Option<Target> {
+        Some(Target {
+            url: "https://a.com/".to_string(),
+            rules: Rules {
+                not_before: Some(not_before.to_string()),
+                expires_at: Some(expires_at.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn expiry() {
+        let now = parse_time("2024-05-01T12:00:00Z").unwrap();
+        assert_eq!(resolve(None, now), Outcome::NotFound);
+        assert_eq!(resolve(target(None), now), to_a(None, true));
+        // The template's values can change from day to day
+        let tagged = target(None).map(|mut t| {
+            t.rules.utm_template = Some("spring".to_string());
+            t
+        });
+        assert_eq!(resolve(tagged, now), to_a(None, false));
+        assert_eq!(
+            resolve(target(Some("2024-05-01T13:00:00Z")), now),
+            to_a(None, false)
+        );
+        // Offsets are compared as instants
+        assert_eq!(
+            resolve(target(Some("2024-05-01T13:00:00+02:00")), now),
+            Outcome::Unavailable(LinkState::Expired)
+        );
+        assert_eq!(
+            resolve(target(Some("2024-05-01T12:00:00Z")), now),
+            Outcome::Unavailable(LinkState::Expired)
+        );
+    }
+
+    #[test]
+    fn schedule() {
+        let (start, end) = ("2024-05-01T12:00:00Z", "2024-05-02T12:00:00Z");
+        let at = |time: &str| resolve(scheduled(start, end), parse_time(time).unwrap());
+        assert_eq!(
+            at("2024-05-01T11:59:59Z"),
+            Outcome::Unavailable(LinkState::Scheduled)
+        );
+        assert_eq!(at("2024-05-01T12:00:00Z"), to_a(None, false));
+        assert_eq!(
+            at("2024-05-02T12:00:00Z"),
+            Outcome::Unavailable(LinkState::Expired)
+        );
+    }
+
+    #[tokio::test]
+    async fn max_visits() {
+        let store = MemoryStore::new();
+        store
+            .insert_link("a", "https://a.com/", "2024-05-01T00:00:00Z")
+            .await
+            .unwrap();
+        store
+            .update_rules(
+                "a",
+                &Rules {
+                    max_visits: Some(1),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let (visitor, limit, now) = (Visitor::default(), AttemptLimit::default(), Utc::now());
+        let looking = |kind| Visitor {
+            kind,
+            ..Default::default()
+        };
+        // Link checkers and chat apps don't use up the only visit
+        for kind in [ClickKind::Bot, ClickKind::Preview] {
+            let preview = match to_a(None, false) {
+                Outcome::Redirect(r) => Outcome::Preview(r),
+                outcome => outcome,
+            };
+            assert_eq!(
+                follow(&store, "a", &looking(kind), &limit, now, &mut || 0).await,
+                Ok(preview)
+            );
+        }
+        assert_eq!(
+            follow(&store, "a", &visitor, &limit, now, &mut || 0).await,
+            Ok(to_a(None, false))
+        );
+        assert_eq!(
+            follow(&store, "a", &visitor, &limit, now, &mut || 0).await,
+            Ok(Outcome::Unavailable(LinkState::UsedUp))
+        );
+        assert_eq!(
+            follow(
+                &store,
+                "a",
+                &looking(ClickKind::Bot),
+                &limit,
+                now,
+                &mut || 0
+            )
+            .await,
+            Ok(Outcome::Unavailable(LinkState::UsedUp))
+        );
+        assert_eq!(
+            follow(&store, "b", &visitor, &limit, now, &mut || 0).await,
+            Ok(Outcome::NotFound)
+        );
+    }
+
+    #[tokio::test]
+    async fn password() {
+        let store = MemoryStore::new();
+        store
+            .insert_link("a", "https://a.com/", "2024-05-01T00:00:00Z")
+            .await
+            .unwrap();
+        let rules = Rules {
+            password_hash: Some(passwords::hash_password("hunter2", &mut || 7)),
+            max_visits: Some(10),
+            ..Default::default()
+        };
+        store.update_rules("a", &rules).await.unwrap();
+
+        let limit = AttemptLimit {
+            max_attempts: 2,
+            window: Duration::minutes(1),
+        };
+        let now = Utc::now();
+        let follow = |password: Option<&str>, now| {
+            let visitor = Visitor {
+                ip: "127.0.0.1".to_string(),
+                password: password.map(str::to_string),
+                ..Default::default()
+            };
+            let (store, limit) = (&store, &limit);
+            async move {
+                follow(store, "a", &visitor, limit, now, &mut || 0)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(
+            follow(None, now).await,
+            Outcome::PasswordRequired { wrong: false }
+        );
+        assert_eq!(follow(Some("hunter2"), now).await, to_a(Some(303), false));
+        for _ in 0..2 {
+            assert_eq!(
+                follow(Some("nope"), now).await,
+                Outcome::PasswordRequired { wrong: true }
+            );
+        }
+        assert_eq!(follow(Some("hunter2"), now).await, Outcome::TooManyAttempts);
+        // Only the right password used up a visit
+        assert_eq!(store.get_link("a").await.unwrap().unwrap().uses, 1);
+
+        let later = now + Duration::minutes(2);
+        assert_eq!(follow(Some("hunter2"), later).await, to_a(Some(303), false));
+    }
+
+    #[tokio::test]
+    async fn passthrough() {
+        let store = MemoryStore::new();
+        store
+            .insert_link("a", "https://a.com/", "2024-05-01T00:00:00Z")
+            .await
+            .unwrap();
+        store
+            .insert_link("docs", "https://a.com/guide?v=1", "2024-05-01T00:00:00Z")
+            .await
+            .unwrap();
+        let rules = Rules {
+            forward_query: true,
+            forward_path: true,
+            ..Default::default()
+        };
+        store.update_rules("docs", &rules).await.unwrap();
+
+        let follow = |code: &'static str, path: Option<&str>, query: Option<&str>| {
+            let visitor = Visitor {
+                path: path.map(str::to_string),
+                query: query.map(str::to_string),
+                ..Default::default()
+            };
+            let store = &store;
+            async move {
+                let limit = AttemptLimit::default();
+                match follow(store, code, &visitor, &limit, Utc::now(), &mut || 0).await {
+                    Ok(Outcome::Redirect(r)) => Some(r.url),
+                    _ => None,
+                }
+            }
+        };
+        assert_eq!(
+            follow("docs", Some("foo/bar"), Some("utm_source=x")).await,
+            Some("https://a.com/guide/foo/bar?v=1&utm_source=x".to_string())
+        );
+        // Links that don't forward ignore the query and have nothing under them
+        assert_eq!(
+            follow("a", None, Some("utm_source=x")).await,
+            Some("https://a.com/".to_string())
+        );
+        assert_eq!(follow("a", Some("foo"), None).await, None);
+
+        // The link's tags win over the visitor's, the destination's over both
+        let template = UtmTemplate {
+            name: "spring".to_string(),
+            params: UtmParams {
+                source: Some("mail".to_string()),
+                campaign: Some("{code}".to_string()),
+                content: Some("{date}".to_string()),
+                ..Default::default()
+            },
+        };
+        store.save_template(&template).await.unwrap();
+        store
+            .update_url("docs", "https://a.com/?utm_content=fixed")
+            .await
+            .unwrap();
+        let rules = Rules {
+            utm_template: Some("spring".to_string()),
+            ..rules
+        };
+        store.update_rules("docs", &rules).await.unwrap();
+        assert_eq!(
+            follow("docs", None, Some("utm_source=x&ref=y")).await,
+            Some(
+                "https://a.com/?utm_content=fixed&utm_source=mail&utm_campaign=docs&ref=y"
+                    .to_string()
+            )
+        );
+        let visitor = Visitor::default();
+        let limit = AttemptLimit::default();
+        let Ok(Outcome::Redirect(redirect)) =
+            super::follow(&store, "docs", &visitor, &limit, Utc::now(), &mut || 0).await
+        else {
+            panic!("docs should redirect");
+        };
+        assert_eq!(redirect.utm_template.as_deref(), Some("spring"));
+    }
+
+    #[tokio::test]
+    async fn destinations() {
+        let store = MemoryStore::new();
+        store
+            .insert_link("ab", "https://a.com/", "2024-05-01T00:00:00Z")
+            .await
+            .unwrap();
+        let split = [("https://a.com/", 70), ("https://b.com/", 30)].map(|(url, weight)| {
+            crate::links::NewDestination {
+                url: url.to_string(),
+                weight,
+            }
+        });
+        store.set_destinations("ab", &split).await.unwrap();
+        let ids = store
+            .get_target("ab")
+            .await
+            .unwrap()
+            .unwrap()
+            .destinations
+            .iter()
+            .map(|d| d.id)
+            .collect::<Vec<u64>>();
+
+        let follow = |roll: u32, previous: Option<u64>| {
+            let visitor = Visitor {
+                destination: previous,
+                ..Default::default()
+            };
+            let store = &store;
+            async move {
+                let limit = AttemptLimit::default();
+                let mut random = || roll;
+                match follow(store, "ab", &visitor, &limit, Utc::now(), &mut random).await {
+                    Ok(Outcome::Redirect(r)) => r,
+                    outcome => panic!("ab should redirect, got {outcome:?}"),
+                }
+            }
+        };
+        for (roll, url) in [(0, "a"), (69, "a"), (70, "b"), (99, "b"), (100, "a")] {
+            let redirect = follow(roll, None).await;
+            assert_eq!(redirect.url, format!("https://{url}.com/"));
+            assert!(!redirect.cacheable && redirect.cookie.is_none());
+        }
+        assert_eq!(follow(0, None).await.destination, Some(ids[0]));
+        // The cookie is ignored until the link is sticky
+        assert_eq!(follow(0, Some(ids[1])).await.destination, Some(ids[0]));
+
+        let rules = Rules {
+            sticky: true,
+            ..Default::default()
+        };
+        store.update_rules("ab", &rules).await.unwrap();
+        let redirect = follow(0, Some(ids[1])).await;
+        assert_eq!(redirect.url, "https://b.com/");
+        let cookie = redirect.cookie.unwrap();
+        assert!(cookie.contains("Path=/r/ab;"));
+        assert_eq!(
+            sticky_destination(&format!("theme=dark; {cookie}")),
+            Some(ids[1])
+        );
+        // A destination that's gone is picked again
+        assert_eq!(follow(0, Some(1000)).await.destination, Some(ids[0]));
+        assert_eq!(sticky_destination("riplakish_destination=x"), None);
+
+        // Targeting goes before the split
+        let rules = Rules {
+            targeting: vec![crate::targeting::TargetingRule {
+                os: None,
+                browser: None,
+                language: Some("de".to_string()),
+                bot: None,
+                country: None,
+                url: "https://de.com/".to_string(),
+            }],
+            ..rules
+        };
+        store.update_rules("ab", &rules).await.unwrap();
+        let visitor = Visitor {
+            accept_language: Some("de-DE".to_string()),
+            ..Default::default()
+        };
+        let limit = AttemptLimit::default();
+        let outcome = super::follow(&store, "ab", &visitor, &limit, Utc::now(), &mut || 0).await;
+        let Ok(Outcome::Redirect(redirect)) = outcome else {
+            panic!("ab should redirect");
+        };
+        assert_eq!(
+            (redirect.url.as_str(), redirect.destination, redirect.cookie),
+            ("https://de.com/", None, None)
+        );
+        assert_eq!(follow(0, None).await.url, "https://a.com/");
+    }
+
+    #[test]
+    fn fallbacks() {
+        let fallbacks = |value: &str| {
+            Fallbacks::from_vars(|key| (key == "EXPIRED_RESPONSE").then(|| value.to_string()))
+        };
+        assert_eq!(fallbacks("gone").reply(LinkState::Expired).status, 410);
+        // Only EXPIRED_RESPONSE was set
+        assert_eq!(fallbacks("page").reply(LinkState::Scheduled).status, 503);
+        assert!(!fallbacks("page").reply(LinkState::Scheduled).html);
+
+        let page = fallbacks("page").reply(LinkState::Expired);
+        assert!(page.html && page.body.contains("This link has expired."));
+
+        let moved = fallbacks("https://example.com/expired").reply(LinkState::UsedUp);
+        assert_eq!(
+            (moved.status, moved.location.as_deref()),
+            (302, Some("https://example.com/expired"))
+        );
+    }
+
+    #[test]
+    fn redirects() {
+        let redirects = |vars: &[(&str, &str)]| {
+            let vars = vars.to_vec();
+            Redirects::from_vars(move |key| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            })
+        };
+        let redirect = |status: Option<u16>, cacheable: bool| Redirect {
+            url: "https://a.com/".to_string(),
+            status,
+            cacheable,
+            utm_template: None,
+            destination: None,
+            cookie: None,
+        };
+
+        let reply = redirects(&[]).reply(redirect(None, true));
+        assert_eq!(
+            (reply.status, reply.location.as_deref()),
+            (302, Some("https://a.com/"))
+        );
+        assert_eq!(reply.cache_control(), "no-store");
+        // Not a redirect status
+        assert_eq!(redirects(&[("REDIRECT_STATUS", "200")]).status, 302);
+
+        let cached = redirects(&[("REDIRECT_STATUS", "301"), ("REDIRECT_CACHE_SECS", "60")]);
+        assert_eq!(
+            cached.reply(redirect(None, true)).cache_control(),
+            "public, max-age=60"
+        );
+        // Temporary redirects and links that change are never cached
+        let reply = cached.reply(redirect(Some(307), true));
+        assert_eq!((reply.status, reply.max_age), (307, None));
+        assert_eq!(cached.reply(redirect(Some(308), false)).max_age, None);
+    }
+}
@@ -0,0 +1,344 @@
+// Jackson Coxson
+// Clicks over time. Bucket edges are worked out here in the requested timezone, so days and
+// months follow daylight saving, and the store only has to count the visits between each pair.
+
+use std::str::FromStr;
+
+use chrono::{
+    DateTime, Datelike, Days, Duration, Months, NaiveDate, NaiveDateTime, NaiveTime, SecondsFormat,
+    TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    links::LinkError,
+    store::LinkStore,
+    times::{self, timestamp},
+};
+
+/// A query asking for more than this needs a shorter range or bigger buckets
+const MAX_BUCKETS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Hour,
+    #[default]
+    Day,
+    /// Starting on Monday
+    Week,
+    Month,
+}
+
+/// Query string of the timeline routes
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SeriesQuery {
+    #[serde(default)]
+    pub bucket: Bucket,
+    /// An RFC 3339 time or a YYYY-MM-DD date in tz, defaults to a range that fits the bucket
+    pub from: Option<String>,
+    /// Same as from, defaults to now. The bucket it falls in is included.
+    pub to: Option<String>,
+    /// An IANA name like Europe/Zurich, defaults to UTC
+    pub tz: Option<String>,
+    /// Count bots and link previews too
+    #[serde(default)]
+    pub include_bots: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SeriesPoint {
+    /// When the bucket starts, RFC 3339 in the requested timezone
+    pub start: String,
+    pub visits: usize,
+}
+
+/// A bucket's edges as UTC log timestamps, start included and end not
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Range {
+    pub start: String,
+    pub end: String,
+}
+
+/// How ranges are handed to SQL, as a JSON array for json_each
+pub fn encode_ranges(ranges: &[Range]) -> String {
+    serde_json::to_string(ranges).unwrap_or_else(|_| "[]".to_string())
+}
+
+impl FromStr for Bucket {
+    type Err = LinkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            "week" => Ok(Bucket::Week),
+            "month" => Ok(Bucket::Month),
+            _ => Err(LinkError::InvalidQuery(format!(
+                "{s} isn't a bucket, use hour, day, week or month"
+            ))),
+        }
+    }
+}
+
+impl Bucket {
+    /// The start of the bucket a time falls in
+    fn floor(self, time: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        let local = time.with_timezone(&tz).naive_local();
+        let date = local.date();
+        let start = match self {
+            Bucket::Hour => date.and_time(NaiveTime::MIN) + Duration::hours(local.hour().into()),
+            Bucket::Day => date.and_time(NaiveTime::MIN),
+            Bucket::Week => {
+                let monday = date - Days::new(date.weekday().num_days_from_monday().into());
+                monday.and_time(NaiveTime::MIN)
+            }
+            Bucket::Month => date.with_day(1).unwrap_or(date).and_time(NaiveTime::MIN),
+        };
+        local_to_utc(tz, start)
+    }
+
+    /// The start of the bucket after the one starting at start
+    fn next(self, start: DateTime<Utc>, tz: Tz) -> DateTime<Utc> {
+        let date = start.with_timezone(&tz).date_naive();
+        let next = match self {
+            // An hour is an hour, even when the clocks change
+            Bucket::Hour => return start + Duration::hours(1),
+            Bucket::Day => date + Days::new(1),
+            Bucket::Week => date + Days::new(7),
+            Bucket::Month => date + Months::new(1),
+        };
+        local_to_utc(tz, next.and_time(NaiveTime::MIN))
+    }
+
+    /// Where a range ending at to starts if the query didn't say
+    fn default_from(self, to: DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Bucket::Hour => to - Duration::hours(23),
+            Bucket::Day => to - Duration::days(29),
+            Bucket::Week => to - Duration::weeks(11),
+            Bucket::Month => to - Months::new(11),
+        }
+    }
+}
+
+/// A local time as UTC. Times skipped by daylight saving move on to the first one after the gap.
+fn local_to_utc(tz: Tz, time: NaiveDateTime) -> DateTime<Utc> {
+    (0..=2)
+        .find_map(|hours| {
+            tz.from_local_datetime(&(time + Duration::hours(hours)))
+                .earliest()
+        })
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&time))
+}
+
+fn parse_time(value: &str, tz: Tz) -> Result<DateTime<Utc>, LinkError> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| local_to_utc(tz, date.and_time(NaiveTime::MIN)))
+        .map_err(|_| {
+            LinkError::InvalidQuery(format!(
+                "{value} isn't an RFC 3339 time or a YYYY-MM-DD date"
+            ))
+        })
+}
+
+/// Bucket starts from the one holding from to the one holding to, then the end of the last one
+fn edges(
+    bucket: Bucket,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    tz: Tz,
+) -> Result<Vec<DateTime<Utc>>, LinkError> {
+    if from > to {
+        return Err(LinkError::InvalidQuery("from is after to".to_string()));
+    }
+    let mut edges = vec![bucket.floor(from, tz)];
+    while let Some(&start) = edges.last().filter(|start| **start <= to) {
+        if edges.len() > MAX_BUCKETS {
+            return Err(LinkError::InvalidQuery(format!(
+                "that's more than {MAX_BUCKETS} buckets, use a shorter range or bigger buckets"
+            )));
+        }
+        edges.push(bucket.next(start, tz));
+    }
+    Ok(edges)
+}
+
+/// Visits per bucket for one link, or every link if code is None
+pub async fn clicks<S: LinkStore + ?Sized>(
+    store: &S,
+    code: Option<&str>,
+    query: &SeriesQuery,
+    now: DateTime<Utc>,
+) -> Result<Vec<SeriesPoint>, LinkError> {
+    let tz = times::zone(query.tz.as_deref())?;
+    let parse = |value: &Option<String>| value.as_deref().map(|v| parse_time(v, tz)).transpose();
+    let to = parse(&query.to)?.unwrap_or(now);
+    let from = parse(&query.from)?.unwrap_or_else(|| query.bucket.default_from(to));
+
+    let edges = edges(query.bucket, from, to, tz)?;
+    let ranges = edges
+        .windows(2)
+        .map(|pair| Range {
+            start: timestamp(pair[0]),
+            end: timestamp(pair[1]),
+        })
+        .collect::<Vec<_>>();
+    let visits = store
+        .count_visits(code, &ranges, query.include_bots)
+        .await?;
+    Ok(edges
+        .iter()
+        .zip(visits)
+        .map(|(start, visits)| SeriesPoint {
+            start: start
+                .with_timezone(&tz)
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            visits,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{memory::MemoryStore, Visit};
+
+    fn time(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn buckets() {
+        let zurich = "Europe/Zurich".parse::<Tz>().unwrap();
+        // Clocks went forward on 2024-03-31, that day only has 23 hours
+        let days = edges(
+            Bucket::Day,
+            time("2024-03-30T12:00:00Z"),
+            time("2024-04-01T12:00:00Z"),
+            zurich,
+        )
+        .unwrap();
+        assert_eq!(
+            days,
+            [
+                time("2024-03-29T23:00:00Z"),
+                time("2024-03-30T23:00:00Z"),
+                time("2024-03-31T22:00:00Z"),
+                time("2024-04-01T22:00:00Z"),
+            ]
+        );
+
+        let weeks = edges(
+            Bucket::Week,
+            time("2024-05-01T00:00:00Z"),
+            time("2024-05-01T00:00:00Z"),
+            Tz::UTC,
+        )
+        .unwrap();
+        assert_eq!(
+            weeks,
+            [time("2024-04-29T00:00:00Z"), time("2024-05-06T00:00:00Z")]
+        );
+
+        let months = edges(
+            Bucket::Month,
+            time("2024-01-31T00:00:00Z"),
+            time("2024-03-01T00:00:00Z"),
+            Tz::UTC,
+        )
+        .unwrap();
+        assert_eq!(months.len(), 4);
+        assert_eq!(months[1], time("2024-02-01T00:00:00Z"));
+
+        // India is half an hour off, hours still start on the local hour
+        let kolkata = "Asia/Kolkata".parse::<Tz>().unwrap();
+        let hours = edges(
+            Bucket::Hour,
+            time("2024-05-01T10:00:00Z"),
+            time("2024-05-01T10:45:00Z"),
+            kolkata,
+        )
+        .unwrap();
+        assert_eq!(
+            hours,
+            [
+                time("2024-05-01T09:30:00Z"),
+                time("2024-05-01T10:30:00Z"),
+                time("2024-05-01T11:30:00Z"),
+            ]
+        );
+
+        assert!(matches!(
+            edges(
+                Bucket::Hour,
+                time("2020-01-01T00:00:00Z"),
+                time("2024-01-01T00:00:00Z"),
+                Tz::UTC
+            ),
+            Err(LinkError::InvalidQuery(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn series() {
+        let store = MemoryStore::new();
+        let visit = |timestamp: &str| Visit {
+            timestamp: timestamp.to_string(),
+            ..Visit::new("asdf", "https://a.com/", "127.0.0.1")
+        };
+        store
+            .log_visits(vec![
+                visit("2024-05-01T21:59:59Z"),
+                visit("2024-05-01T22:00:00Z"),
+                visit("2024-05-02T12:00:00Z"),
+            ])
+            .await
+            .unwrap();
+
+        let query = SeriesQuery {
+            from: Some("2024-05-01".to_string()),
+            to: Some("2024-05-02".to_string()),
+            tz: Some("Europe/Zurich".to_string()),
+            ..Default::default()
+        };
+        let now = time("2024-06-01T00:00:00Z");
+        assert_eq!(
+            clicks(&store, Some("asdf"), &query, now).await,
+            Ok(vec![
+                SeriesPoint {
+                    start: "2024-05-01T00:00:00+02:00".to_string(),
+                    visits: 1,
+                },
+                SeriesPoint {
+                    start: "2024-05-02T00:00:00+02:00".to_string(),
+                    visits: 2,
+                },
+            ])
+        );
+        assert_eq!(clicks(&store, None, &query, now).await.unwrap().len(), 2);
+
+        let query = SeriesQuery {
+            bucket: Bucket::Month,
+            ..Default::default()
+        };
+        let points = clicks(&store, Some("asdf"), &query, now).await.unwrap();
+        assert_eq!(points.len(), 12);
+        assert_eq!(points[0].start, "2023-07-01T00:00:00Z");
+        assert_eq!(points[10].visits, 3);
+
+        let query = SeriesQuery {
+            tz: Some("Mars/Olympus".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            clicks(&store, None, &query, now).await,
+            Err(LinkError::InvalidQuery(_))
+        ));
+    }
+}
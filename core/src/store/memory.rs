@@ -0,0 +1,495 @@
+// Jackson Coxson
+// Keeps everything in memory, nothing survives a restart. Good for tests and trying things out.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+
+use super::{
+    DatabaseLog, DatabaseStats, Destination, DestinationStats, LinkChanges, LinkStore, NewLinkRow,
+    Rules, StoreError, StoreResult, Target, TemplateVisits, Visit,
+};
+use crate::{
+    agents::ClickKind, geo::CountryVisits, links::NewDestination, series::Range, utm::UtmTemplate,
+    visitors::LinkVisitors,
+};
+
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: Mutex<Inner>,
+}
+
+struct Redirect {
+    url: String,
+    comment: Option<String>,
+    created_at: String,
+    updated_at: String,
+    uses: u64,
+    rules: Rules,
+    destinations: Vec<Destination>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// code -> redirect
+    redirects: BTreeMap<String, Redirect>,
+    log: Vec<Visit>,
+    /// token -> expiration
+    tokens: BTreeMap<String, String>,
+    /// (code, ip) -> (count, window start)
+    attempts: BTreeMap<(String, String), (u64, String)>,
+    /// name -> template
+    templates: BTreeMap<String, UtmTemplate>,
+    /// Destination ids are never reused, like an autoincrement column
+    last_destination: u64,
+    /// day -> visitor salt
+    salts: BTreeMap<String, String>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> StoreResult<std::sync::MutexGuard<'_, Inner>> {
+        self.inner
+            .lock()
+            .map_err(|_| StoreError::Backend("memory store was poisoned".to_string()))
+    }
+}
+
+impl Inner {
+    /// Gives each new destination the next id
+    fn number(&mut self, destinations: &[NewDestination]) -> Vec<Destination> {
+        destinations
+            .iter()
+            .map(|d| {
+                self.last_destination += 1;
+                Destination {
+                    id: self.last_destination,
+                    url: d.url.clone(),
+                    weight: d.weight,
+                }
+            })
+            .collect()
+    }
+
+    fn visits(&self, code: &str, kind: ClickKind) -> usize {
+        self.log
+            .iter()
+            .filter(|v| v.code == code && v.kind == kind)
+            .count()
+    }
+
+    fn stats(&self, code: &str, redirect: &Redirect) -> DatabaseStats {
+        DatabaseStats {
+            url: redirect.url.clone(),
+            code: code.to_string(),
+            comment: redirect.comment.clone().unwrap_or_default(),
+            created_at: Some(redirect.created_at.clone()),
+            updated_at: Some(redirect.updated_at.clone()),
+            visits: self.visits(code, ClickKind::Human),
+            bot_visits: self.visits(code, ClickKind::Bot),
+            preview_visits: self.visits(code, ClickKind::Preview),
+            unique_visitors: self
+                .log
+                .iter()
+                .filter(|v| v.code == code && v.kind == ClickKind::Human)
+                .filter_map(|v| v.visitor.as_ref())
+                .map(|v| &v.hash)
+                .collect::<HashSet<_>>()
+                .len(),
+            uses: redirect.uses,
+            rules: redirect.rules.clone(),
+            destinations: redirect
+                .destinations
+                .iter()
+                .map(|destination| DestinationStats {
+                    destination: destination.clone(),
+                    visits: self
+                        .log
+                        .iter()
+                        .filter(|v| {
+                            v.code == code
+                                && v.kind == ClickKind::Human
+                                && v.destination == Some(destination.id)
+                        })
+                        .count(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+impl LinkStore for MemoryStore {
+    async fn get_target(&self, code: &str) -> StoreResult<Option<Target>> {
+        Ok(self.lock()?.redirects.get(code).map(|r| Target {
+            url: r.url.clone(),
+            rules: r.rules.clone(),
+            destinations: r.destinations.clone(),
+        }))
+    }
+
+    async fn get_link(&self, code: &str) -> StoreResult<Option<DatabaseStats>> {
+        let inner = self.lock()?;
+        Ok(inner
+            .redirects
+            .get(code)
+            .map(|redirect| inner.stats(code, redirect)))
+    }
+
+    async fn list_links(&self) -> StoreResult<Vec<DatabaseStats>> {
+        let inner = self.lock()?;
+        Ok(inner
+            .redirects
+            .iter()
+            .map(|(code, redirect)| inner.stats(code, redirect))
+            .collect())
+    }
+
+    async fn count_links(&self) -> StoreResult<usize> {
+        Ok(self.lock()?.redirects.len())
+    }
+
+    async fn insert_link_row(&self, code: &str, link: &NewLinkRow, now: &str) -> StoreResult<()> {
+        let mut inner = self.lock()?;
+        if inner.redirects.contains_key(code) {
+            return Err(StoreError::Conflict);
+        }
+        let destinations = inner.number(&link.destinations);
+        inner.redirects.insert(
+            code.to_string(),
+            Redirect {
+                url: link.url.clone(),
+                comment: link.comment.clone(),
+                created_at: now.to_string(),
+                updated_at: now.to_string(),
+                uses: 0,
+                rules: link.rules.clone(),
+                destinations,
+            },
+        );
+        Ok(())
+    }
+
+    async fn update_link_row(
+        &self,
+        code: &str,
+        changes: &LinkChanges,
+        now: &str,
+    ) -> StoreResult<()> {
+        let mut inner = self.lock()?;
+        if !inner.redirects.contains_key(code) {
+            return Ok(());
+        }
+        let destinations = changes.destinations.as_deref().map(|d| inner.number(d));
+        if let Some(redirect) = inner.redirects.get_mut(code) {
+            if let Some(url) = &changes.url {
+                redirect.url = url.clone();
+            }
+            if let Some(comment) = &changes.comment {
+                redirect.comment = Some(comment.clone());
+            }
+            if let Some(rules) = &changes.rules {
+                redirect.rules = rules.clone();
+            }
+            if let Some(destinations) = destinations {
+                redirect.destinations = destinations;
+            }
+            redirect.updated_at = now.to_string();
+        }
+        Ok(())
+    }
+
+    async fn touch_link(&self, code: &str, now: &str) -> StoreResult<()> {
+        if let Some(redirect) = self.lock()?.redirects.get_mut(code) {
+            redirect.updated_at = now.to_string();
+        }
+        Ok(())
+    }
+
+    async fn update_url(&self, code: &str, url: &str) -> StoreResult<()> {
+        if let Some(redirect) = self.lock()?.redirects.get_mut(code) {
+            redirect.url = url.to_string();
+        }
+        Ok(())
+    }
+
+    async fn update_comment(&self, code: &str, comment: &str) -> StoreResult<()> {
+        if let Some(redirect) = self.lock()?.redirects.get_mut(code) {
+            redirect.comment = Some(comment.to_string());
+        }
+        Ok(())
+    }
+
+    async fn update_rules(&self, code: &str, rules: &Rules) -> StoreResult<()> {
+        if let Some(redirect) = self.lock()?.redirects.get_mut(code) {
+            redirect.rules = rules.clone();
+        }
+        Ok(())
+    }
+
+    async fn claim_visit(&self, code: &str) -> StoreResult<bool> {
+        let mut inner = self.lock()?;
+        let redirect = match inner.redirects.get_mut(code) {
+            Some(r) => r,
+            None => return Ok(false),
+        };
+        if redirect
+            .rules
+            .max_visits
+            .is_some_and(|max| redirect.uses >= max)
+        {
+            return Ok(false);
+        }
+        redirect.uses += 1;
+        Ok(true)
+    }
+
+    async fn claim_attempt(
+        &self,
+        code: &str,
+        ip: &str,
+        now: &str,
+        since: &str,
+        max_attempts: u64,
+    ) -> StoreResult<bool> {
+        let mut inner = self.lock()?;
+        let attempt = inner
+            .attempts
+            .entry((code.to_string(), ip.to_string()))
+            .or_insert((0, now.to_string()));
+        if attempt.1.as_str() < since {
+            *attempt = (0, now.to_string());
+        }
+        if attempt.0 >= max_attempts {
+            return Ok(false);
+        }
+        attempt.0 += 1;
+        Ok(true)
+    }
+
+    async fn release_attempt(&self, code: &str, ip: &str) -> StoreResult<()> {
+        if let Some(attempt) = self
+            .lock()?
+            .attempts
+            .get_mut(&(code.to_string(), ip.to_string()))
+        {
+            attempt.0 = attempt.0.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    async fn set_destinations(
+        &self,
+        code: &str,
+        destinations: &[NewDestination],
+    ) -> StoreResult<()> {
+        let mut inner = self.lock()?;
+        if inner.redirects.contains_key(code) {
+            let destinations = inner.number(destinations);
+            if let Some(redirect) = inner.redirects.get_mut(code) {
+                redirect.destinations = destinations;
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_link(&self, code: &str) -> StoreResult<()> {
+        self.lock()?.redirects.remove(code);
+        Ok(())
+    }
+
+    async fn log_visit(&self, visit: Visit) -> StoreResult<()> {
+        self.lock()?.log.push(visit);
+        Ok(())
+    }
+
+    async fn get_logs(&self, code: &str) -> StoreResult<Vec<DatabaseLog>> {
+        let inner = self.lock()?;
+        let mut log = inner
+            .log
+            .iter()
+            .filter(|v| v.code == code)
+            .collect::<Vec<_>>();
+        // Stable, so visits in the same second stay in the order they were logged
+        log.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(log
+            .into_iter()
+            .map(|v| DatabaseLog {
+                timestamp: v.timestamp.clone(),
+                ip: v.ip.clone(),
+                url: v.url.clone(),
+                destination: v.destination,
+                location: v.location.clone(),
+                client: v.client.clone(),
+                kind: v.kind,
+                visitor: v.visitor.as_ref().map(|v| v.hash.clone()),
+            })
+            .collect())
+    }
+
+    async fn get_template(&self, name: &str) -> StoreResult<Option<UtmTemplate>> {
+        Ok(self.lock()?.templates.get(name).cloned())
+    }
+
+    async fn list_templates(&self) -> StoreResult<Vec<UtmTemplate>> {
+        Ok(self.lock()?.templates.values().cloned().collect())
+    }
+
+    async fn save_template(&self, template: &UtmTemplate) -> StoreResult<()> {
+        self.lock()?
+            .templates
+            .insert(template.name.clone(), template.clone());
+        Ok(())
+    }
+
+    async fn remove_template(&self, name: &str) -> StoreResult<()> {
+        let mut inner = self.lock()?;
+        inner.templates.remove(name);
+        for redirect in inner.redirects.values_mut() {
+            if redirect.rules.utm_template.as_deref() == Some(name) {
+                redirect.rules.utm_template = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn template_visits(&self, include_bots: bool) -> StoreResult<Vec<TemplateVisits>> {
+        let mut visits = BTreeMap::<String, usize>::new();
+        for template in self
+            .lock()?
+            .log
+            .iter()
+            .filter(|v| include_bots || v.kind == ClickKind::Human)
+            .filter_map(|v| v.utm_template.clone())
+        {
+            *visits.entry(template).or_default() += 1;
+        }
+        Ok(visits
+            .into_iter()
+            .map(|(template, visits)| TemplateVisits { template, visits })
+            .collect())
+    }
+
+    async fn country_visits(
+        &self,
+        code: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<CountryVisits>> {
+        let mut visits = BTreeMap::<Option<String>, usize>::new();
+        for visit in self.lock()?.log.iter().filter(|v| {
+            code.is_none_or(|code| v.code == code) && (include_bots || v.kind == ClickKind::Human)
+        }) {
+            *visits.entry(visit.location.country.clone()).or_default() += 1;
+        }
+        Ok(visits
+            .into_iter()
+            .map(|(country, visits)| CountryVisits { country, visits })
+            .collect())
+    }
+
+    async fn unique_visitors(
+        &self,
+        code: Option<&str>,
+        since: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<LinkVisitors>> {
+        let mut visitors = BTreeMap::<String, HashSet<String>>::new();
+        for visit in self.lock()?.log.iter().filter(|v| {
+            code.is_none_or(|code| v.code == code) && (include_bots || v.kind == ClickKind::Human)
+        }) {
+            let Some(visitor) = &visit.visitor else {
+                continue;
+            };
+            if since.is_some_and(|since| visitor.day.as_str() < since) {
+                continue;
+            }
+            visitors
+                .entry(visit.code.clone())
+                .or_default()
+                .insert(visitor.hash.clone());
+        }
+        Ok(visitors
+            .into_iter()
+            .map(|(code, visitors)| LinkVisitors {
+                code,
+                visitors: visitors.len(),
+            })
+            .collect())
+    }
+
+    async fn count_visits(
+        &self,
+        code: Option<&str>,
+        ranges: &[Range],
+        include_bots: bool,
+    ) -> StoreResult<Vec<usize>> {
+        let inner = self.lock()?;
+        let log = inner
+            .log
+            .iter()
+            .filter(|v| {
+                code.is_none_or(|code| v.code == code)
+                    && (include_bots || v.kind == ClickKind::Human)
+            })
+            .collect::<Vec<_>>();
+        Ok(ranges
+            .iter()
+            .map(|range| {
+                log.iter()
+                    .filter(|v| v.timestamp >= range.start && v.timestamp < range.end)
+                    .count()
+            })
+            .collect())
+    }
+
+    async fn day_salt(&self, day: &str, new_salt: &str) -> StoreResult<String> {
+        let mut inner = self.lock()?;
+        inner.salts.retain(|d, _| d.as_str() >= day);
+        Ok(inner
+            .salts
+            .entry(day.to_string())
+            .or_insert_with(|| new_salt.to_string())
+            .clone())
+    }
+
+    async fn insert_token(&self, token: &str, expiration: &str) -> StoreResult<()> {
+        self.lock()?
+            .tokens
+            .insert(token.to_string(), expiration.to_string());
+        Ok(())
+    }
+
+    async fn token_expiration(&self, token: &str) -> StoreResult<Option<String>> {
+        Ok(self.lock()?.tokens.get(token).cloned())
+    }
+
+    async fn remove_token(&self, token: &str) -> StoreResult<()> {
+        self.lock()?.tokens.remove(token);
+        Ok(())
+    }
+
+    async fn remove_expired_tokens(&self, now: &str) -> StoreResult<()> {
+        // Same string comparison SQLite does
+        self.lock()?
+            .tokens
+            .retain(|_, expiration| expiration.as_str() >= now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory() {
+        super::super::check_store(&MemoryStore::new()).await;
+    }
+}
@@ -0,0 +1,787 @@
+// Jackson Coxson
+// Storage interface. Handlers only talk to a LinkStore so they don't care where the data lives.
+// SQLite and D1 backends live next to the server and the worker, only the memory store is here.
+
+use std::fmt::Display;
+
+use async_trait::async_trait;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agents::{ClickKind, ClientInfo},
+    geo::{CountryVisits, Location},
+    links::{LinkError, NewDestination},
+    series::Range,
+    targeting::TargetingRule,
+    times::{self, timestamp},
+    utm::UtmTemplate,
+    visitors::{Fingerprint, LinkVisitors},
+};
+
+pub mod memory;
+
+/// Conditions on when and how a link redirects. Times are RFC 3339 in UTC.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rules {
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    /// How many times the link can be followed
+    #[serde(default)]
+    pub max_visits: Option<u64>,
+    /// When the link starts redirecting
+    #[serde(default)]
+    pub not_before: Option<String>,
+    /// From passwords::hash_password, never sent anywhere
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    /// 301, 302, 303, 307 or 308, None for the server default
+    #[serde(default)]
+    pub redirect_status: Option<u16>,
+    /// Pass the visitor's query string on to the destination
+    #[serde(default)]
+    pub forward_query: bool,
+    /// Treat the destination as a prefix for anything after the code
+    #[serde(default)]
+    pub forward_path: bool,
+    /// Name of the UTM template added to the destination
+    #[serde(default)]
+    pub utm_template: Option<String>,
+    /// Keep sending a visitor to the same destination, through a cookie
+    #[serde(default)]
+    pub sticky: bool,
+    /// Checked in order before anything else decides the destination
+    #[serde(default)]
+    pub targeting: Vec<TargetingRule>,
+}
+
+/// One of several places a link splits its visitors between
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Destination {
+    pub id: u64,
+    pub url: String,
+    /// Share of visits compared to the link's other destinations
+    pub weight: u32,
+}
+
+/// A destination along with how many logged visits went to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DestinationStats {
+    #[serde(flatten)]
+    pub destination: Destination,
+    pub visits: usize,
+}
+
+/// What a redirect needs to know about a code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Target {
+    pub url: String,
+    pub rules: Rules,
+    /// Used instead of url if there are any
+    pub destinations: Vec<Destination>,
+}
+
+/// Everything a link starts out with, written all at once by LinkStore::insert_link_row
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NewLinkRow {
+    pub url: String,
+    pub comment: Option<String>,
+    pub rules: Rules,
+    pub destinations: Vec<NewDestination>,
+}
+
+/// Changes written all at once by LinkStore::update_link_row, None leaves that part as it is
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkChanges {
+    pub url: Option<String>,
+    pub comment: Option<String>,
+    pub rules: Option<Rules>,
+    pub destinations: Option<Vec<NewDestination>>,
+}
+
+/// A redirect along with how many times it has been visited
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatabaseStats {
+    pub url: String,
+    pub code: String,
+    pub comment: String,
+    /// RFC 3339 in UTC, None for links made before these were kept
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    /// Logged visits from people, see bot_visits and preview_visits for the rest
+    pub visits: usize,
+    pub bot_visits: usize,
+    pub preview_visits: usize,
+    /// Distinct people by their daily fingerprint, see visitors
+    pub unique_visitors: usize,
+    /// Visits counted against max_visits, only counted while there is a limit
+    pub uses: u64,
+    #[serde(flatten)]
+    pub rules: Rules,
+    pub destinations: Vec<DestinationStats>,
+}
+
+/// How many logged visits had a UTM template added
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TemplateVisits {
+    pub template: String,
+    pub visits: usize,
+}
+
+/// One visit to a redirect
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DatabaseLog {
+    /// RFC 3339 in UTC
+    pub timestamp: String,
+    pub ip: String,
+    pub url: String,
+    /// Set if the link was split between destinations
+    pub destination: Option<u64>,
+    #[serde(flatten)]
+    pub location: Location,
+    #[serde(flatten)]
+    pub client: ClientInfo,
+    pub kind: ClickKind,
+    /// Hash of the visitor's IP and User-Agent, changes every day
+    pub visitor: Option<String>,
+}
+
+impl DatabaseLog {
+    /// Shows the time in tz instead of UTC
+    pub fn localize(mut self, tz: Tz) -> Self {
+        self.timestamp = times::localize(&self.timestamp, tz);
+        self
+    }
+}
+
+/// A visit that is about to be logged
+#[derive(Debug, Clone)]
+pub struct Visit {
+    pub code: String,
+    pub url: String,
+    pub ip: String,
+    /// RFC 3339 in UTC, which keeps the logs in order when compared as text
+    pub timestamp: String,
+    /// The UTM template that was added to the URL
+    pub utm_template: Option<String>,
+    /// Which of the link's destinations was picked
+    pub destination: Option<u64>,
+    pub location: Location,
+    /// Referrer, User-Agent and the like from the request
+    pub client: ClientInfo,
+    pub kind: ClickKind,
+    /// None if unique visitors aren't being counted
+    pub visitor: Option<Fingerprint>,
+}
+
+impl Visit {
+    /// A visit happening right now
+    pub fn new(code: &str, url: &str, ip: &str) -> Self {
+        Self {
+            code: code.to_string(),
+            url: url.to_string(),
+            ip: ip.to_string(),
+            timestamp: timestamp(chrono::Utc::now()),
+            utm_template: None,
+            destination: None,
+            location: Location::default(),
+            client: ClientInfo::default(),
+            kind: ClickKind::Human,
+            visitor: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoreError {
+    /// The code is already used by another redirect
+    Conflict,
+    Backend(String),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Conflict => write!(f, "Code is already in use"),
+            StoreError::Backend(e) => write!(f, "Storage error: {e}"),
+        }
+    }
+}
+
+impl From<StoreError> for LinkError {
+    fn from(value: StoreError) -> Self {
+        match value {
+            StoreError::Conflict => LinkError::Conflict,
+            StoreError::Backend(e) => {
+                log::error!("{e}");
+                LinkError::Failed
+            }
+        }
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Everything Riplakish keeps: redirects, the visit log and login sessions.
+/// Updates and removals of codes that don't exist are not errors.
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+pub trait LinkStore {
+    async fn get_target(&self, code: &str) -> StoreResult<Option<Target>>;
+    async fn get_link(&self, code: &str) -> StoreResult<Option<DatabaseStats>>;
+    async fn list_links(&self) -> StoreResult<Vec<DatabaseStats>>;
+    async fn count_links(&self) -> StoreResult<usize>;
+    /// Writes a link with its comment, rules and destinations in one transaction, so nothing is
+    /// left behind if any of it fails. Fails with StoreError::Conflict if the code is taken.
+    /// now is an RFC 3339 time in UTC, used for both created_at and updated_at.
+    async fn insert_link_row(&self, code: &str, link: &NewLinkRow, now: &str) -> StoreResult<()>;
+    /// Inserts a link with nothing but its URL, see insert_link_row
+    async fn insert_link(&self, code: &str, url: &str, now: &str) -> StoreResult<()> {
+        let link = NewLinkRow {
+            url: url.to_string(),
+            ..Default::default()
+        };
+        self.insert_link_row(code, &link, now).await
+    }
+    /// Writes every change to a link and sets updated_at to now in one transaction, so a failure
+    /// leaves the link as it was
+    async fn update_link_row(
+        &self,
+        code: &str,
+        changes: &LinkChanges,
+        now: &str,
+    ) -> StoreResult<()>;
+    /// Sets updated_at to the given RFC 3339 time in UTC
+    async fn touch_link(&self, code: &str, now: &str) -> StoreResult<()>;
+    async fn update_url(&self, code: &str, url: &str) -> StoreResult<()>;
+    async fn update_comment(&self, code: &str, comment: &str) -> StoreResult<()>;
+    /// Replaces every rule on a link
+    async fn update_rules(&self, code: &str, rules: &Rules) -> StoreResult<()>;
+    /// Counts one use if the link is under its max_visits, in a single atomic step.
+    /// Returns false if there are none left.
+    async fn claim_visit(&self, code: &str) -> StoreResult<bool>;
+    /// Counts a password attempt from an IP if it has made fewer than max_attempts, in a single
+    /// atomic step. A count that started before since starts over at now.
+    /// Returns false if there are none left.
+    async fn claim_attempt(
+        &self,
+        code: &str,
+        ip: &str,
+        now: &str,
+        since: &str,
+        max_attempts: u64,
+    ) -> StoreResult<bool>;
+    /// Gives back an attempt that turned out to have the right password
+    async fn release_attempt(&self, code: &str, ip: &str) -> StoreResult<()>;
+    /// Replaces every destination a link splits its visitors between, each one gets a new id
+    async fn set_destinations(
+        &self,
+        code: &str,
+        destinations: &[NewDestination],
+    ) -> StoreResult<()>;
+    /// Removes the link along with its destinations
+    async fn remove_link(&self, code: &str) -> StoreResult<()>;
+
+    async fn log_visit(&self, visit: Visit) -> StoreResult<()>;
+    /// Logs several visits at once, backends should do it in one transaction
+    async fn log_visits(&self, visits: Vec<Visit>) -> StoreResult<()> {
+        for visit in visits {
+            self.log_visit(visit).await?;
+        }
+        Ok(())
+    }
+    /// Oldest first, visits logged in the same second in the order they were logged
+    async fn get_logs(&self, code: &str) -> StoreResult<Vec<DatabaseLog>>;
+
+    async fn get_template(&self, name: &str) -> StoreResult<Option<UtmTemplate>>;
+    async fn list_templates(&self) -> StoreResult<Vec<UtmTemplate>>;
+    /// Adds a template, or replaces the one with the same name
+    async fn save_template(&self, template: &UtmTemplate) -> StoreResult<()>;
+    /// Removes a template and takes it off every link using it
+    async fn remove_template(&self, name: &str) -> StoreResult<()>;
+    /// Logged visits per template, visits without one aren't counted and bots only if asked for
+    async fn template_visits(&self, include_bots: bool) -> StoreResult<Vec<TemplateVisits>>;
+    /// Logged visits per country for one link, or every link if code is None, bots only if asked for.
+    /// Sorted by country, with visits that weren't located first.
+    async fn country_visits(
+        &self,
+        code: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<CountryVisits>>;
+    /// Distinct visitors per link, for one link or every link if code is None, sorted by code.
+    /// Only visits on or after the since day (YYYY-MM-DD) count, and bots only if asked for.
+    async fn unique_visitors(
+        &self,
+        code: Option<&str>,
+        since: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<LinkVisitors>>;
+    /// Logged visits in each range, in the same order, for one link or every link if code is None
+    async fn count_visits(
+        &self,
+        code: Option<&str>,
+        ranges: &[Range],
+        include_bots: bool,
+    ) -> StoreResult<Vec<usize>>;
+
+    /// The random salt for a UTC day (YYYY-MM-DD), saving new_salt if the day doesn't have one yet.
+    /// Salts from earlier days are deleted so their fingerprints can't be made again.
+    async fn day_salt(&self, day: &str, new_salt: &str) -> StoreResult<String>;
+
+    /// Stores a session token that expires at the given RFC 3339 time in UTC
+    async fn insert_token(&self, token: &str, expiration: &str) -> StoreResult<()>;
+    /// The RFC 3339 expiration of a token, if it exists
+    async fn token_expiration(&self, token: &str) -> StoreResult<Option<String>>;
+    async fn remove_token(&self, token: &str) -> StoreResult<()>;
+    /// Removes every token that expired before the given RFC 3339 time in UTC
+    async fn remove_expired_tokens(&self, now: &str) -> StoreResult<()>;
+}
+
+/// Runs the same checks against any backend so they all behave alike
+#[cfg(any(test, feature = "testing"))]
+pub async fn check_store(store: &(dyn LinkStore + Sync)) {
+    assert_eq!(store.get_target("asdf").await, Ok(None));
+    let created = "2024-05-01T10:00:00Z";
+    assert_eq!(
+        store
+            .insert_link("asdf", "https://google.com", created)
+            .await,
+        Ok(())
+    );
+    assert_eq!(
+        store
+            .insert_link("asdf", "https://google.com", created)
+            .await,
+        Err(StoreError::Conflict)
+    );
+    assert_eq!(
+        store.get_target("asdf").await,
+        Ok(Some(Target {
+            url: "https://google.com".to_string(),
+            ..Default::default()
+        }))
+    );
+    assert_eq!(store.count_links().await, Ok(1));
+
+    store
+        .update_url("asdf", "https://google.com/search?q=a&b=c")
+        .await
+        .unwrap();
+    store.update_comment("asdf", "hello there").await.unwrap();
+    store
+        .touch_link("asdf", "2024-05-01T11:00:00Z")
+        .await
+        .unwrap();
+    let rules = Rules {
+        expires_at: Some("2024-05-01T12:00:00Z".to_string()),
+        max_visits: Some(2),
+        not_before: Some("2024-04-01T12:00:00Z".to_string()),
+        password_hash: Some("hash".to_string()),
+        redirect_status: Some(308),
+        forward_query: true,
+        forward_path: true,
+        utm_template: Some("spring".to_string()),
+        sticky: true,
+        targeting: vec![TargetingRule {
+            os: Some(crate::agents::Os::Ios),
+            browser: None,
+            language: Some("en".to_string()),
+            bot: Some(false),
+            country: Some("US".to_string()),
+            url: "https://apps.apple.com/".to_string(),
+        }],
+    };
+    store.update_rules("asdf", &rules).await.unwrap();
+    assert_eq!(
+        store.get_target("asdf").await.unwrap().map(|t| t.rules),
+        Some(rules.clone())
+    );
+    assert_eq!(store.claim_visit("asdf").await, Ok(true));
+    assert_eq!(store.claim_visit("asdf").await, Ok(true));
+    assert_eq!(store.claim_visit("asdf").await, Ok(false));
+    assert_eq!(store.claim_visit("nope").await, Ok(false));
+
+    let (ip, since) = ("127.0.0.1", "2024-05-01T12:00:00Z");
+    for (now, claimed) in [
+        ("2024-05-01T12:00:00Z", true),
+        ("2024-05-01T12:01:00Z", true),
+        ("2024-05-01T12:02:00Z", false),
+    ] {
+        assert_eq!(
+            store.claim_attempt("asdf", ip, now, since, 2).await,
+            Ok(claimed)
+        );
+    }
+    assert_eq!(
+        store.claim_attempt("asdf", "::1", since, since, 2).await,
+        Ok(true)
+    );
+    // A right password gives its attempt back
+    store.release_attempt("asdf", ip).await.unwrap();
+    assert_eq!(
+        store.claim_attempt("asdf", ip, since, since, 2).await,
+        Ok(true)
+    );
+    assert_eq!(
+        store.claim_attempt("asdf", ip, since, since, 2).await,
+        Ok(false)
+    );
+    // A count that started before the window doesn't matter any more
+    let (now, since) = ("2024-05-01T13:00:00Z", "2024-05-01T12:30:00Z");
+    assert_eq!(
+        store.claim_attempt("asdf", ip, now, since, 2).await,
+        Ok(true)
+    );
+    assert_eq!(
+        store.claim_attempt("asdf", ip, now, since, 2).await,
+        Ok(true)
+    );
+    assert_eq!(
+        store.claim_attempt("asdf", ip, now, since, 2).await,
+        Ok(false)
+    );
+
+    let split = |url: &str, weight| NewDestination {
+        url: url.to_string(),
+        weight,
+    };
+    store
+        .set_destinations("asdf", &[split("https://old.com/", 1)])
+        .await
+        .unwrap();
+    store
+        .set_destinations(
+            "asdf",
+            &[split("https://a.com/", 70), split("https://b.com/", 30)],
+        )
+        .await
+        .unwrap();
+    let destinations = store
+        .get_target("asdf")
+        .await
+        .unwrap()
+        .unwrap()
+        .destinations;
+    let first = destinations[0].id;
+    let client = ClientInfo::new(
+        Some("https://t.co/"),
+        Some("Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/124.0.0.0 Safari/537.36"),
+        Some("en-US,en;q=0.9"),
+        Some("riplakish.test"),
+    );
+    let location = Location {
+        country: Some("US".to_string()),
+        region: Some("CA".to_string()),
+        city: Some("San Francisco".to_string()),
+        asn: Some(15169),
+    };
+    assert_eq!(
+        destinations
+            .iter()
+            .map(|d| (d.url.as_str(), d.weight))
+            .collect::<Vec<_>>(),
+        vec![("https://a.com/", 70), ("https://b.com/", 30)]
+    );
+    store
+        .log_visit(Visit {
+            code: "asdf".to_string(),
+            url: "https://google.com/search?q=a&b=c".to_string(),
+            ip: "127.0.0.1".to_string(),
+            timestamp: "2024-05-01T12:00:00Z".to_string(),
+            utm_template: Some("spring".to_string()),
+            destination: Some(first),
+            location: location.clone(),
+            client: client.clone(),
+            kind: ClickKind::Human,
+            visitor: Some(Fingerprint {
+                day: "2024-05-01".to_string(),
+                hash: "aaaa".to_string(),
+            }),
+        })
+        .await
+        .unwrap();
+    // Bots and previews are logged but only counted on their own
+    for kind in [ClickKind::Bot, ClickKind::Preview] {
+        store
+            .log_visit(Visit {
+                destination: Some(first),
+                utm_template: Some("spring".to_string()),
+                kind,
+                visitor: (kind == ClickKind::Bot).then(|| Fingerprint {
+                    day: "2024-05-02".to_string(),
+                    hash: "bbbb".to_string(),
+                }),
+                ..Visit::new("asdf", "https://google.com/search?q=a&b=c", "127.0.0.1")
+            })
+            .await
+            .unwrap();
+    }
+
+    store
+        .log_visits(vec![
+            Visit::new("other", "https://a.com", "127.0.0.1"),
+            Visit::new("other", "https://a.com", "127.0.0.1"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(store.get_logs("other").await.unwrap().len(), 2);
+
+    let link = DatabaseStats {
+        url: "https://google.com/search?q=a&b=c".to_string(),
+        code: "asdf".to_string(),
+        comment: "hello there".to_string(),
+        created_at: Some(created.to_string()),
+        updated_at: Some("2024-05-01T11:00:00Z".to_string()),
+        visits: 1,
+        bot_visits: 1,
+        preview_visits: 1,
+        unique_visitors: 1,
+        uses: 2,
+        rules,
+        destinations: destinations
+            .into_iter()
+            .zip([1, 0])
+            .map(|(destination, visits)| DestinationStats {
+                destination,
+                visits,
+            })
+            .collect(),
+    };
+    assert_eq!(store.get_link("asdf").await, Ok(Some(link.clone())));
+    assert_eq!(store.list_links().await, Ok(vec![link]));
+    let logs = store.get_logs("asdf").await.unwrap();
+    assert_eq!(logs.len(), 3);
+    assert_eq!(
+        logs.iter().map(|l| l.kind).collect::<Vec<_>>(),
+        vec![ClickKind::Human, ClickKind::Bot, ClickKind::Preview]
+    );
+    assert_eq!(logs[0].destination, Some(first));
+    assert_eq!(logs[0].visitor.as_deref(), Some("aaaa"));
+    let visitors = |visitors| {
+        Ok(vec![LinkVisitors {
+            code: "asdf".to_string(),
+            visitors,
+        }])
+    };
+    assert_eq!(
+        store.unique_visitors(Some("asdf"), None, false).await,
+        visitors(1)
+    );
+    assert_eq!(
+        store.unique_visitors(Some("asdf"), None, true).await,
+        visitors(2)
+    );
+    assert_eq!(
+        store.unique_visitors(None, Some("2024-05-02"), true).await,
+        visitors(1)
+    );
+    assert_eq!(
+        store.unique_visitors(None, Some("2024-05-02"), false).await,
+        Ok(vec![])
+    );
+    assert_eq!(logs[0].location, location);
+    assert_eq!(logs[0].client, client);
+    let ranges = [
+        ("2024-05-01T00:00:00Z", "2024-05-01T12:00:00Z"),
+        ("2024-05-01T12:00:00Z", "2024-05-02T00:00:00Z"),
+        ("2024-05-02T00:00:00Z", "9999-01-01T00:00:00Z"),
+    ]
+    .map(|(start, end)| Range {
+        start: start.to_string(),
+        end: end.to_string(),
+    });
+    assert_eq!(
+        store.count_visits(Some("asdf"), &ranges, false).await,
+        Ok(vec![0, 1, 0])
+    );
+    assert_eq!(
+        store.count_visits(Some("asdf"), &ranges, true).await,
+        Ok(vec![0, 1, 2])
+    );
+    assert_eq!(
+        store.count_visits(None, &ranges, false).await,
+        Ok(vec![0, 1, 2])
+    );
+    let visits = |country: Option<&str>, visits| CountryVisits {
+        country: country.map(str::to_string),
+        visits,
+    };
+    assert_eq!(
+        store.country_visits(Some("asdf"), false).await,
+        Ok(vec![visits(Some("US"), 1)])
+    );
+    assert_eq!(
+        store.country_visits(Some("asdf"), true).await,
+        Ok(vec![visits(None, 2), visits(Some("US"), 1)])
+    );
+    assert_eq!(
+        store.country_visits(None, false).await,
+        Ok(vec![visits(None, 2), visits(Some("US"), 1)])
+    );
+    assert_eq!(
+        store.country_visits(None, true).await,
+        Ok(vec![visits(None, 4), visits(Some("US"), 1)])
+    );
+
+    let template = UtmTemplate {
+        name: "spring".to_string(),
+        params: crate::utm::UtmParams {
+            source: Some("mail".to_string()),
+            campaign: Some("{code}".to_string()),
+            ..Default::default()
+        },
+    };
+    store.save_template(&template).await.unwrap();
+    store.save_template(&template).await.unwrap();
+    assert_eq!(
+        store.get_template("spring").await,
+        Ok(Some(template.clone()))
+    );
+    assert_eq!(store.list_templates().await, Ok(vec![template]));
+    let visits = |visits| {
+        Ok(vec![TemplateVisits {
+            template: "spring".to_string(),
+            visits,
+        }])
+    };
+    assert_eq!(store.template_visits(false).await, visits(1));
+    assert_eq!(store.template_visits(true).await, visits(3));
+    store.remove_template("spring").await.unwrap();
+    assert_eq!(store.get_template("spring").await, Ok(None));
+    assert_eq!(
+        store
+            .get_target("asdf")
+            .await
+            .unwrap()
+            .unwrap()
+            .rules
+            .utm_template,
+        None
+    );
+
+    store.remove_link("asdf").await.unwrap();
+    assert_eq!(store.get_link("asdf").await, Ok(None));
+    // The destinations went with it
+    store
+        .insert_link("asdf", "https://google.com", created)
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_target("asdf")
+            .await
+            .unwrap()
+            .unwrap()
+            .destinations,
+        vec![]
+    );
+    store.remove_link("asdf").await.unwrap();
+
+    // A new link is written with everything it starts with
+    let link = NewLinkRow {
+        url: "https://google.com".to_string(),
+        comment: Some("split".to_string()),
+        rules: Rules {
+            max_visits: Some(3),
+            sticky: true,
+            ..Default::default()
+        },
+        destinations: vec![NewDestination {
+            url: "https://a.com/".to_string(),
+            weight: 1,
+        }],
+    };
+    store.insert_link_row("full", &link, created).await.unwrap();
+    assert_eq!(
+        store.insert_link_row("full", &link, created).await,
+        Err(StoreError::Conflict)
+    );
+    let stats = store.get_link("full").await.unwrap().unwrap();
+    assert_eq!(
+        (stats.comment.as_str(), &stats.rules),
+        ("split", &link.rules)
+    );
+    // The conflicting insert didn't add its destinations either
+    assert_eq!(
+        stats
+            .destinations
+            .iter()
+            .map(|d| d.destination.url.as_str())
+            .collect::<Vec<_>>(),
+        vec!["https://a.com/"]
+    );
+    // Changes are written together, anything left out stays as it was
+    let changes = LinkChanges {
+        url: Some("https://b.com/".to_string()),
+        rules: Some(Rules::default()),
+        destinations: Some(vec![]),
+        ..Default::default()
+    };
+    store
+        .update_link_row("full", &changes, "2024-05-02T00:00:00Z")
+        .await
+        .unwrap();
+    let stats = store.get_link("full").await.unwrap().unwrap();
+    assert_eq!(
+        (
+            stats.url.as_str(),
+            stats.comment.as_str(),
+            &stats.rules,
+            stats.destinations.len(),
+            stats.updated_at.as_deref()
+        ),
+        (
+            "https://b.com/",
+            "split",
+            &Rules::default(),
+            0,
+            Some("2024-05-02T00:00:00Z")
+        )
+    );
+    store.remove_link("full").await.unwrap();
+
+    let salt = |day, new| store.day_salt(day, new);
+    assert_eq!(salt("2024-05-01", "a").await, Ok("a".to_string()));
+    assert_eq!(salt("2024-05-01", "b").await, Ok("a".to_string()));
+    assert_eq!(salt("2024-05-02", "c").await, Ok("c".to_string()));
+    // The first day's salt was thrown away with the day
+    assert_eq!(salt("2024-05-01", "d").await, Ok("d".to_string()));
+    assert_eq!(salt("2024-05-02", "e").await, Ok("c".to_string()));
+
+    store
+        .insert_token("token", "2024-05-01T12:00:00Z")
+        .await
+        .unwrap();
+    assert_eq!(
+        store.token_expiration("token").await,
+        Ok(Some("2024-05-01T12:00:00Z".to_string()))
+    );
+    store
+        .remove_expired_tokens("2024-05-01T13:00:00Z")
+        .await
+        .unwrap();
+    assert_eq!(store.token_expiration("token").await, Ok(None));
+
+    let visit = |timestamp: &str, ip: &str| Visit {
+        timestamp: timestamp.to_string(),
+        ..Visit::new("order", "https://a.com", ip)
+    };
+    store
+        .log_visits(vec![
+            visit("2024-05-02T00:00:00Z", "1"),
+            visit("2024-05-01T00:00:00Z", "2"),
+            visit("2024-05-02T00:00:00Z", "3"),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(
+        store
+            .get_logs("order")
+            .await
+            .unwrap()
+            .iter()
+            .map(|l| l.ip.as_str())
+            .collect::<Vec<_>>(),
+        vec!["2", "1", "3"]
+    );
+}
@@ -0,0 +1,247 @@
+// Jackson Coxson
+// Per-link rules that send visitors somewhere else depending on their device, browser or language.
+// The first rule that matches wins, visitors that match none go to the link's own destination.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    agents::{self, Browser, ClickKind, Os, UserAgent},
+    geo,
+    links::LinkError,
+    resolve::Visitor,
+    urls::UrlPolicy,
+};
+
+const MAX_RULES: usize = 32;
+
+/// Conditions that are left out match everyone
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TargetingRule {
+    #[serde(default)]
+    pub os: Option<Os>,
+    #[serde(default)]
+    pub browser: Option<Browser>,
+    /// A language tag like en or pt-br, matched against the visitor's preferred language
+    #[serde(default)]
+    pub language: Option<String>,
+    /// true for crawlers and link previews, false for people
+    #[serde(default)]
+    pub bot: Option<bool>,
+    /// Two letter country code, only known if there's somewhere to look visitors up
+    #[serde(default)]
+    pub country: Option<String>,
+    pub url: String,
+}
+
+impl TargetingRule {
+    pub fn matches(
+        &self,
+        agent: &UserAgent,
+        kind: ClickKind,
+        language: Option<&str>,
+        country: Option<&str>,
+    ) -> bool {
+        self.os.is_none_or(|os| os == agent.os)
+            && self.browser.is_none_or(|browser| browser == agent.browser)
+            && self.bot.is_none_or(|bot| bot == (kind != ClickKind::Human))
+            && self
+                .country
+                .as_deref()
+                .is_none_or(|wanted| country == Some(wanted))
+            && self.language.as_deref().is_none_or(|wanted| {
+                // en matches en-us, but en-us doesn't match en
+                language.is_some_and(|language| {
+                    language == wanted
+                        || language
+                            .strip_prefix(wanted)
+                            .is_some_and(|rest| rest.starts_with('-'))
+                })
+            })
+    }
+}
+
+/// The first rule for the visitor, if any
+pub fn find<'a>(rules: &'a [TargetingRule], visitor: &Visitor) -> Option<&'a TargetingRule> {
+    if rules.is_empty() {
+        return None;
+    }
+    let agent = UserAgent::parse(visitor.user_agent.as_deref().unwrap_or_default());
+    let language = visitor
+        .accept_language
+        .as_deref()
+        .and_then(agents::preferred_language);
+    rules.iter().find(|rule| {
+        rule.matches(
+            &agent,
+            visitor.kind,
+            language.as_deref(),
+            visitor.country.as_deref(),
+        )
+    })
+}
+
+/// Checks rules from the API, normalizing their URLs and languages
+pub fn check_rules(
+    policy: &UrlPolicy,
+    rules: Vec<TargetingRule>,
+) -> Result<Vec<TargetingRule>, LinkError> {
+    if rules.len() > MAX_RULES {
+        return Err(LinkError::InvalidRule(format!(
+            "a link can have at most {MAX_RULES} targeting rules"
+        )));
+    }
+    rules
+        .into_iter()
+        .map(|rule| {
+            let language = rule
+                .language
+                .map(|l| l.trim().to_ascii_lowercase())
+                .filter(|l| !l.is_empty());
+            if let Some(language) = &language {
+                let valid = language.split('-').all(|part| {
+                    !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric())
+                });
+                if !valid {
+                    return Err(LinkError::InvalidRule(format!(
+                        "{language} isn't a language tag"
+                    )));
+                }
+            }
+            let country = rule
+                .country
+                .as_deref()
+                .map(geo::check_country)
+                .transpose()?;
+            if rule.os.is_none()
+                && rule.browser.is_none()
+                && language.is_none()
+                && rule.bot.is_none()
+                && country.is_none()
+            {
+                return Err(LinkError::InvalidRule(
+                    "targeting rules need at least one of os, browser, language, bot or country"
+                        .to_string(),
+                ));
+            }
+            Ok(TargetingRule {
+                url: policy.validate(&rule.url).map_err(LinkError::InvalidUrl)?,
+                language,
+                country,
+                ..rule
+            })
+        })
+        .collect()
+}
+
+/// How rules are kept in the redirects table, NULL when there are none
+pub fn encode(rules: &[TargetingRule]) -> Option<String> {
+    if rules.is_empty() {
+        return None;
+    }
+    serde_json::to_string(rules).ok()
+}
+
+pub fn decode(column: Option<&str>) -> Vec<TargetingRule> {
+    let column = match column {
+        Some(c) => c,
+        None => return Vec::new(),
+    };
+    serde_json::from_str(column).unwrap_or_else(|e| {
+        warn!("Ignoring unreadable targeting rules: {e}");
+        Vec::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPHONE: &str = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_4 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Mobile/15E148 Safari/604.1";
+    const ANDROID: &str = "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Mobile Safari/537.36";
+
+    fn rule(json: &str) -> TargetingRule {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn targeting() {
+        let rules = vec![
+            rule(r#"{"os": "ios", "url": "https://apps.apple.com/"}"#),
+            rule(r#"{"os": "android", "language": "de", "url": "https://play.google.com/?hl=de"}"#),
+            rule(r#"{"os": "android", "url": "https://play.google.com/"}"#),
+            rule(r#"{"bot": true, "url": "https://a.com/preview"}"#),
+        ];
+        let url = |ua: Option<&str>, language: Option<&str>| {
+            let visitor = Visitor {
+                user_agent: ua.map(str::to_string),
+                accept_language: language.map(str::to_string),
+                kind: agents::classify(false, ua, []),
+                ..Default::default()
+            };
+            find(&rules, &visitor).map(|r| r.url.as_str())
+        };
+        assert_eq!(url(Some(IPHONE), None), Some("https://apps.apple.com/"));
+        assert_eq!(
+            url(Some(ANDROID), Some("de-AT, en;q=0.5")),
+            Some("https://play.google.com/?hl=de")
+        );
+        assert_eq!(
+            url(Some(ANDROID), Some("en, de;q=0.5")),
+            Some("https://play.google.com/")
+        );
+        assert_eq!(url(None, None), Some("https://a.com/preview"));
+        let windows = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) Chrome/124.0.0.0 Safari/537.36";
+        assert_eq!(url(Some(windows), None), None);
+
+        // dev isn't de
+        let android = UserAgent::parse(ANDROID);
+        assert!(!rules[1].matches(&android, ClickKind::Human, Some("dev"), None));
+        // Chat apps fetching a preview count as bots, whatever browser they claim to be
+        assert!(rules[3].matches(&android, ClickKind::Preview, None, None));
+
+        let rules = vec![rule(r#"{"country": "CH", "url": "https://a.ch/"}"#)];
+        let visitor = |country: Option<&str>| Visitor {
+            country: country.map(str::to_string),
+            ..Default::default()
+        };
+        assert!(find(&rules, &visitor(Some("CH"))).is_some());
+        assert!(find(&rules, &visitor(Some("DE"))).is_none());
+        assert!(find(&rules, &visitor(None)).is_none());
+    }
+
+    #[test]
+    fn check() {
+        let policy = UrlPolicy::default();
+        let rules = check_rules(
+            &policy,
+            vec![rule(
+                r#"{"language": " PT-BR ", "country": "br", "url": "example.com"}"#,
+            )],
+        )
+        .unwrap();
+        assert_eq!(rules[0].language.as_deref(), Some("pt-br"));
+        assert_eq!(rules[0].country.as_deref(), Some("BR"));
+        assert_eq!(rules[0].url, "https://example.com/");
+        assert_eq!(decode(encode(&rules).as_deref()), rules);
+        assert_eq!(encode(&[]), None);
+
+        for bad in [
+            r#"{"url": "example.com"}"#,
+            r#"{"language": "en_US", "url": "example.com"}"#,
+            r#"{"country": "USA", "url": "example.com"}"#,
+        ] {
+            assert!(matches!(
+                check_rules(&policy, vec![rule(bad)]),
+                Err(LinkError::InvalidRule(_))
+            ));
+        }
+        assert!(matches!(
+            check_rules(
+                &policy,
+                vec![rule(r#"{"os": "ios", "url": "javascript:alert(1)"}"#)]
+            ),
+            Err(LinkError::InvalidUrl(_))
+        ));
+    }
+}
@@ -0,0 +1,72 @@
+// Jackson Coxson
+// Every time Riplakish stores is RFC 3339 in UTC, so they sort and compare as text.
+// Clients can ask to see them in their own timezone, which only changes the output.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+use crate::links::LinkError;
+
+/// Query string asking for times in a timezone other than UTC
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DisplayZone {
+    /// An IANA name like Europe/Zurich
+    pub tz: Option<String>,
+}
+
+impl DisplayZone {
+    pub fn zone(&self) -> Result<Tz, LinkError> {
+        zone(self.tz.as_deref())
+    }
+}
+
+/// How a time is stored, like 2024-05-01T12:00:00Z
+pub fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// The timezone with the given IANA name, UTC if there is none
+pub fn zone(name: Option<&str>) -> Result<Tz, LinkError> {
+    match name {
+        Some(name) => name
+            .parse()
+            .map_err(|_| LinkError::InvalidQuery(format!("{name} isn't a timezone"))),
+        None => Ok(Tz::UTC),
+    }
+}
+
+/// A stored time as it reads in tz, anything that isn't RFC 3339 is left alone
+pub fn localize(time: &str, tz: Tz) -> String {
+    match DateTime::parse_from_rfc3339(time) {
+        Ok(t) => t
+            .with_timezone(&tz)
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+        Err(_) => time.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zones() {
+        assert_eq!(zone(None), Ok(Tz::UTC));
+        assert!(matches!(
+            zone(Some("Nope")),
+            Err(LinkError::InvalidQuery(_))
+        ));
+
+        let zurich = zone(Some("Europe/Zurich")).unwrap();
+        assert_eq!(
+            localize("2024-05-01T12:00:00Z", zurich),
+            "2024-05-01T14:00:00+02:00"
+        );
+        assert_eq!(
+            localize("2024-01-01T12:00:00+00:00", Tz::UTC),
+            "2024-01-01T12:00:00Z"
+        );
+        assert_eq!(localize("someday", zurich), "someday");
+    }
+}
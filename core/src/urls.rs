@@ -0,0 +1,227 @@
+// Jackson Coxson
+
+use std::{collections::HashSet, fmt::Display};
+
+use percent_encoding::percent_decode_str;
+use url::{form_urlencoded, ParseError, Url};
+
+const DEFAULT_SCHEMES: &[&str] = &["http", "https", "mailto", "tel"];
+
+/// Decides which destinations a link is allowed to point at
+#[derive(Clone, Debug)]
+pub struct UrlPolicy {
+    pub schemes: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UrlError {
+    Unparseable,
+    Scheme(String),
+}
+
+impl Display for UrlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UrlError::Unparseable => write!(f, "URL could not be parsed"),
+            UrlError::Scheme(s) => write!(f, "URLs with the {s} scheme are not allowed"),
+        }
+    }
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        Self {
+            schemes: DEFAULT_SCHEMES.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl UrlPolicy {
+    /// ALLOWED_SCHEMES - comma separated list of schemes links may point to
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        match var("ALLOWED_SCHEMES") {
+            Some(s) => Self {
+                schemes: s
+                    .split(',')
+                    .map(|s| s.trim().to_lowercase())
+                    .filter(|s| !s.is_empty())
+                    .collect(),
+            },
+            None => Self::default(),
+        }
+    }
+
+    /// Parses a destination and returns it normalized.
+    /// Anything without a scheme, like `example.com/page`, is treated as https.
+    pub fn validate(&self, raw: &str) -> Result<String, UrlError> {
+        let raw = raw.trim();
+        let url = match Url::parse(raw) {
+            Ok(u) => u,
+            Err(ParseError::RelativeUrlWithoutBase) => {
+                Url::parse(&format!("https://{raw}")).map_err(|_| UrlError::Unparseable)?
+            }
+            Err(_) => return Err(UrlError::Unparseable),
+        };
+
+        if !self.schemes.iter().any(|s| s == url.scheme()) {
+            return Err(UrlError::Scheme(url.scheme().to_string()));
+        }
+        Ok(url.to_string())
+    }
+}
+
+/// What comes after the code in a /r/{code}/... request path, still percent-encoded
+pub fn path_suffix(path: &str) -> Option<&str> {
+    let (_, suffix) = path.strip_prefix("/r/")?.split_once('/')?;
+    Some(suffix).filter(|s| !s.is_empty())
+}
+
+/// A path segment with its percent-encoding undone, the same way axum's Path extractor does it.
+/// None if it isn't UTF-8 once decoded.
+pub fn decode_segment(segment: &str) -> Option<String> {
+    percent_decode_str(segment)
+        .decode_utf8()
+        .ok()
+        .map(|s| s.into_owned())
+}
+
+/// . and .., encoded or not
+fn is_dot_segment(segment: &str) -> bool {
+    matches!(
+        segment.to_ascii_lowercase().replace("%2e", ".").as_str(),
+        "." | ".."
+    )
+}
+
+/// Adds a path onto the end of a destination's, so a link can act as a prefix.
+/// Dot segments are dropped so the path can't climb out of the destination's.
+pub fn append_path(url: &mut Url, suffix: &str) {
+    if url.cannot_be_a_base() {
+        return;
+    }
+    // Browsers treat a backslash like a slash in http URLs
+    let suffix = suffix
+        .split(['/', '\\'])
+        .filter(|s| !is_dot_segment(s))
+        .collect::<Vec<&str>>()
+        .join("/");
+    let suffix = suffix.trim_start_matches('/');
+    if suffix.is_empty() {
+        return;
+    }
+    let path = format!("{}/{suffix}", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+}
+
+/// Adds query parameters to a destination. Ones the destination already has are left as they are.
+pub fn merge_query(url: &mut Url, query: &str) {
+    let existing = url
+        .query_pairs()
+        .map(|(key, _)| key.into_owned())
+        .collect::<HashSet<String>>();
+    let extra = query
+        .split('&')
+        .filter(|pair| {
+            form_urlencoded::parse(pair.as_bytes())
+                .next()
+                .is_some_and(|(key, _)| !existing.contains(key.as_ref()))
+        })
+        .collect::<Vec<&str>>()
+        .join("&");
+    if extra.is_empty() {
+        return;
+    }
+    let query = match url.query() {
+        Some(q) if !q.is_empty() => format!("{q}&{extra}"),
+        _ => extra,
+    };
+    url.set_query(Some(&query));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destinations() {
+        let policy = UrlPolicy::default();
+        assert_eq!(
+            policy.validate("https://example.com/search?q=a%20b&x=1#top"),
+            Ok("https://example.com/search?q=a%20b&x=1#top".to_string())
+        );
+        assert_eq!(
+            policy.validate("example.com/~user"),
+            Ok("https://example.com/~user".to_string())
+        );
+        assert_eq!(
+            policy.validate("mailto:someone@example.com"),
+            Ok("mailto:someone@example.com".to_string())
+        );
+        assert_eq!(
+            policy.validate("javascript:alert(1)"),
+            Err(UrlError::Scheme("javascript".to_string()))
+        );
+        assert_eq!(policy.validate("https://"), Err(UrlError::Unparseable));
+    }
+
+    #[test]
+    fn passthrough() {
+        let forward = |destination: &str, suffix: Option<&str>, query: Option<&str>| {
+            let mut url = Url::parse(destination).unwrap();
+            if let Some(suffix) = suffix {
+                append_path(&mut url, suffix);
+            }
+            if let Some(query) = query {
+                merge_query(&mut url, query);
+            }
+            url.to_string()
+        };
+        assert_eq!(path_suffix("/r/docs/foo/bar"), Some("foo/bar"));
+        assert_eq!(path_suffix("/r/docs/"), None);
+        assert_eq!(path_suffix("/r/docs"), None);
+        assert_eq!(
+            decode_segment("50%25%20off+more%2Fless").as_deref(),
+            Some("50% off+more/less")
+        );
+        assert_eq!(decode_segment("%FF"), None);
+
+        assert_eq!(
+            forward("https://a.com/guide/", Some("foo/bar%20baz"), None),
+            "https://a.com/guide/foo/bar%20baz"
+        );
+        assert_eq!(
+            forward("https://a.com/guide?v=1#top", Some("foo/"), Some("q=a+b")),
+            "https://a.com/guide/foo/?v=1&q=a+b#top"
+        );
+        assert_eq!(
+            forward("https://a.com/guide", Some("../%2e%2E/..\\admin"), None),
+            "https://a.com/guide/admin"
+        );
+        // The destination's own parameters win
+        assert_eq!(
+            forward(
+                "https://a.com/?utm_source=mail",
+                None,
+                Some("utm_source=x&ref=y&")
+            ),
+            "https://a.com/?utm_source=mail&ref=y"
+        );
+        assert_eq!(
+            forward("mailto:someone@example.com", Some("foo"), None),
+            "mailto:someone@example.com"
+        );
+    }
+
+    #[test]
+    fn from_vars() {
+        let policy = UrlPolicy::from_vars(|key| match key {
+            "ALLOWED_SCHEMES" => Some("HTTPS, ftp".to_string()),
+            _ => None,
+        });
+        assert!(policy.validate("ftp://example.com/file").is_ok());
+        assert_eq!(
+            policy.validate("http://example.com"),
+            Err(UrlError::Scheme("http".to_string()))
+        );
+    }
+}
@@ -0,0 +1,199 @@
+// Jackson Coxson
+// Named sets of UTM parameters. Links with a template get them added as they redirect,
+// so destinations stay clean and every link in a campaign is tagged the same way.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::form_urlencoded;
+
+use crate::{
+    links::{LinkError, StatsOptions},
+    store::{LinkStore, TemplateVisits},
+};
+
+const MAX_NAME_LENGTH: usize = 64;
+
+/// Values can use {code} and {date}, filled in when a link is followed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtmParams {
+    pub source: Option<String>,
+    pub medium: Option<String>,
+    pub campaign: Option<String>,
+    pub term: Option<String>,
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtmTemplate {
+    pub name: String,
+    #[serde(flatten)]
+    pub params: UtmParams,
+}
+
+impl UtmParams {
+    fn pairs(&self) -> [(&'static str, &Option<String>); 5] {
+        [
+            ("utm_source", &self.source),
+            ("utm_medium", &self.medium),
+            ("utm_campaign", &self.campaign),
+            ("utm_term", &self.term),
+            ("utm_content", &self.content),
+        ]
+    }
+
+    /// The query string for following a link right now
+    pub fn render(&self, code: &str, now: DateTime<Utc>) -> String {
+        let date = now.format("%Y-%m-%d").to_string();
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        for (key, value) in self.pairs() {
+            if let Some(value) = value {
+                query.append_pair(key, &value.replace("{code}", code).replace("{date}", &date));
+            }
+        }
+        query.finish()
+    }
+
+    /// Blank values are dropped, and there has to be something left
+    fn clean(self) -> Result<Self, LinkError> {
+        let clean = |value: Option<String>| {
+            value
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let params = Self {
+            source: clean(self.source),
+            medium: clean(self.medium),
+            campaign: clean(self.campaign),
+            term: clean(self.term),
+            content: clean(self.content),
+        };
+        if params == Self::default() {
+            return Err(LinkError::InvalidRule(
+                "a template needs at least one parameter".to_string(),
+            ));
+        }
+        Ok(params)
+    }
+}
+
+fn check_name(name: &str) -> Result<(), LinkError> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(LinkError::InvalidRule(format!(
+            "template names are 1 to {MAX_NAME_LENGTH} letters, numbers, - or _"
+        )));
+    }
+    Ok(())
+}
+
+/// Makes sure a template a link is about to use exists
+pub async fn check_exists<S: LinkStore + ?Sized>(store: &S, name: &str) -> Result<(), LinkError> {
+    match store.get_template(name).await? {
+        Some(_) => Ok(()),
+        None => Err(LinkError::InvalidRule(format!(
+            "there's no utm_template called {name}"
+        ))),
+    }
+}
+
+/// Adds a template, or replaces the one with the same name
+pub async fn save_template<S: LinkStore + ?Sized>(
+    store: &S,
+    name: &str,
+    params: UtmParams,
+) -> Result<UtmTemplate, LinkError> {
+    check_name(name)?;
+    let template = UtmTemplate {
+        name: name.to_string(),
+        params: params.clean()?,
+    };
+    store.save_template(&template).await?;
+    Ok(template)
+}
+
+pub async fn list_templates<S: LinkStore + ?Sized>(
+    store: &S,
+) -> Result<Vec<UtmTemplate>, LinkError> {
+    Ok(store.list_templates().await?)
+}
+
+/// Links using the template are left without one
+pub async fn delete_template<S: LinkStore + ?Sized>(
+    store: &S,
+    name: &str,
+) -> Result<(), LinkError> {
+    if store.get_template(name).await?.is_none() {
+        return Err(LinkError::TemplateNotFound);
+    }
+    store.remove_template(name).await?;
+    Ok(())
+}
+
+pub async fn template_visits<S: LinkStore + ?Sized>(
+    store: &S,
+    options: &StatsOptions,
+) -> Result<Vec<TemplateVisits>, LinkError> {
+    Ok(store.template_visits(options.include_bots).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resolve::parse_time, store::memory::MemoryStore};
+
+    #[test]
+    fn render() {
+        let params = UtmParams {
+            source: Some("newsletter".to_string()),
+            campaign: Some("{code} {date}".to_string()),
+            ..Default::default()
+        };
+        let now = parse_time("2024-05-01T23:00:00-02:00").unwrap();
+        assert_eq!(
+            params.render("asdf", now),
+            "utm_source=newsletter&utm_campaign=asdf+2024-05-02"
+        );
+    }
+
+    #[tokio::test]
+    async fn templates() {
+        let store = MemoryStore::new();
+        let params = UtmParams {
+            source: Some(" mail ".to_string()),
+            medium: Some("".to_string()),
+            ..Default::default()
+        };
+        let template = save_template(&store, "spring", params).await.unwrap();
+        assert_eq!(template.params.source.as_deref(), Some("mail"));
+        assert_eq!(template.params.medium, None);
+        assert_eq!(list_templates(&store).await.unwrap(), vec![template]);
+        assert_eq!(check_exists(&store, "spring").await, Ok(()));
+
+        assert!(matches!(
+            save_template(&store, "spring", UtmParams::default()).await,
+            Err(LinkError::InvalidRule(_))
+        ));
+        assert!(matches!(
+            check_exists(&store, "summer").await,
+            Err(LinkError::InvalidRule(_))
+        ));
+        let params = UtmParams {
+            source: Some("mail".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            save_template(&store, "no spaces", params).await,
+            Err(LinkError::InvalidRule(_))
+        ));
+
+        assert_eq!(delete_template(&store, "spring").await, Ok(()));
+        assert_eq!(
+            delete_template(&store, "spring").await,
+            Err(LinkError::TemplateNotFound)
+        );
+    }
+}
@@ -0,0 +1,212 @@
+// Jackson Coxson
+// Unique visitors are counted from a keyed hash of the IP and User-Agent, never the IP itself.
+// The key mixes in a random salt for the UTC day that's thrown away once the day is over,
+// so the same person on two different days can't be linked, even by whoever has the secret.
+
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    passwords::hex,
+    store::{LinkStore, StoreResult},
+};
+
+/// Bytes of the hash that are kept, plenty to tell a day's visitors apart
+const FINGERPRINT_BYTES: usize = 16;
+/// Random words in a day's salt
+const SALT_WORDS: usize = 8;
+
+/// Distinct visitors to a link
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkVisitors {
+    pub code: String,
+    pub visitors: usize,
+}
+
+/// Who a visit came from, only comparable with other fingerprints from the same day
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// The UTC day as YYYY-MM-DD
+    pub day: String,
+    pub hash: String,
+}
+
+pub struct Fingerprinter {
+    secret: Vec<u8>,
+    /// The day and salt last handed out by the store
+    salt: Mutex<Option<(String, String)>>,
+}
+
+impl Fingerprinter {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            secret: secret.to_vec(),
+            salt: Mutex::new(None),
+        }
+    }
+
+    /// Reads VISITOR_SECRET, which should be long and random
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        var("VISITOR_SECRET")
+            .filter(|s| !s.is_empty())
+            .map(|s| Self::new(s.as_bytes()))
+    }
+
+    /// ip is the one address the visit came from, never a list a client could add to
+    pub async fn fingerprint<S: LinkStore + ?Sized>(
+        &self,
+        store: &S,
+        ip: &str,
+        user_agent: Option<&str>,
+        now: DateTime<Utc>,
+        random: &mut (impl FnMut() -> u32 + Send),
+    ) -> StoreResult<Fingerprint> {
+        let day = day(now);
+        let key = mac(
+            &self.secret,
+            self.salt(store, &day, random).await?.as_bytes(),
+        );
+        let input = format!("{ip}\n{}", user_agent.unwrap_or_default());
+        let hash = mac(&key, input.as_bytes());
+        Ok(Fingerprint {
+            day,
+            hash: hex(&hash[..FINGERPRINT_BYTES]),
+        })
+    }
+
+    /// The day's salt, only asking the store once the day has changed
+    async fn salt<S: LinkStore + ?Sized>(
+        &self,
+        store: &S,
+        day: &str,
+        random: &mut (impl FnMut() -> u32 + Send),
+    ) -> StoreResult<String> {
+        if let Some((d, salt)) = &*self.lock() {
+            if d == day {
+                return Ok(salt.clone());
+            }
+        }
+        let new = (0..SALT_WORDS)
+            .flat_map(|_| random().to_le_bytes())
+            .collect::<Vec<u8>>();
+        let salt = store.day_salt(day, &hex(&new)).await?;
+        *self.lock() = Some((day.to_string(), salt.clone()));
+        Ok(salt)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<(String, String)>> {
+        // Only ever holds a finished value, so a panic elsewhere can't leave it half written
+        self.salt.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn mac(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The UTC day as YYYY-MM-DD
+pub fn day(time: DateTime<Utc>) -> String {
+    time.format("%Y-%m-%d").to_string()
+}
+
+/// The first day of a window of days ending today
+pub fn window_start(now: DateTime<Utc>, days: u32) -> String {
+    day(now - Duration::days(i64::from(days.max(1)) - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use crate::store::memory::MemoryStore;
+
+    async fn fingerprint(
+        store: &MemoryStore,
+        fingerprinter: &Fingerprinter,
+        ip: &str,
+        user_agent: &str,
+        now: DateTime<Utc>,
+    ) -> Fingerprint {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let mut random = || COUNTER.fetch_add(1, Ordering::Relaxed);
+        fingerprinter
+            .fingerprint(store, ip, Some(user_agent), now, &mut random)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn fingerprints() {
+        let now = "2024-05-01T23:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let later = now + Duration::minutes(30);
+        let tomorrow = now + Duration::hours(2);
+        let store = MemoryStore::new();
+        let fingerprinter = Fingerprinter::new(b"secret");
+
+        let first = fingerprint(&store, &fingerprinter, "203.0.113.7", "a", now).await;
+        assert_eq!(first.day, "2024-05-01");
+        assert_eq!(first.hash.len(), FINGERPRINT_BYTES * 2);
+        assert!(!first.hash.contains("203"));
+        assert_eq!(
+            fingerprint(&store, &fingerprinter, "203.0.113.7", "a", later).await,
+            first
+        );
+        assert_ne!(
+            fingerprint(&store, &fingerprinter, "203.0.113.7", "b", now).await,
+            first
+        );
+        assert_ne!(
+            fingerprint(&store, &fingerprinter, "203.0.113.8", "a", now).await,
+            first
+        );
+        // The salt is kept in the store, so a restart still recognises people
+        assert_eq!(
+            fingerprint(
+                &store,
+                &Fingerprinter::new(b"secret"),
+                "203.0.113.7",
+                "a",
+                now
+            )
+            .await,
+            first
+        );
+        assert_ne!(
+            fingerprint(
+                &store,
+                &Fingerprinter::new(b"other"),
+                "203.0.113.7",
+                "a",
+                now
+            )
+            .await,
+            first
+        );
+
+        // A new day gets a new salt and the old one is gone, so yesterday can't be hashed again
+        let next = fingerprint(&store, &fingerprinter, "203.0.113.7", "a", tomorrow).await;
+        assert_eq!(next.day, "2024-05-02");
+        assert_ne!(next.hash, first.hash);
+        assert_ne!(
+            fingerprint(
+                &store,
+                &Fingerprinter::new(b"secret"),
+                "203.0.113.7",
+                "a",
+                now
+            )
+            .await,
+            first
+        );
+
+        assert_eq!(window_start(now, 7), "2024-04-25");
+        assert_eq!(window_start(now, 0), "2024-05-01");
+    }
+}
@@ -0,0 +1,7 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Tables as they were before migrations existed, so old databases are picked up as version 1
+CREATE TABLE IF NOT EXISTS log (id INTEGER PRIMARY KEY AUTOINCREMENT, timestamp DATETIME, redirect TEXT, url TEXT, ip TEXT);
+CREATE TABLE IF NOT EXISTS redirects (id INTEGER PRIMARY KEY AUTOINCREMENT, url TEXT, redirect TEXT, comment TEXT);
+CREATE TABLE IF NOT EXISTS tokens (id INTEGER PRIMARY KEY AUTOINCREMENT, token TEXT, expiration DATETIME);
+INSERT INTO schema_version (version, name, applied_at) VALUES (1, 'initial', datetime('now'));
@@ -0,0 +1,7 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Codes could be added twice before this. Visitors always got the first one, so only it is kept.
+DELETE FROM redirects
+WHERE redirect IS NOT NULL AND id NOT IN (SELECT MIN(id) FROM redirects GROUP BY redirect);
+CREATE UNIQUE INDEX IF NOT EXISTS redirects_redirect ON redirects (redirect);
+INSERT INTO schema_version (version, name, applied_at) VALUES (2, 'unique_codes', datetime('now'));
@@ -0,0 +1,5 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- RFC 3339 time after which a link stops redirecting, NULL for never
+ALTER TABLE redirects ADD COLUMN expires_at TEXT;
+INSERT INTO schema_version (version, name, applied_at) VALUES (3, 'link_expiry', datetime('now'));
@@ -0,0 +1,6 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- How many times a link can be followed, NULL for no limit, and how many times it has been
+ALTER TABLE redirects ADD COLUMN max_visits INTEGER;
+ALTER TABLE redirects ADD COLUMN uses INTEGER NOT NULL DEFAULT 0;
+INSERT INTO schema_version (version, name, applied_at) VALUES (4, 'max_visits', datetime('now'));
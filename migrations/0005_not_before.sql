@@ -0,0 +1,5 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- RFC 3339 time before which a link doesn't redirect yet, NULL for right away
+ALTER TABLE redirects ADD COLUMN not_before TEXT;
+INSERT INTO schema_version (version, name, applied_at) VALUES (5, 'not_before', datetime('now'));
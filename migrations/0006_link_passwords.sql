@@ -0,0 +1,7 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Salted hash of a link's password, NULL if it doesn't have one
+ALTER TABLE redirects ADD COLUMN password_hash TEXT;
+-- Wrong passwords per link and IP, counted from window_start
+CREATE TABLE IF NOT EXISTS password_attempts (redirect TEXT NOT NULL, ip TEXT NOT NULL, count INTEGER NOT NULL, window_start TEXT NOT NULL, PRIMARY KEY (redirect, ip));
+INSERT INTO schema_version (version, name, applied_at) VALUES (6, 'link_passwords', datetime('now'));
@@ -0,0 +1,5 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- HTTP status a link redirects with, NULL for the server default
+ALTER TABLE redirects ADD COLUMN redirect_status INTEGER;
+INSERT INTO schema_version (version, name, applied_at) VALUES (7, 'redirect_status', datetime('now'));
@@ -0,0 +1,6 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Whether a link passes the visitor's query string and extra path on to its destination
+ALTER TABLE redirects ADD COLUMN forward_query INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE redirects ADD COLUMN forward_path INTEGER NOT NULL DEFAULT 0;
+INSERT INTO schema_version (version, name, applied_at) VALUES (8, 'passthrough', datetime('now'));
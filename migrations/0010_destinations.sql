@@ -0,0 +1,10 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Links that split their visitors between several destinations by weight
+CREATE TABLE IF NOT EXISTS destinations (id INTEGER PRIMARY KEY AUTOINCREMENT, redirect TEXT NOT NULL, url TEXT NOT NULL, weight INTEGER NOT NULL);
+CREATE INDEX IF NOT EXISTS destinations_redirect ON destinations (redirect);
+-- Whether a visitor keeps getting the same destination
+ALTER TABLE redirects ADD COLUMN sticky INTEGER NOT NULL DEFAULT 0;
+-- The destination a visit was sent to, NULL for links with just a url
+ALTER TABLE log ADD COLUMN destination INTEGER;
+INSERT INTO schema_version (version, name, applied_at) VALUES (10, 'destinations', datetime('now'));
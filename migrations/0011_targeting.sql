@@ -0,0 +1,5 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Ordered device, browser and language rules as JSON, NULL if the link has none
+ALTER TABLE redirects ADD COLUMN targeting TEXT;
+INSERT INTO schema_version (version, name, applied_at) VALUES (11, 'targeting', datetime('now'));
@@ -0,0 +1,9 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Where each visit came from, NULL when it couldn't be looked up
+ALTER TABLE log ADD COLUMN country TEXT;
+ALTER TABLE log ADD COLUMN region TEXT;
+ALTER TABLE log ADD COLUMN city TEXT;
+ALTER TABLE log ADD COLUMN asn INTEGER;
+CREATE INDEX IF NOT EXISTS log_country ON log (country);
+INSERT INTO schema_version (version, name, applied_at) VALUES (12, 'geoip', datetime('now'));
@@ -0,0 +1,12 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- What each visitor's request said about them, NULL when a header was missing
+ALTER TABLE log ADD COLUMN referrer TEXT;
+ALTER TABLE log ADD COLUMN user_agent TEXT;
+ALTER TABLE log ADD COLUMN accept_language TEXT;
+ALTER TABLE log ADD COLUMN host TEXT;
+-- Parsed from user_agent
+ALTER TABLE log ADD COLUMN browser TEXT;
+ALTER TABLE log ADD COLUMN os TEXT;
+ALTER TABLE log ADD COLUMN device TEXT;
+INSERT INTO schema_version (version, name, applied_at) VALUES (13, 'client_info', datetime('now'));
@@ -0,0 +1,6 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- human, bot or preview. Visits logged before this are assumed to be people.
+ALTER TABLE log ADD COLUMN kind TEXT NOT NULL DEFAULT 'human';
+CREATE INDEX IF NOT EXISTS log_redirect_kind ON log (redirect, kind);
+INSERT INTO schema_version (version, name, applied_at) VALUES (14, 'click_kind', datetime('now'));
@@ -0,0 +1,7 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Daily hash of the visitor's IP and User-Agent, and the UTC day it was made on
+ALTER TABLE log ADD COLUMN visitor TEXT;
+ALTER TABLE log ADD COLUMN day TEXT;
+CREATE INDEX IF NOT EXISTS log_redirect_day ON log (redirect, day);
+INSERT INTO schema_version (version, name, applied_at) VALUES (15, 'unique_visitors', datetime('now'));
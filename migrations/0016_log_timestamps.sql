@@ -0,0 +1,16 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Log times were written as MM/DD/YYYY HH:MM:SS in the server's local time, which can't be
+-- compared as text. They become RFC 3339 in UTC like new ones. The 'utc' modifier reads them as
+-- local time, so run this in the timezone the server logged in (TZ for SQLite, D1 is always UTC).
+UPDATE log
+SET timestamp = strftime(
+    '%Y-%m-%dT%H:%M:%SZ',
+    substr(timestamp, 7, 4) || '-' || substr(timestamp, 1, 2) || '-' || substr(timestamp, 4, 2)
+        || ' ' || substr(timestamp, 12, 8),
+    'utc'
+)
+WHERE timestamp LIKE '__/__/____ __:__:__';
+CREATE INDEX IF NOT EXISTS log_timestamp ON log (timestamp);
+CREATE INDEX IF NOT EXISTS log_redirect_timestamp ON log (redirect, timestamp);
+INSERT INTO schema_version (version, name, applied_at) VALUES (16, 'log_timestamps', datetime('now'));
@@ -0,0 +1,9 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- Token expirations were written with the server's UTC offset, they're stored in UTC like everything else now
+UPDATE tokens SET expiration = strftime('%Y-%m-%dT%H:%M:%SZ', expiration)
+WHERE strftime('%Y-%m-%dT%H:%M:%SZ', expiration) IS NOT NULL;
+-- When a link was made and last edited, left NULL for links made before they were kept
+ALTER TABLE redirects ADD COLUMN created_at TEXT;
+ALTER TABLE redirects ADD COLUMN updated_at TEXT;
+INSERT INTO schema_version (version, name, applied_at) VALUES (17, 'utc_times', datetime('now'));
@@ -0,0 +1,5 @@
+-- Generated by riplakish d1-migrations, edit core/migrations instead
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY, name TEXT, applied_at DATETIME);
+-- The random salt fingerprints are made with, only the current UTC day's is kept
+CREATE TABLE IF NOT EXISTS visitor_salts (day TEXT PRIMARY KEY, salt TEXT NOT NULL);
+INSERT INTO schema_version (version, name, applied_at) VALUES (18, 'visitor_salts', datetime('now'));
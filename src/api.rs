@@ -0,0 +1,173 @@
+// Jackson Coxson
+// Versioned JSON API, everything here lives under /api/v2
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use log::{info, warn};
+use riplakish_core::{
+    links::{self, ApiError, LinkError, LinkUpdate, NewLink, StatsOptions},
+    times::DisplayZone,
+    utm::{self, UtmParams},
+};
+
+use crate::{check_login, state::AppState};
+
+fn link_error(e: LinkError) -> Response {
+    let status = StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (status, Json(ApiError::from(e))).into_response()
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(ApiError {
+            error: "Unauthorized".to_string(),
+        }),
+    )
+        .into_response()
+}
+
+pub async fn list_links(
+    State(state): State<AppState>,
+    Query(options): Query<StatsOptions>,
+    Query(zone): Query<DisplayZone>,
+    headers: HeaderMap,
+) -> Response {
+    if !check_login(&state, &headers).await {
+        return unauthorized();
+    }
+
+    match links::list_links(&*state.store, options, &zone).await {
+        Ok(links) => Json(links).into_response(),
+        Err(e) => link_error(e),
+    }
+}
+
+pub async fn get_link(
+    Path(code): Path<String>,
+    State(state): State<AppState>,
+    Query(options): Query<StatsOptions>,
+    Query(zone): Query<DisplayZone>,
+    headers: HeaderMap,
+) -> Response {
+    if !check_login(&state, &headers).await {
+        return unauthorized();
+    }
+
+    match links::link_stats(&*state.store, &code, options, &zone).await {
+        Ok(link) => Json(link).into_response(),
+        Err(e) => link_error(e),
+    }
+}
+
+pub async fn create_link(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(new_link): Json<NewLink>,
+) -> Response {
+    if !check_login(&state, &headers).await {
+        return unauthorized();
+    }
+    info!("Creating link to {}", new_link.url);
+
+    let res = links::create_link(
+        &*state.store,
+        &state.policies,
+        new_link,
+        &mut rand::random::<u32>,
+    )
+    .await;
+    match res {
+        Ok(link) => (StatusCode::CREATED, Json(link)).into_response(),
+        Err(e) => link_error(e),
+    }
+}
+
+pub async fn update_link(
+    Path(code): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(update): Json<LinkUpdate>,
+) -> Response {
+    if !check_login(&state, &headers).await {
+        return unauthorized();
+    }
+    info!("Updating link {code}");
+
+    let res = links::update_link(
+        &*state.store,
+        &state.policies,
+        &code,
+        update,
+        &mut rand::random::<u32>,
+    )
+    .await;
+    match res {
+        Ok(link) => Json(link).into_response(),
+        Err(e) => link_error(e),
+    }
+}
+
+pub async fn delete_link(
+    Path(code): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if !check_login(&state, &headers).await {
+        return unauthorized();
+    }
+    warn!("Removing link {code}");
+
+    match links::delete_link(&*state.store, &code).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => link_error(e),
+    }
+}
+
+pub async fn list_templates(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !check_login(&state, &headers).await {
+        return unauthorized();
+    }
+
+    match utm::list_templates(&*state.store).await {
+        Ok(templates) => Json(templates).into_response(),
+        Err(e) => link_error(e),
+    }
+}
+
+pub async fn save_template(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(params): Json<UtmParams>,
+) -> Response {
+    if !check_login(&state, &headers).await {
+        return unauthorized();
+    }
+    info!("Saving UTM template {name}");
+
+    match utm::save_template(&*state.store, &name, params).await {
+        Ok(template) => Json(template).into_response(),
+        Err(e) => link_error(e),
+    }
+}
+
+pub async fn delete_template(
+    Path(name): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Response {
+    if !check_login(&state, &headers).await {
+        return unauthorized();
+    }
+    warn!("Removing UTM template {name}");
+
+    match utm::delete_template(&*state.store, &name).await {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => link_error(e),
+    }
+}
@@ -0,0 +1,250 @@
+// Jackson Coxson
+// Redirects hand their visits to a bounded queue and a background writer saves them in batches.
+// Nothing waits on the database to follow a link, and a slow database can't pile up tasks.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use serde::Serialize;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+
+use crate::store::{LinkStore, Visit};
+
+/// What to do with a visit when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Forget the visit, the redirect still happens right away
+    Drop,
+    /// Hold the redirect until there's room
+    Wait,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClickConfig {
+    pub capacity: usize,
+    pub batch_size: usize,
+    /// How long the writer waits for a batch to fill up
+    pub flush_interval: Duration,
+    pub overflow: Overflow,
+}
+
+impl Default for ClickConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            batch_size: 500,
+            flush_interval: Duration::from_millis(250),
+            overflow: Overflow::Drop,
+        }
+    }
+}
+
+impl ClickConfig {
+    /// Reads CLICK_QUEUE_SIZE, CLICK_BATCH_SIZE, CLICK_FLUSH_MS and CLICK_QUEUE_FULL (drop or wait)
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+        let number = |key: &str| var(key).and_then(|v| v.parse::<usize>().ok());
+        Self {
+            capacity: number("CLICK_QUEUE_SIZE")
+                .unwrap_or(default.capacity)
+                .max(1),
+            batch_size: number("CLICK_BATCH_SIZE")
+                .unwrap_or(default.batch_size)
+                .max(1),
+            flush_interval: number("CLICK_FLUSH_MS")
+                .map(|ms| Duration::from_millis(ms as u64))
+                .unwrap_or(default.flush_interval),
+            overflow: match var("CLICK_QUEUE_FULL").as_deref() {
+                Some("wait") => Overflow::Wait,
+                _ => default.overflow,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClickStats {
+    /// Visits waiting to be written
+    pub queued: usize,
+    pub capacity: usize,
+    pub written: u64,
+    pub dropped: u64,
+    pub failed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    written: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+/// The sending side, cheap to clone into every handler
+#[derive(Clone)]
+pub struct ClickLogger {
+    sender: mpsc::Sender<Visit>,
+    overflow: Overflow,
+    counters: Arc<Counters>,
+}
+
+/// The receiving side, run it on its own task. It returns once every ClickLogger is gone
+/// and the queue has been written out.
+pub struct ClickWriter {
+    receiver: mpsc::Receiver<Visit>,
+    batch_size: usize,
+    flush_interval: Duration,
+    counters: Arc<Counters>,
+}
+
+pub fn channel(config: ClickConfig) -> (ClickLogger, ClickWriter) {
+    let (sender, receiver) = mpsc::channel(config.capacity);
+    let counters = Arc::new(Counters::default());
+    (
+        ClickLogger {
+            sender,
+            overflow: config.overflow,
+            counters: counters.clone(),
+        },
+        ClickWriter {
+            receiver,
+            batch_size: config.batch_size,
+            flush_interval: config.flush_interval,
+            counters,
+        },
+    )
+}
+
+impl ClickLogger {
+    pub async fn log(&self, visit: Visit) {
+        let sent = match self.overflow {
+            Overflow::Drop => match self.sender.try_send(visit) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    // Only warn the first time so a flood doesn't also flood the logs
+                    if self.counters.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+                        warn!("Click queue is full, visits are being dropped");
+                    }
+                    return;
+                }
+                Err(TrySendError::Closed(_)) => false,
+            },
+            Overflow::Wait => self.sender.send(visit).await.is_ok(),
+        };
+        if !sent {
+            error!("Click writer has stopped, visit was not logged");
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> ClickStats {
+        ClickStats {
+            queued: self.sender.max_capacity() - self.sender.capacity(),
+            capacity: self.sender.max_capacity(),
+            written: self.counters.written.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl ClickWriter {
+    pub async fn run(mut self, store: Arc<dyn LinkStore + Send + Sync>) {
+        while let Some(first) = self.receiver.recv().await {
+            let mut batch = vec![first];
+            let deadline = Instant::now() + self.flush_interval;
+            while batch.len() < self.batch_size {
+                match tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+                    Ok(Some(visit)) => batch.push(visit),
+                    // Out of time, or shutting down with everything received
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            self.write(&*store, batch).await;
+        }
+        info!("Click writer is done");
+    }
+
+    async fn write(&self, store: &(dyn LinkStore + Send + Sync), batch: Vec<Visit>) {
+        let count = batch.len() as u64;
+        // One retry covers the database being briefly busy
+        let res = match store.log_visits(batch.clone()).await {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                warn!("Failed to write {count} visits, retrying: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                store.log_visits(batch).await
+            }
+        };
+        match res {
+            Ok(()) => self.counters.written.fetch_add(count, Ordering::Relaxed),
+            Err(e) => {
+                error!("Failed to write {count} visits: {e}");
+                self.counters.failed.fetch_add(count, Ordering::Relaxed)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    fn visit() -> Visit {
+        Visit::new("asdf", "https://google.com/", "127.0.0.1")
+    }
+
+    #[tokio::test]
+    async fn drops_when_full() {
+        let (logger, writer) = channel(ClickConfig {
+            capacity: 2,
+            ..Default::default()
+        });
+        for _ in 0..3 {
+            logger.log(visit()).await;
+        }
+        let stats = logger.stats();
+        assert_eq!((stats.queued, stats.dropped), (2, 1));
+
+        // Everything queued is written once the loggers are gone
+        let store = Arc::new(MemoryStore::new());
+        drop(logger);
+        writer.run(store.clone()).await;
+        assert_eq!(store.get_logs("asdf").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn batches() {
+        let (logger, writer) = channel(ClickConfig {
+            batch_size: 10,
+            overflow: Overflow::Wait,
+            ..Default::default()
+        });
+        let store = Arc::new(MemoryStore::new());
+        let task = tokio::spawn(writer.run(store.clone()));
+        for _ in 0..25 {
+            logger.log(visit()).await;
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while logger.stats().written < 25 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(logger.stats().queued, 0);
+
+        drop(logger);
+        task.await.unwrap();
+        assert_eq!(store.get_logs("asdf").await.unwrap().len(), 25);
+    }
+}
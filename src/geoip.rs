@@ -0,0 +1,87 @@
+// Jackson Coxson
+// Looks visitors up in local MaxMind databases (GeoLite2 or GeoIP2, City or Country, and ASN).
+// The files are read once at startup and nothing ever goes over the network.
+
+use std::net::IpAddr;
+
+use log::{info, warn};
+use maxminddb::{geoip2, Reader};
+use riplakish_core::geo::Location;
+
+#[derive(Default)]
+pub struct GeoIp {
+    city: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Reads GEOIP_DB (a City or Country database) and GEOIP_ASN_DB, either can be left out
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let open = |key: &str| {
+            let path = var(key).filter(|p| !p.is_empty())?;
+            match Reader::open_readfile(&path) {
+                Ok(reader) => {
+                    info!("Loaded {} from {path}", reader.metadata.database_type);
+                    Some(reader)
+                }
+                Err(e) => {
+                    warn!("Couldn't open {key} at {path}, visits won't be located: {e}");
+                    None
+                }
+            }
+        };
+        Self {
+            city: open("GEOIP_DB"),
+            asn: open("GEOIP_ASN_DB"),
+        }
+    }
+
+    /// Where the address a visit came from is, nothing if it isn't a single address
+    pub fn lookup(&self, ip: &str) -> Location {
+        let ip = match ip.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return Location::default(),
+        };
+        let mut location = Location::default();
+        if let Some(city) = self
+            .city
+            .as_ref()
+            .and_then(|r| r.lookup::<geoip2::City>(ip).ok())
+        {
+            location.country = city.country.and_then(|c| c.iso_code).map(str::to_string);
+            location.region = city
+                .subdivisions
+                .and_then(|s| s.into_iter().next())
+                .and_then(|s| s.iso_code)
+                .map(str::to_string);
+            location.city = city
+                .city
+                .and_then(|c| c.names)
+                .and_then(|names| names.get("en").copied())
+                .map(str::to_string);
+        }
+        location.asn = self
+            .asn
+            .as_ref()
+            .and_then(|r| r.lookup::<geoip2::Asn>(ip).ok())
+            .and_then(|asn| asn.autonomous_system_number);
+        location
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let geoip = GeoIp::from_vars(|key| {
+            (key == "GEOIP_DB").then(|| "/nonexistent/GeoLite2-City.mmdb".to_string())
+        });
+        assert!(geoip.city.is_none());
+        assert_eq!(geoip.lookup("203.0.113.7"), Location::default());
+        // A forwarded list isn't trusted as an address
+        assert_eq!(geoip.lookup("203.0.113.7, 10.0.0.1"), Location::default());
+        assert_eq!(geoip.lookup("unknown"), Location::default());
+    }
+}
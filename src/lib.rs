@@ -2,31 +2,25 @@
 
 // Cloudflare port of Riplakish
 
+use std::sync::OnceLock;
+
+use riplakish_core::{
+    agents::{self, ClientInfo},
+    auth,
+    geo::{self, Location},
+    links::{self, ApiError, LinkError, LinkUpdate, NewLink, Policies, StatsOptions},
+    resolve::{self, AttemptLimit, Fallbacks, Outcome, Redirects, Reply, Visitor},
+    series::{self, Bucket, SeriesQuery},
+    times::{timestamp, DisplayZone},
+    urls,
+    utm::{self, UtmParams},
+    visitors::Fingerprinter,
+};
 use serde::{Deserialize, Serialize};
+use store::{d1::D1Store, LinkStore, Visit};
 use worker::*;
 
-#[derive(Deserialize)]
-struct Stat {
-    url: String,
-    redirect: String,
-    log_count: u32,
-    comment: Option<String>,
-}
-
-#[derive(Serialize)]
-struct SerStat {
-    url: String,
-    code: String,
-    comment: String,
-    visits: u32,
-}
-
-#[derive(Serialize, Deserialize)]
-struct Log {
-    timestamp: String,
-    ip: String,
-    url: String,
-}
+mod store;
 
 #[event(fetch)]
 pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
@@ -59,93 +53,37 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 .unwrap()
                 .with_headers(headers))
         })
-        // REMOVE BEFORE DEPLOY
-        .get_async("/headers", |req, _ctx| async move {
-            // return the headers
-            let headers = req.headers();
-            Response::ok(format!("{:?}", headers))
-        })
         .get_async("/r/:code", |req, ctx| async move {
-            let d1 = ctx.env.d1("riplakish")?;
-            let code = match ctx.param("code") {
-                Some(c) => c,
-                None => {
-                    return Response::error("Bad Request", 400);
-                }
-            };
-
-            // Check for injection
-            if check_string_injection(code) {
-                return Response::error("Bad Request", 400);
-            }
-
-            let statement = d1.prepare("SELECT url FROM redirects WHERE redirect = ?");
-            let query = statement.bind(&[code.into()])?;
-            let result = query.first::<String>(Some("url")).await?;
-            let res = match result {
-                Some(r) => r,
-                None => return Response::error("404 Not found\n\n-- Riplakish --", 404),
-            };
-
-            let url = match Url::parse(&res) {
-                Ok(u) => u,
-                Err(_) => return Response::error("Bad URL", 500),
-            };
-
-            // Log the redirect
-            let ip = req
-                .headers()
-                .get("CF-Connecting-IP")?
-                .unwrap_or("unknown".to_string()); // I don't think this works in dev???
-            let statement =
-                d1.prepare("INSERT INTO log (redirect, ip, url, timestamp) VALUES (?, ?, ?, ?)");
-            let query = statement.bind(&[
-                code.into(),
-                ip.into(),
-                url.to_string().into(),
-                chrono::offset::Local::now()
-                    .format("%m/%d/%Y %T")
-                    .to_string()
-                    .into(),
-            ])?;
-            if let Err(e) = query.run().await {
-                return Response::error(e.to_string(), 500);
-            }
-
-            Response::redirect(url)
+            follow(&req, &ctx, None).await
+        })
+        .get_async("/r/:code/*rest", |req, ctx| async move {
+            follow(&req, &ctx, None).await
+        })
+        .head_async("/r/:code", |req, ctx| async move {
+            follow(&req, &ctx, None).await
+        })
+        .head_async("/r/:code/*rest", |req, ctx| async move {
+            follow(&req, &ctx, None).await
+        })
+        .post_async("/r/:code", |req, ctx| async move {
+            follow_with_password(req, &ctx).await
+        })
+        .post_async("/r/:code/*rest", |req, ctx| async move {
+            follow_with_password(req, &ctx).await
         })
         .get_async("/admin/login", |req, ctx| async move {
-            let d1 = ctx.env.d1("riplakish")?;
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
             let username = ctx.env.var("USERNAME")?.to_string();
             let password = ctx.env.var("PASSWORD")?.to_string();
-            wasm_rs_dbg::dbg!(&username);
-            wasm_rs_dbg::dbg!(&password);
 
             let input_username = req.headers().get("X-Username")?;
             let input_password = req.headers().get("X-Password")?;
-            wasm_rs_dbg::dbg!(&input_username);
-            wasm_rs_dbg::dbg!(&input_password);
 
             if input_username == Some(username) && input_password == Some(password) {
-                // Generate a token
-                let mut buf = [0; 16];
-                let _ = getrandom::getrandom(&mut buf);
-                let mut token = String::new();
-                for c in buf {
-                    token.push_str(&format!("{:02X}", c));
-                }
-
-                // Set the token
-                // INSERT INTO tokens (token, expiration) VALUES (?, ?);
-                let expires = chrono::offset::Local::now()
-                    .checked_add_signed(chrono::Duration::hours(1))
-                    .unwrap();
-                let statement = d1.prepare("INSERT INTO tokens (token, expiration) VALUES (?, ?)");
-                let query = statement.bind(&[token.clone().into(), expires.to_rfc3339().into()])?;
-
-                if let Err(e) = query.run().await {
-                    return Response::error(e.to_string(), 500);
-                }
+                let token = match auth::login(&store, &mut random).await {
+                    Ok(t) => t,
+                    Err(e) => return Response::error(e.to_string(), 500),
+                };
 
                 // Set the X-Token header
                 let mut headers = Headers::new();
@@ -162,37 +100,54 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
             Response::ok(ctx.env.var("BASE_URL")?.to_string())
         })
         .get_async("/admin/stats", |req, ctx| async move {
-            let d1 = ctx.env.d1("riplakish")?;
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
+
+            if !check_token(&req.headers(), &store).await {
+                return Response::error("Unauthorized", 401);
+            }
+
+            match links::list_links(&store, stats_options(&req)?, &display_zone(&req)?).await {
+                Ok(r) => Response::from_json(&r),
+                Err(e) => Response::error(e.to_string(), e.status()),
+            }
+        })
+        .get_async("/admin/stats/templates", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-            if !check_token(&req.headers(), &d1).await {
+            if !check_token(&req.headers(), &store).await {
                 return Response::error("Unauthorized", 401);
             }
 
-            let query = "SELECT r.url, r.redirect, COUNT(l.id) AS log_count, r.comment
-            FROM redirects r
-            LEFT JOIN log l ON r.redirect = l.redirect
-            GROUP BY r.url, r.redirect;";
-
-            let statement = d1.prepare(query);
-            let result = statement.all().await?;
-            match result.results::<Stat>() {
-                Ok(r) => Response::from_json(
-                    &r.iter()
-                        .map(|r| SerStat {
-                            url: r.url.clone(),
-                            code: r.redirect.clone(),
-                            comment: r.comment.clone().unwrap_or_default(),
-                            visits: r.log_count,
-                        })
-                        .collect::<Vec<SerStat>>(),
-                ),
+            match utm::template_visits(&store, &stats_options(&req)?).await {
+                Ok(r) => Response::from_json(&r),
                 Err(_) => Response::error("Failed to query", 500),
             }
         })
-        .get_async("/admin/logs/:code", |req, ctx| async move {
-            let d1 = ctx.env.d1("riplakish")?;
+        .get_async("/admin/stats/countries", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
+
+            if !check_token(&req.headers(), &store).await {
+                return Response::error("Unauthorized", 401);
+            }
+
+            match geo::country_visits(&store, None, &stats_options(&req)?).await {
+                Ok(r) => Response::from_json(&r),
+                Err(_) => Response::error("Failed to query", 500),
+            }
+        })
+        .get_async("/admin/stats/timeline", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-            if !check_token(&req.headers(), &d1).await {
+            if !check_token(&req.headers(), &store).await {
+                return Response::error("Unauthorized", 401);
+            }
+
+            timeline(&store, None, &req).await
+        })
+        .get_async("/admin/logs/:code/timeline", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
+
+            if !check_token(&req.headers(), &store).await {
                 return Response::error("Unauthorized", 401);
             }
 
@@ -203,59 +158,75 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 }
             };
 
-            // Check for injection
-            if check_string_injection(code) {
-                return Response::error("Bad Request", 400);
+            timeline(&store, Some(code), &req).await
+        })
+        .get_async("/admin/logs/:code/countries", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
+
+            if !check_token(&req.headers(), &store).await {
+                return Response::error("Unauthorized", 401);
             }
 
-            // SELECT * FROM log WHERE redirect = ?
-            let statement = d1.prepare("SELECT * FROM log WHERE redirect = ?");
-            let query = statement.bind(&[code.into()])?;
-            let result = query.all().await?;
-            match result.results::<Log>() {
+            let code = match ctx.param("code") {
+                Some(c) => c,
+                None => {
+                    return Response::error("Bad Request", 400);
+                }
+            };
+
+            match geo::country_visits(&store, Some(code), &stats_options(&req)?).await {
                 Ok(r) => Response::from_json(&r),
                 Err(_) => Response::error("Failed to query", 500),
             }
         })
-        .post_async("/admin/add/*url", |req, ctx| async move {
-            let d1 = ctx.env.d1("riplakish")?;
+        .get_async("/admin/logs/:code", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-            if !check_token(&req.headers(), &d1).await {
+            if !check_token(&req.headers(), &store).await {
                 return Response::error("Unauthorized", 401);
             }
 
-            let url = match ctx.param("url") {
-                Some(u) => u,
+            let code = match ctx.param("code") {
+                Some(c) => c,
                 None => {
                     return Response::error("Bad Request", 400);
                 }
             };
 
-            // Check for injection
-            if check_string_injection(url) {
-                return Response::error("Bad Request", 400);
+            match links::logs(&store, code, &display_zone(&req)?).await {
+                Ok(r) => Response::from_json(&r),
+                Err(e) => Response::error(e.to_string(), e.status()),
             }
+        })
+        .post_async("/admin/add/*url", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-            let mut buf = [0; 2];
-            let _ = getrandom::getrandom(&mut buf);
-            let mut code = String::new();
-            for c in buf {
-                code.push_str(&format!("{:02X}", c));
+            if !check_token(&req.headers(), &store).await {
+                return Response::error("Unauthorized", 401);
             }
 
-            let statement = d1.prepare("INSERT INTO redirects (url, redirect) VALUES (?, ?)");
+            let url = match ctx.param("url") {
+                Some(u) => u,
+                None => {
+                    return Response::error("Bad Request", 400);
+                }
+            };
 
-            let query = statement.bind(&[url.into(), code.into()])?;
-            if let Err(e) = query.run().await {
-                return Response::error(e.to_string(), 500);
+            let requested = req
+                .url()?
+                .query_pairs()
+                .find(|(k, _)| k == "code")
+                .map(|(_, v)| v.into_owned());
+            let policies = policies(&ctx.env);
+            match links::create_redirect(&store, &policies, url, requested, &mut random).await {
+                Ok(_) => Response::ok("Success"),
+                Err(e) => Response::error(e.to_string(), e.status()),
             }
-
-            Response::ok("Success")
         })
         .delete_async("/admin/remove/:code", |req, ctx| async move {
-            let d1 = ctx.env.d1("riplakish")?;
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-            if !check_token(&req.headers(), &d1).await {
+            if !check_token(&req.headers(), &store).await {
                 return Response::error("Unauthorized", 401);
             }
 
@@ -266,24 +237,16 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 }
             };
 
-            // Check for injection
-            if check_string_injection(code) {
-                return Response::error("Bad Request", 400);
-            }
-
-            // DELETE FROM redirects WHERE redirect = '{code}';
-            let statement = d1.prepare("DELETE FROM redirects WHERE redirect = ?");
-            let query = statement.bind(&[code.into()])?;
-            if let Err(e) = query.run().await {
+            if let Err(e) = store.remove_link(code).await {
                 return Response::error(e.to_string(), 500);
             }
 
             Response::ok("Success")
         })
         .post_async("/admin/modify/:code/*new_url", |req, ctx| async move {
-            let d1 = ctx.env.d1("riplakish")?;
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-            if !check_token(&req.headers(), &d1).await {
+            if !check_token(&req.headers(), &store).await {
                 return Response::error("Unauthorized", 401);
             }
 
@@ -301,15 +264,15 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                 }
             };
 
-            // Check for injection
-            if check_string_injection(code) || check_string_injection(new_url) {
-                return Response::error("Bad Request", 400);
-            }
+            let new_url = match policies(&ctx.env).urls.validate(new_url) {
+                Ok(u) => u,
+                Err(e) => return Response::error(e.to_string(), 400),
+            };
 
-            // UPDATE redirects SET url = '{url}' WHERE redirect = '{code}';
-            let statement = d1.prepare("UPDATE redirects SET url = ? WHERE redirect = ?");
-            let query = statement.bind(&[new_url.into(), code.into()])?;
-            if let Err(e) = query.run().await {
+            if let Err(e) = store.update_url(code, &new_url).await {
+                return Response::error(e.to_string(), 500);
+            }
+            if let Err(e) = store.touch_link(code, &timestamp(chrono::Utc::now())).await {
                 return Response::error(e.to_string(), 500);
             }
 
@@ -318,9 +281,9 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
         .post_async(
             "/admin/modify-comment/:code/*new_comment",
             |req, ctx| async move {
-                let d1 = ctx.env.d1("riplakish")?;
+                let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-                if !check_token(&req.headers(), &d1).await {
+                if !check_token(&req.headers(), &store).await {
                     return Response::error("Unauthorized", 401);
                 }
 
@@ -338,94 +301,388 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
                     }
                 };
 
-                // Check for injection
-                if check_string_injection(code) || check_string_injection(&new_comment) {
-                    return Response::error("Bad Request", 400);
-                }
-
-                let new_comment = new_comment.replace("%20", " ");
+                // Decoded the same way the native server's Path does
+                let new_comment = match urls::decode_segment(new_comment) {
+                    Some(c) => c,
+                    None => {
+                        return Response::error("Bad Request", 400);
+                    }
+                };
 
-                // UPDATE redirects SET comment = ? WHERE redirect = ?;
-                let statement = d1.prepare("UPDATE redirects SET comment = ? WHERE redirect = ?");
-                let query = statement.bind(&[new_comment.into(), code.into()])?;
-                if let Err(e) = query.run().await {
+                if let Err(e) = store.update_comment(code, &new_comment).await {
+                    return Response::error(e.to_string(), 500);
+                }
+                if let Err(e) = store.touch_link(code, &timestamp(chrono::Utc::now())).await {
                     return Response::error(e.to_string(), 500);
                 }
 
                 Response::ok("Success")
             },
         )
-        .run(req, env)
-        .await
-}
+        .get_async("/api/v2/links", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-fn check_string_injection(s: &str) -> bool {
-    for c in s.chars() {
-        if !c.is_alphanumeric() {
-            match c {
-                '.' | ':' | '/' | '-' | '_' => continue,
-                _ => return true,
+            if !check_token(&req.headers(), &store).await {
+                return api_error("Unauthorized", 401);
             }
-        }
-    }
-    false
-}
 
-#[inline]
-async fn get_token(headers: &Headers) -> Option<String> {
-    let cookies = headers.get("cookie").ok()??;
-    let cookies = cookies.split(';').collect::<Vec<&str>>();
-    for cookie in cookies {
-        if cookie.starts_with("X-Token=") {
-            let token = cookie.split('=').collect::<Vec<&str>>()[1];
-            let token = token.replace(" SameSite", "");
+            match links::list_links(&store, stats_options(&req)?, &display_zone(&req)?).await {
+                Ok(r) => Response::from_json(&r),
+                Err(e) => link_error(e),
+            }
+        })
+        .post_async("/api/v2/links", |mut req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-            return Some(token);
-        }
-    }
-    None
-}
+            if !check_token(&req.headers(), &store).await {
+                return api_error("Unauthorized", 401);
+            }
+
+            let new_link = match req.json::<NewLink>().await {
+                Ok(l) => l,
+                Err(e) => return api_error(e, 400),
+            };
+
+            let policies = policies(&ctx.env);
+            match links::create_link(&store, &policies, new_link, &mut random).await {
+                Ok(link) => Ok(Response::from_json(&link)?.with_status(201)),
+                Err(e) => link_error(e),
+            }
+        })
+        .get_async("/api/v2/links/:code", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-async fn check_token(headers: &Headers, d1: &D1Database) -> bool {
-    match get_token(headers).await {
-        Some(token) => {
-            if check_string_injection(&token) {
-                return false;
+            if !check_token(&req.headers(), &store).await {
+                return api_error("Unauthorized", 401);
             }
 
-            // check the token
-            let statement = d1.prepare("SELECT expiration FROM tokens WHERE token = ?");
-            let query = match statement.bind(&[token.into()]) {
-                Ok(q) => q,
-                Err(_) => return false,
+            let code = match ctx.param("code") {
+                Some(c) => c,
+                None => return api_error("Bad Request", 400),
             };
-            let result = match query.first::<String>(Some("expiration")).await {
-                Ok(r) => r,
-                Err(_) => return false,
+
+            let zone = display_zone(&req)?;
+            match links::link_stats(&store, code, stats_options(&req)?, &zone).await {
+                Ok(link) => Response::from_json(&link),
+                Err(e) => link_error(e),
+            }
+        })
+        .patch_async("/api/v2/links/:code", |mut req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
+
+            if !check_token(&req.headers(), &store).await {
+                return api_error("Unauthorized", 401);
+            }
+
+            let code = match ctx.param("code") {
+                Some(c) => c,
+                None => return api_error("Bad Request", 400),
             };
 
-            let res = match result {
-                Some(r) => r,
-                None => return false,
+            let update = match req.json::<LinkUpdate>().await {
+                Ok(u) => u,
+                Err(e) => return api_error(e, 400),
             };
 
-            let expires = match chrono::DateTime::parse_from_rfc3339(&res) {
-                Ok(e) => e,
-                Err(_) => return false,
+            let policies = policies(&ctx.env);
+            match links::update_link(&store, &policies, code, update, &mut random).await {
+                Ok(link) => Response::from_json(&link),
+                Err(e) => link_error(e),
+            }
+        })
+        .delete_async("/api/v2/links/:code", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
+
+            if !check_token(&req.headers(), &store).await {
+                return api_error("Unauthorized", 401);
+            }
+
+            let code = match ctx.param("code") {
+                Some(c) => c,
+                None => return api_error("Bad Request", 400),
             };
 
-            if chrono::offset::Local::now() > expires {
-                return false;
+            match links::delete_link(&store, code).await {
+                Ok(()) => Ok(Response::empty()?.with_status(204)),
+                Err(e) => link_error(e),
+            }
+        })
+        .get_async("/api/v2/templates", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
+
+            if !check_token(&req.headers(), &store).await {
+                return api_error("Unauthorized", 401);
+            }
+
+            match utm::list_templates(&store).await {
+                Ok(r) => Response::from_json(&r),
+                Err(e) => link_error(e),
             }
+        })
+        .put_async("/api/v2/templates/:name", |mut req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
 
-            // Delete old tokens
-            let statement = d1.prepare("DELETE FROM tokens WHERE expiration < ?");
-            if let Ok(query) = statement.bind(&[chrono::offset::Local::now().to_rfc3339().into()]) {
-                let _ = query.run().await;
+            if !check_token(&req.headers(), &store).await {
+                return api_error("Unauthorized", 401);
             }
 
-            true
+            let name = match ctx.param("name") {
+                Some(n) => n,
+                None => return api_error("Bad Request", 400),
+            };
+
+            let params = match req.json::<UtmParams>().await {
+                Ok(p) => p,
+                Err(e) => return api_error(e, 400),
+            };
+
+            match utm::save_template(&store, name, params).await {
+                Ok(template) => Response::from_json(&template),
+                Err(e) => link_error(e),
+            }
+        })
+        .delete_async("/api/v2/templates/:name", |req, ctx| async move {
+            let store = D1Store::new(ctx.env.d1("riplakish")?);
+
+            if !check_token(&req.headers(), &store).await {
+                return api_error("Unauthorized", 401);
+            }
+
+            let name = match ctx.param("name") {
+                Some(n) => n,
+                None => return api_error("Bad Request", 400),
+            };
+
+            match utm::delete_template(&store, name).await {
+                Ok(()) => Ok(Response::empty()?.with_status(204)),
+                Err(e) => link_error(e),
+            }
+        })
+        .run(req, env)
+        .await
+}
+
+/// The password form on a protected link posts back to the link
+async fn follow_with_password(mut req: Request, ctx: &RouteContext<()>) -> Result<Response> {
+    let password = match req.form_data().await?.get("password") {
+        Some(FormEntry::Field(p)) => p,
+        _ => return Response::error("Bad Request", 400),
+    };
+    follow(&req, ctx, Some(password)).await
+}
+
+/// Follows /r/:code, with the password if the form was sent
+async fn follow(
+    req: &Request,
+    ctx: &RouteContext<()>,
+    password: Option<String>,
+) -> Result<Response> {
+    let store = D1Store::new(ctx.env.d1("riplakish")?);
+    let code = match ctx.param("code") {
+        Some(c) => c,
+        None => {
+            return Response::error("Bad Request", 400);
+        }
+    };
+
+    let var = |key: &str| ctx.env.var(key).ok().map(|v| v.to_string());
+    let ip = req
+        .headers()
+        .get("CF-Connecting-IP")?
+        .unwrap_or("unknown".to_string()); // I don't think this works in dev???
+    let url = req.url()?;
+    // Cloudflare already knows where the visitor is, no database needed
+    let location = req
+        .cf()
+        .map(|cf| Location {
+            country: cf.country(),
+            region: cf.region_code(),
+            city: cf.city(),
+            asn: Some(cf.asn()).filter(|asn| *asn != 0),
+        })
+        .unwrap_or_default();
+    let mut purposes = Vec::new();
+    for name in agents::PREFETCH_HEADERS {
+        purposes.extend(req.headers().get(name)?);
+    }
+    let user_agent = req.headers().get("User-Agent")?;
+    let visitor = Visitor {
+        ip: ip.clone(),
+        password,
+        path: urls::path_suffix(url.path()).map(str::to_string),
+        query: url.query().map(str::to_string),
+        destination: req
+            .headers()
+            .get("cookie")?
+            .as_deref()
+            .and_then(resolve::sticky_destination),
+        kind: agents::classify(
+            req.method() == Method::Head,
+            user_agent.as_deref(),
+            purposes.iter().map(String::as_str),
+        ),
+        user_agent,
+        accept_language: req.headers().get("Accept-Language")?,
+        country: location.country.clone(),
+    };
+    let limit = AttemptLimit::from_vars(var);
+
+    let now = chrono::Utc::now();
+    let outcome = match resolve::follow(&store, code, &visitor, &limit, now, &mut random).await {
+        Ok(o) => o,
+        Err(e) => return Response::error(e.to_string(), 500),
+    };
+    let (redirect, preview) = match outcome {
+        Outcome::Redirect(r) => (r, false),
+        // Logged like any other visit, it just doesn't get the link
+        Outcome::Preview(r) => (r, true),
+        Outcome::NotFound => return reply(Reply::not_found()),
+        Outcome::Unavailable(state) => return reply(Fallbacks::from_vars(var).reply(state)),
+        Outcome::PasswordRequired { wrong } => return reply(Reply::password_form(wrong)),
+        Outcome::TooManyAttempts => return reply(Reply::too_many_attempts()),
+    };
+
+    // Kept for as long as the isolate lives, so the day's salt is only looked up once a day
+    static FINGERPRINTER: OnceLock<Fingerprinter> = OnceLock::new();
+    let fingerprinter = FINGERPRINTER.get_or_init(|| {
+        Fingerprinter::from_vars(var).unwrap_or_else(|| {
+            // Isolates come and go, so a made up secret wouldn't match between them
+            console_warn!("VISITOR_SECRET isn't set, visitors are only hashed with the day's salt");
+            Fingerprinter::new(&[])
+        })
+    });
+
+    // Log the redirect
+    let visit = Visit {
+        utm_template: redirect.utm_template.clone(),
+        destination: redirect.destination,
+        location,
+        client: ClientInfo::new(
+            req.headers().get("Referer")?.as_deref(),
+            visitor.user_agent.as_deref(),
+            visitor.accept_language.as_deref(),
+            url.host_str(),
+        ),
+        kind: visitor.kind,
+        visitor: fingerprinter
+            .fingerprint(&store, &ip, visitor.user_agent.as_deref(), now, &mut random)
+            .await
+            .inspect_err(|e| console_error!("Failed to fingerprint a visit to {code}: {e}"))
+            .ok(),
+        ..Visit::new(code, &redirect.url, &ip)
+    };
+    if let Err(e) = store.log_visit(visit).await {
+        return Response::error(e.to_string(), 500);
+    }
+
+    if preview {
+        return reply(Reply::preview());
+    }
+    reply(Redirects::from_vars(var).reply(redirect))
+}
+
+/// ?include_bots=true and ?unique_days=7 on the stats and link routes
+fn stats_options(req: &Request) -> Result<StatsOptions> {
+    let url = req.url()?;
+    let query = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    };
+    Ok(StatsOptions {
+        include_bots: query("include_bots").as_deref() == Some("true"),
+        unique_days: query("unique_days").and_then(|d| d.parse().ok()),
+    })
+}
+
+/// The ?tz= a client wants times shown in
+fn display_zone(req: &Request) -> Result<DisplayZone> {
+    Ok(DisplayZone {
+        tz: req
+            .url()?
+            .query_pairs()
+            .find(|(k, _)| k == "tz")
+            .map(|(_, v)| v.into_owned()),
+    })
+}
+
+/// Visits over time for one link, or all of them
+async fn timeline(store: &D1Store, code: Option<&str>, req: &Request) -> Result<Response> {
+    let url = req.url()?;
+    let query = |key: &str| {
+        url.query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    };
+    let bucket = match query("bucket").map(|b| b.parse::<Bucket>()).transpose() {
+        Ok(bucket) => bucket.unwrap_or_default(),
+        Err(e) => return Response::error(e.to_string(), 400),
+    };
+    let query = SeriesQuery {
+        bucket,
+        from: query("from"),
+        to: query("to"),
+        tz: query("tz"),
+        include_bots: query("include_bots").as_deref() == Some("true"),
+    };
+    match series::clicks(store, code, &query, chrono::Utc::now()).await {
+        Ok(r) => Response::from_json(&r),
+        Err(e) => Response::error(e.to_string(), e.status()),
+    }
+}
+
+fn policies(env: &Env) -> Policies {
+    Policies::from_vars(|key| env.var(key).ok().map(|v| v.to_string()))
+}
+
+fn random() -> u32 {
+    let mut buf = [0; 4];
+    let _ = getrandom::getrandom(&mut buf);
+    u32::from_le_bytes(buf)
+}
+
+/// Sends a reply decided by riplakish_core::resolve
+fn reply(reply: Reply) -> Result<Response> {
+    let cache_control = reply.cache_control();
+    // Built by hand, Response::redirect comes back with headers that can't be changed
+    let mut response = match reply.location {
+        Some(location) => {
+            let url = match Url::parse(&location) {
+                Ok(u) => u,
+                Err(_) => return Response::error("Bad URL", 500),
+            };
+            let mut response = Response::empty()?;
+            response.headers_mut().set("Location", url.as_str())?;
+            response
         }
+        None if reply.html => Response::from_html(reply.body)?,
+        None => Response::ok(reply.body)?,
+    };
+    response
+        .headers_mut()
+        .set("Cache-Control", &cache_control)?;
+    if let Some(cookie) = &reply.set_cookie {
+        response.headers_mut().set("Set-Cookie", cookie)?;
+    }
+    Ok(response.with_status(reply.status))
+}
+
+fn api_error(error: impl ToString, status: u16) -> Result<Response> {
+    Ok(Response::from_json(&ApiError {
+        error: error.to_string(),
+    })?
+    .with_status(status))
+}
+
+fn link_error(error: LinkError) -> Result<Response> {
+    api_error(&error, error.status())
+}
+
+async fn check_token(headers: &Headers, store: &D1Store) -> bool {
+    let header = headers.get("X-Token").ok().flatten();
+    let cookies = headers.get("cookie").ok().flatten();
+    match auth::find_token(header.as_deref(), cookies.as_deref()) {
+        Some(token) => auth::check_token(store, &token).await,
         None => false,
     }
 }
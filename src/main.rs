@@ -4,24 +4,41 @@
 use std::net::SocketAddr;
 
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, Query, State},
     http::{
-        header::{CONTENT_TYPE, SET_COOKIE},
-        HeaderMap, HeaderName, Method, StatusCode,
+        header::{
+            ACCEPT_LANGUAGE, CACHE_CONTROL, CONTENT_TYPE, COOKIE, HOST, LOCATION, REFERER,
+            SET_COOKIE, USER_AGENT,
+        },
+        HeaderMap, HeaderName, Method, StatusCode, Uri,
     },
-    response::Response,
-    routing::{delete, get, post},
-    Router,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Form, Router,
 };
 
-use axum_client_ip::InsecureClientIp;
-use log::{info, warn};
-use rand::Rng;
+use log::{error, info, warn};
+use riplakish_core::{
+    agents::{self, ClientInfo},
+    auth, geo,
+    links::{self, StatsOptions},
+    resolve::{self, Outcome, Reply},
+    series::{self, SeriesQuery},
+    times::{timestamp, DisplayZone},
+    urls, utm,
+};
+use serde::Deserialize;
+use state::AppState;
 use statics::*;
+use store::Visit;
 use tower_http::cors::CorsLayer;
 
-mod db;
+mod api;
+mod clicks;
+mod geoip;
+mod state;
 mod statics;
+mod store;
 
 #[tokio::main]
 async fn main() {
@@ -30,10 +47,28 @@ async fn main() {
     env_logger::init();
     info!("Logger initialized");
 
-    let database = db::Database::new();
+    // riplakish migrate [--dry-run] or riplakish d1-migrations [dir]
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    match args.first().map(String::as_str) {
+        Some("migrate") => return migrate(args.iter().any(|a| a == "--dry-run")),
+        Some("d1-migrations") => {
+            return d1_migrations(args.get(1).map(String::as_str).unwrap_or("migrations"))
+        }
+        _ => {}
+    }
+
+    let (state, click_writer) = state::AppState::new();
+    let click_writer = tokio::spawn(click_writer.run(state.store.clone()));
 
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::PATCH,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
         .allow_origin(tower_http::cors::Any)
         .allow_headers([
             CONTENT_TYPE,
@@ -48,20 +83,40 @@ async fn main() {
         .route("/admin/login", get(login))
         .route("/scripts.js", get(js))
         .route("/styles.css", get(css))
-        .route("/r/:code", get(redirect))
+        .route("/r/:code", get(redirect).post(redirect_with_password))
+        .route("/r/:code/*rest", get(redirect).post(redirect_with_password))
         .route("/base", get(base_url))
         .route("/admin/stats", get(get_stats))
+        .route("/admin/stats/templates", get(get_template_stats))
+        .route("/admin/stats/countries", get(get_country_stats))
+        .route("/admin/stats/timeline", get(get_timeline))
+        .route("/admin/cache", get(get_cache_stats))
+        .route("/admin/clicks", get(get_click_stats))
         .route("/admin/logs/:code", get(get_logs))
+        .route("/admin/logs/:code/countries", get(get_link_country_stats))
+        .route("/admin/logs/:code/timeline", get(get_link_timeline))
         .route("/admin/add/*url", post(add_url))
-        .route("/admin/remove/*url", delete(remove_url))
+        .route("/admin/remove/:code", delete(remove_url))
         .route("/admin/modify/:code/*new_url", post(modify_url))
         .route(
             "/admin/modify-comment/:code/*new_comment",
             post(modify_comment),
         )
+        .route("/api/v2/links", get(api::list_links).post(api::create_link))
+        .route(
+            "/api/v2/links/:code",
+            get(api::get_link)
+                .patch(api::update_link)
+                .delete(api::delete_link),
+        )
+        .route("/api/v2/templates", get(api::list_templates))
+        .route(
+            "/api/v2/templates/:name",
+            put(api::save_template).delete(api::delete_template),
+        )
         .fallback(fallback)
         .layer(cors)
-        .with_state(database);
+        .with_state(state);
 
     let port = std::env::var("RIPLAKISH_PORT").unwrap_or("3009".to_string());
 
@@ -72,38 +127,245 @@ async fn main() {
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(shutdown_signal())
     .await
     .unwrap();
+
+    // The router is gone and every ClickLogger with it, so the writer finishes what's queued and stops
+    info!("Writing the last visits");
+    if let Err(e) = click_writer.await {
+        error!("Click writer failed: {e}");
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down");
+}
+
+/// Applies pending migrations to the SQLite database, or just prints them
+fn migrate(dry_run: bool) {
+    let filename = std::env::var("SQLITE_PATH").unwrap_or("riplakish.db".to_string());
+    let connection = sqlite::open(&filename).expect("Failed to read to database");
+    match store::sqlite::migrate(&connection, dry_run) {
+        Ok([]) => println!("{filename} is up to date"),
+        Ok(pending) if dry_run => println!("{} pending migrations", pending.len()),
+        Ok(pending) => println!("Applied {} migrations", pending.len()),
+        Err(e) => {
+            error!("Failed to migrate {filename}: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Writes every migration as a wrangler migration file for D1
+fn d1_migrations(dir: &str) {
+    std::fs::create_dir_all(dir).expect("Failed to create the migrations directory");
+    for migration in riplakish_core::migrations::MIGRATIONS {
+        let path = std::path::Path::new(dir).join(migration.file_name());
+        let sql = format!(
+            "-- Generated by riplakish d1-migrations, edit core/migrations instead\n{}",
+            migration.script()
+        );
+        std::fs::write(&path, sql).expect("Failed to write migration");
+        println!("Wrote {}", path.display());
+    }
+}
+
+/// /r/:code, or /r/:code/*rest for links that forward their path
+#[derive(Deserialize)]
+struct RedirectPath {
+    code: String,
 }
 
 async fn redirect(
-    Path(code): Path<String>,
-    State(database): State<db::Database>,
+    Path(path): Path<RedirectPath>,
+    method: Method,
+    uri: Uri,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ConnectInfo(socket): ConnectInfo<SocketAddr>,
+) -> Response {
+    let head = method == Method::HEAD;
+    follow(state, path.code, &uri, &headers, socket, None, head).await
+}
+
+#[derive(Deserialize)]
+struct PasswordForm {
+    password: String,
+}
+
+/// The password form on a protected link posts back here
+async fn redirect_with_password(
+    Path(path): Path<RedirectPath>,
+    uri: Uri,
+    State(state): State<AppState>,
     headers: HeaderMap,
-    insecure_ip: InsecureClientIp,
-) -> Result<axum::response::Redirect, (StatusCode, &'static str)> {
-    let moved_db = database.clone();
-    let moved_code = code.clone();
-    if let Ok(redirect) = tokio::task::spawn_blocking(move || moved_db.get_url(moved_code)).await {
-        if let Some(redirect) = redirect {
-            let ip = if database.behind_traefik {
-                if let Some(h) = headers.get("X-Forwarded-For") {
-                    h.to_str().unwrap_or("unknown").to_string()
-                } else {
-                    "unknown".to_string()
-                }
-            } else {
-                insecure_ip.0.to_string()
-            };
-            let moved_redirect = redirect.clone();
-            tokio::task::spawn_blocking(move || database.log(code, moved_redirect, ip));
-            return Ok(axum::response::Redirect::to(redirect.as_str()));
+    ConnectInfo(socket): ConnectInfo<SocketAddr>,
+    Form(form): Form<PasswordForm>,
+) -> Response {
+    let password = Some(form.password);
+    follow(state, path.code, &uri, &headers, socket, password, false).await
+}
+
+async fn follow(
+    state: AppState,
+    code: String,
+    uri: &Uri,
+    headers: &HeaderMap,
+    socket: SocketAddr,
+    password: Option<String>,
+    head: bool,
+) -> Response {
+    let ip = client_ip(state.behind_traefik, headers, socket);
+    let header = |name| headers.get(name).and_then(|h| h.to_str().ok());
+    let location = state.geoip.lookup(&ip);
+    let visitor = resolve::Visitor {
+        ip: ip.clone(),
+        password,
+        // Taken from the raw URI so percent-encoding makes it through untouched
+        path: urls::path_suffix(uri.path()).map(str::to_string),
+        query: uri.query().map(str::to_string),
+        destination: header(COOKIE).and_then(resolve::sticky_destination),
+        user_agent: header(USER_AGENT).map(str::to_string),
+        accept_language: header(ACCEPT_LANGUAGE).map(str::to_string),
+        country: location.country.clone(),
+        kind: agents::classify(
+            head,
+            header(USER_AGENT),
+            agents::PREFETCH_HEADERS
+                .iter()
+                .filter_map(|name| headers.get(*name)?.to_str().ok()),
+        ),
+    };
+
+    let now = chrono::Utc::now();
+    let mut random = rand::random::<u32>;
+    let outcome = resolve::follow(
+        &*state.store,
+        &code,
+        &visitor,
+        &state.attempts,
+        now,
+        &mut random,
+    );
+    let outcome = match outcome.await {
+        Ok(o) => o,
+        Err(e) => {
+            error!("Failed to look up {code}: {e}");
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "500 Internal Server Error",
+            )
+                .into_response();
+        }
+    };
+    let (redirect, preview) = match outcome {
+        Outcome::Redirect(r) => (r, false),
+        // Logged like any other visit, it just doesn't get the link
+        Outcome::Preview(r) => (r, true),
+        Outcome::NotFound => return reply(Reply::not_found()),
+        Outcome::Unavailable(link_state) => {
+            info!("{code} is {link_state:?}, not redirecting");
+            return reply(state.fallbacks.reply(link_state));
         }
+        Outcome::PasswordRequired { wrong } => return reply(Reply::password_form(wrong)),
+        Outcome::TooManyAttempts => return reply(Reply::too_many_attempts()),
+    };
+
+    let fingerprint = state
+        .fingerprints
+        .fingerprint(
+            &*state.store,
+            &ip,
+            visitor.user_agent.as_deref(),
+            now,
+            &mut random,
+        )
+        .await
+        .inspect_err(|e| error!("Failed to fingerprint a visit to {code}: {e}"))
+        .ok();
+    let visit = Visit {
+        utm_template: redirect.utm_template.clone(),
+        destination: redirect.destination,
+        location,
+        client: ClientInfo::new(
+            header(REFERER),
+            visitor.user_agent.as_deref(),
+            visitor.accept_language.as_deref(),
+            header(HOST).or(uri.host()),
+        ),
+        kind: visitor.kind,
+        visitor: fingerprint,
+        ..Visit::new(&code, &redirect.url, &ip)
+    };
+    state.clicks.log(visit).await;
+    if preview {
+        return reply(Reply::preview());
     }
-    Err((StatusCode::NOT_FOUND, "404 Not Found\n-- Riplakish --"))
+    reply(state.redirects.reply(redirect))
 }
 
-async fn login(State(database): State<db::Database>, headers: HeaderMap) -> Response {
+/// Sends a reply decided by riplakish_core::resolve
+/// The address a visit came from. Behind traefik that's the last X-Forwarded-For hop, the one
+/// traefik added itself, since anything before it was written by the client.
+fn client_ip(behind_traefik: bool, headers: &HeaderMap, socket: SocketAddr) -> String {
+    if !behind_traefik {
+        return socket.ip().to_string();
+    }
+    headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .next_back()
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.rsplit(',').next())
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+fn reply(reply: Reply) -> Response {
+    let cache_control = reply.cache_control();
+    let mut builder = Response::builder()
+        .status(StatusCode::from_u16(reply.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR));
+    if let Some(location) = reply.location {
+        builder = builder.header(LOCATION, location);
+    }
+    if let Some(cookie) = reply.set_cookie {
+        builder = builder.header(SET_COOKIE, cookie);
+    }
+    let content_type = if reply.html {
+        "text/html; charset=utf-8"
+    } else {
+        "text/plain; charset=utf-8"
+    };
+    builder
+        .header(CONTENT_TYPE, content_type)
+        .header(CACHE_CONTROL, cache_control)
+        .body(reply.body.into())
+        .unwrap()
+}
+
+async fn login(State(state): State<AppState>, headers: HeaderMap) -> Response {
     // Get the username and password
     let username = headers.get("X-Username").and_then(|h| h.to_str().ok());
     let password = headers.get("X-Password").and_then(|h| h.to_str().ok());
@@ -115,21 +377,23 @@ async fn login(State(database): State<db::Database>, headers: HeaderMap) -> Resp
             .unwrap();
     }
 
-    if username.unwrap() != database.username || password.unwrap() != database.password {
+    if username.unwrap() != state.username || password.unwrap() != state.password {
         return Response::builder()
             .status(StatusCode::UNAUTHORIZED)
             .body(Default::default())
             .unwrap();
     }
 
-    let token: String = rand::thread_rng()
-        .sample_iter(&rand::distributions::Alphanumeric)
-        .take(32)
-        .map(char::from)
-        .collect();
-
-    let moved_token = token.clone();
-    tokio::task::spawn_blocking(move || database.insert_token(moved_token));
+    let token = match auth::login(&*state.store, &mut rand::random::<u32>).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Failed to insert token: {e}");
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Default::default())
+                .unwrap();
+        }
+    };
 
     Response::builder()
         .status(StatusCode::OK)
@@ -142,115 +406,222 @@ async fn login(State(database): State<db::Database>, headers: HeaderMap) -> Resp
 }
 
 #[inline]
-async fn check_login(database: &db::Database, headers: &HeaderMap) -> bool {
+async fn check_login(state: &AppState, headers: &HeaderMap) -> bool {
+    let header = headers.get("X-Token").and_then(|h| h.to_str().ok());
     let cookies = headers.get("cookie").and_then(|h| h.to_str().ok());
-    if let Some(cookies) = cookies {
-        let cookies = cookies.split(';').collect::<Vec<&str>>();
-        for cookie in cookies {
-            if cookie.starts_with("X-Token=") {
-                let token = cookie.split('=').collect::<Vec<&str>>()[1];
-                let token = token.replace(" SameSite", "");
-
-                let moved_db = database.clone();
-                if let Ok(yay) = tokio::task::spawn_blocking(move || {
-                    moved_db.check_token(token.trim().to_string())
-                })
-                .await
-                {
-                    return yay;
-                }
-            }
-        }
+    match auth::find_token(header, cookies) {
+        Some(token) => auth::check_token(&*state.store, &token).await,
+        None => false,
     }
-    false
 }
 
-async fn base_url(State(database): State<db::Database>) -> String {
-    database.base_url
+async fn base_url(State(state): State<AppState>) -> String {
+    state.base_url
 }
 
-async fn get_stats(State(database): State<db::Database>, headers: HeaderMap) -> Response {
+async fn get_stats(
+    State(state): State<AppState>,
+    Query(options): Query<StatsOptions>,
+    Query(zone): Query<DisplayZone>,
+    headers: HeaderMap,
+) -> Response {
     info!("Getting the stats...");
 
-    if check_login(&database, &headers).await {
-        if let Ok(stats) = tokio::task::spawn_blocking(move || database.get_stats()).await {
-            return Response::builder()
-                .status(StatusCode::OK)
-                .body(serde_json::to_string(&stats).unwrap().into())
-                .unwrap();
-        }
+    if !check_login(&state, &headers).await {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Default::default())
+            .unwrap();
+    }
+    json_or_error(links::list_links(&*state.store, options, &zone).await)
+}
+
+/// Sends the value as JSON, or the error with its status
+fn json_or_error(res: Result<impl serde::Serialize, links::LinkError>) -> Response {
+    match res {
+        Ok(value) => Response::builder()
+            .status(StatusCode::OK)
+            .body(serde_json::to_string(&value).unwrap().into())
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+            .body(e.to_string().into())
+            .unwrap(),
+    }
+}
+
+/// Visits broken down by the UTM template they were tagged with
+async fn get_template_stats(
+    State(state): State<AppState>,
+    Query(options): Query<StatsOptions>,
+    headers: HeaderMap,
+) -> Response {
+    if !check_login(&state, &headers).await {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Default::default())
+            .unwrap();
+    }
+    json_or_error(utm::template_visits(&*state.store, &options).await)
+}
+
+async fn get_country_stats(
+    State(state): State<AppState>,
+    Query(options): Query<StatsOptions>,
+    headers: HeaderMap,
+) -> Response {
+    country_stats(&state, &headers, None, &options).await
+}
+
+async fn get_link_country_stats(
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    Query(options): Query<StatsOptions>,
+    headers: HeaderMap,
+) -> Response {
+    country_stats(&state, &headers, Some(&code), &options).await
+}
+
+/// Visits per country for one link, or all of them
+async fn country_stats(
+    state: &AppState,
+    headers: &HeaderMap,
+    code: Option<&str>,
+    options: &StatsOptions,
+) -> Response {
+    if !check_login(state, headers).await {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Default::default())
+            .unwrap();
+    }
+    json_or_error(geo::country_visits(&*state.store, code, options).await)
+}
+
+async fn get_timeline(
+    State(state): State<AppState>,
+    Query(query): Query<SeriesQuery>,
+    headers: HeaderMap,
+) -> Response {
+    timeline(&state, &headers, None, &query).await
+}
+
+async fn get_link_timeline(
+    State(state): State<AppState>,
+    Path(code): Path<String>,
+    Query(query): Query<SeriesQuery>,
+    headers: HeaderMap,
+) -> Response {
+    timeline(&state, &headers, Some(&code), &query).await
+}
+
+/// Visits over time for one link, or all of them
+async fn timeline(
+    state: &AppState,
+    headers: &HeaderMap,
+    code: Option<&str>,
+    query: &SeriesQuery,
+) -> Response {
+    if !check_login(state, headers).await {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Default::default())
+            .unwrap();
+    }
+    json_or_error(series::clicks(&*state.store, code, query, chrono::Utc::now()).await)
+}
+
+async fn get_cache_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !check_login(&state, &headers).await {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Default::default())
+            .unwrap();
+    }
+    match &state.cache {
+        Some(cache) => Response::builder()
+            .status(StatusCode::OK)
+            .body(serde_json::to_string(&cache.stats()).unwrap().into())
+            .unwrap(),
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("The cache is turned off".into())
+            .unwrap(),
+    }
+}
+
+async fn get_click_stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !check_login(&state, &headers).await {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Default::default())
+            .unwrap();
     }
     Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .body(Default::default())
+        .status(StatusCode::OK)
+        .body(serde_json::to_string(&state.clicks.stats()).unwrap().into())
         .unwrap()
 }
 
 async fn get_logs(
-    State(database): State<db::Database>,
+    State(state): State<AppState>,
     Path(code): Path<String>,
+    Query(zone): Query<DisplayZone>,
     headers: HeaderMap,
 ) -> Response {
     info!("Getting the logs for {code}");
 
-    if check_login(&database, &headers).await {
-        if let Ok(logs) = tokio::task::spawn_blocking(move || database.get_logs(code)).await {
-            return Response::builder()
-                .status(StatusCode::OK)
-                .body(serde_json::to_string(&logs).unwrap().into())
-                .unwrap();
-        }
+    if !check_login(&state, &headers).await {
+        return Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Default::default())
+            .unwrap();
     }
-    Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
-        .body(Default::default())
-        .unwrap()
+    json_or_error(links::logs(&*state.store, &code, &zone).await)
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    code: Option<String>,
 }
 
 async fn add_url(
     Path(url): Path<String>,
-    State(database): State<db::Database>,
+    Query(params): Query<AddParams>,
+    State(state): State<AppState>,
     headers: HeaderMap,
-) -> Result<(StatusCode, String), StatusCode> {
-    if check_login(&database, &headers).await {
-        let s: String = rand::thread_rng()
-            .sample_iter(&rand::distributions::Alphanumeric)
-            .take(4)
-            .map(char::from)
-            .collect();
-        info!("Attempting to insert {url} with code {s}");
-        if let Ok(res) = tokio::task::spawn_blocking(move || {
-            if database.insert_url(&url, &s) {
-                Ok((StatusCode::OK, s))
-            } else {
-                Err(StatusCode::INTERNAL_SERVER_ERROR)
-            }
-        })
-        .await
-        {
-            return res;
-        } else {
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
-        }
-    } else {
-        Err(StatusCode::UNAUTHORIZED)
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    if !check_login(&state, &headers).await {
+        return Err((StatusCode::UNAUTHORIZED, String::new()));
+    }
+
+    match links::create_redirect(
+        &*state.store,
+        &state.policies,
+        &url,
+        params.code,
+        &mut rand::random::<u32>,
+    )
+    .await
+    {
+        Ok(code) => Ok((StatusCode::OK, code)),
+        Err(e) => Err((
+            StatusCode::from_u16(e.status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            e.to_string(),
+        )),
     }
 }
 
 async fn remove_url(
     Path(code): Path<String>,
-    State(database): State<db::Database>,
+    State(state): State<AppState>,
     headers: HeaderMap,
 ) -> StatusCode {
     warn!("Removing redirect code {code}");
 
-    if check_login(&database, &headers).await {
-        if let Ok(res) = tokio::task::spawn_blocking(move || database.remove_url(code)).await {
-            if res {
-                StatusCode::OK
-            } else {
-                StatusCode::NOT_FOUND
-            }
+    if check_login(&state, &headers).await {
+        if state.store.remove_link(&code).await.is_ok() {
+            StatusCode::OK
         } else {
             StatusCode::INTERNAL_SERVER_ERROR
         }
@@ -261,20 +632,21 @@ async fn remove_url(
 
 async fn modify_url(
     Path((code, new_url)): Path<(String, String)>,
-    State(database): State<db::Database>,
+    State(state): State<AppState>,
     headers: HeaderMap,
 ) -> StatusCode {
     info!("Updating {code} to new URL: {new_url}");
 
-    if check_login(&database, &headers).await {
-        if let Ok(res) =
-            tokio::task::spawn_blocking(move || database.modify_url(code, new_url)).await
+    if check_login(&state, &headers).await {
+        let new_url = match state.policies.urls.validate(&new_url) {
+            Ok(u) => u,
+            Err(_) => return StatusCode::BAD_REQUEST,
+        };
+        let now = timestamp(chrono::Utc::now());
+        if state.store.update_url(&code, &new_url).await.is_ok()
+            && state.store.touch_link(&code, &now).await.is_ok()
         {
-            if res {
-                StatusCode::OK
-            } else {
-                StatusCode::NOT_FOUND
-            }
+            StatusCode::OK
         } else {
             StatusCode::INTERNAL_SERVER_ERROR
         }
@@ -285,20 +657,21 @@ async fn modify_url(
 
 async fn modify_comment(
     Path((code, new_comment)): Path<(String, String)>,
-    State(database): State<db::Database>,
+    State(state): State<AppState>,
     headers: HeaderMap,
 ) -> StatusCode {
     info!("Updating {code} to new comment: {new_comment}");
 
-    if check_login(&database, &headers).await {
-        if let Ok(res) =
-            tokio::task::spawn_blocking(move || database.modify_comment(code, new_comment)).await
+    if check_login(&state, &headers).await {
+        let now = timestamp(chrono::Utc::now());
+        if state
+            .store
+            .update_comment(&code, &new_comment)
+            .await
+            .is_ok()
+            && state.store.touch_link(&code, &now).await.is_ok()
         {
-            if res {
-                StatusCode::OK
-            } else {
-                StatusCode::NOT_FOUND
-            }
+            StatusCode::OK
         } else {
             StatusCode::INTERNAL_SERVER_ERROR
         }
@@ -0,0 +1,116 @@
+// Jackson Coxson
+
+use std::sync::Arc;
+
+use log::{info, warn};
+use riplakish_core::{
+    links::Policies,
+    resolve::{AttemptLimit, Fallbacks, Redirects},
+    store::{memory::MemoryStore, LinkStore},
+    visitors::Fingerprinter,
+};
+
+use crate::{
+    clicks::{self, ClickConfig, ClickLogger, ClickWriter},
+    geoip::GeoIp,
+    store::{
+        cache::{CacheConfig, CachedStore},
+        sqlite::SqliteStore,
+    },
+};
+
+/// Everything the handlers share
+#[derive(Clone)]
+pub struct AppState {
+    pub store: Arc<dyn LinkStore + Send + Sync>,
+    /// The same store as above, if redirects are being cached
+    pub cache: Option<Arc<CachedStore>>,
+    pub clicks: ClickLogger,
+    pub behind_traefik: bool,
+    pub base_url: String,
+    pub username: String,
+    pub password: String,
+    pub policies: Policies,
+    /// What links that aren't redirecting respond with
+    pub fallbacks: Fallbacks,
+    /// Wrong passwords allowed on protected links
+    pub attempts: AttemptLimit,
+    /// The default redirect status and how long redirects are cached
+    pub redirects: Redirects,
+    pub geoip: Arc<GeoIp>,
+    /// Hashes visitors so unique visitors can be counted without keeping IPs around
+    pub fingerprints: Arc<Fingerprinter>,
+}
+
+impl AppState {
+    /// The click writer that comes back needs to be run for visits to be saved
+    pub fn new() -> (Self, ClickWriter) {
+        let username = std::env::var("USERNAME").expect("USERNAME environment variable not set");
+        let password = std::env::var("PASSWORD").expect("PASSWORD environment variable not set");
+
+        // STORE=memory keeps everything in memory, handy for trying things out
+        let store: Arc<dyn LinkStore + Send + Sync> =
+            match std::env::var("STORE").unwrap_or_default().as_str() {
+                "memory" => {
+                    info!("Using the in-memory store, nothing will be saved");
+                    Arc::new(MemoryStore::new())
+                }
+                _ => {
+                    let filename =
+                        std::env::var("SQLITE_PATH").unwrap_or("riplakish.db".to_string());
+                    let pool_size = std::env::var("SQLITE_POOL_SIZE")
+                        .ok()
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or(4);
+                    Arc::new(SqliteStore::new(filename, pool_size))
+                }
+            };
+
+        let cache = CachedStore::new(
+            store.clone(),
+            CacheConfig::from_vars(|key| std::env::var(key).ok()),
+        )
+        .map(Arc::new);
+        let store = match &cache {
+            Some(cache) => cache.clone(),
+            None => store,
+        };
+
+        let behind_traefik = if let Ok(v) = std::env::var("BEHIND_TRAEFIK") {
+            v == "true"
+        } else {
+            false
+        };
+
+        let base_url = std::env::var("BASE_URL").expect("Base URL is not set");
+        let policies = Policies::from_vars(|key| std::env::var(key).ok());
+        let fallbacks = Fallbacks::from_vars(|key| std::env::var(key).ok());
+        let attempts = AttemptLimit::from_vars(|key| std::env::var(key).ok());
+        let redirects = Redirects::from_vars(|key| std::env::var(key).ok());
+        let geoip = Arc::new(GeoIp::from_vars(|key| std::env::var(key).ok()));
+        let fingerprints =
+            Fingerprinter::from_vars(|key| std::env::var(key).ok()).unwrap_or_else(|| {
+                warn!("VISITOR_SECRET isn't set, unique visitors will start over on every restart");
+                Fingerprinter::new(&rand::random::<[u8; 32]>())
+            });
+        let (clicks, click_writer) =
+            clicks::channel(ClickConfig::from_vars(|key| std::env::var(key).ok()));
+
+        let state = Self {
+            store,
+            cache,
+            clicks,
+            behind_traefik,
+            base_url,
+            username,
+            password,
+            policies,
+            fallbacks,
+            attempts,
+            redirects,
+            geoip,
+            fingerprints: Arc::new(fingerprints),
+        };
+        (state, click_writer)
+    }
+}
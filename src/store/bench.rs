@@ -0,0 +1,126 @@
+// Jackson Coxson
+// Redirect latency under concurrent load, pooled connections against opening one per request.
+// cargo test --release bench -- --ignored --nocapture
+
+use std::time::{Duration, Instant};
+
+use super::{sqlite::SqliteStore, LinkStore, Visit};
+
+const CODES: usize = 100;
+const CLIENTS: usize = 64;
+const REQUESTS: usize = 200;
+
+/// Prints latency percentiles and throughput for a set of timings
+fn report(name: &str, mut timings: Vec<Duration>, total: Duration) {
+    timings.sort();
+    let percentile = |p: usize| timings[(timings.len() * p / 100).min(timings.len() - 1)];
+    println!(
+        "{name}: {} redirects in {:?} ({:.0}/s), p50 {:?}, p95 {:?}, p99 {:?}",
+        timings.len(),
+        total,
+        timings.len() as f64 / total.as_secs_f64(),
+        percentile(50),
+        percentile(95),
+        percentile(99),
+    );
+}
+
+/// Runs CLIENTS concurrent clients each following REQUESTS redirects
+async fn load<F, Fut>(redirect: F) -> (Vec<Duration>, Duration)
+where
+    F: Fn(String) -> Fut + Clone + Send + 'static,
+    Fut: std::future::Future<Output = ()> + Send,
+{
+    let start = Instant::now();
+    let clients = (0..CLIENTS)
+        .map(|client| {
+            let redirect = redirect.clone();
+            tokio::spawn(async move {
+                let mut timings = Vec::with_capacity(REQUESTS);
+                for i in 0..REQUESTS {
+                    let code = format!("code{}", (client * REQUESTS + i) % CODES);
+                    let started = Instant::now();
+                    redirect(code).await;
+                    timings.push(started.elapsed());
+                }
+                timings
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut timings = Vec::new();
+    for client in clients {
+        timings.extend(client.await.unwrap());
+    }
+    (timings, start.elapsed())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_redirects() {
+    let path = std::env::temp_dir().join(format!("riplakish-bench-{}.db", rand::random::<u32>()));
+    let filename = path.to_string_lossy().to_string();
+    let store = SqliteStore::new(filename.clone(), 4);
+    for i in 0..CODES {
+        store
+            .insert_link(
+                &format!("code{i}"),
+                "https://example.com/",
+                "2024-05-01T00:00:00Z",
+            )
+            .await
+            .unwrap();
+    }
+
+    let pooled = store.clone();
+    let (timings, total) = load(move |code| {
+        let store = pooled.clone();
+        async move {
+            let target = store.get_target(&code).await.unwrap().unwrap();
+            store
+                .log_visit(Visit::new(&code, &target.url, "127.0.0.1"))
+                .await
+                .unwrap();
+        }
+    })
+    .await;
+    report("pooled", timings, total);
+
+    // What every redirect used to do
+    let (timings, total) = load(move |code| {
+        let filename = filename.clone();
+        async move {
+            tokio::task::spawn_blocking(move || {
+                let connection = sqlite::open(&filename).unwrap();
+                let mut statement = connection
+                    .prepare("SELECT url FROM redirects WHERE redirect = ?")
+                    .unwrap();
+                statement.bind((1, code.as_str())).unwrap();
+                statement.next().unwrap();
+                let url = statement.read::<String, _>(0).unwrap();
+                let mut statement = connection
+                    .prepare("INSERT INTO log (redirect, ip, url, timestamp) VALUES (?, ?, ?, ?)")
+                    .unwrap();
+                let visit = Visit::new(&code, &url, "127.0.0.1");
+                statement
+                    .bind(
+                        &[
+                            (1, visit.code.as_str()),
+                            (2, visit.ip.as_str()),
+                            (3, visit.url.as_str()),
+                            (4, visit.timestamp.as_str()),
+                        ][..],
+                    )
+                    .unwrap();
+                // Without a busy timeout some of these fail outright, which is part of the point
+                let _ = statement.next();
+            })
+            .await
+            .unwrap();
+        }
+    })
+    .await;
+    report("open per request", timings, total);
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,475 @@
+// Jackson Coxson
+// Keeps recently visited codes in memory so popular links don't touch the database.
+// Wraps another store, anything that changes a code's destination drops it from the cache.
+
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use lru::LruCache;
+use serde::Serialize;
+
+use riplakish_core::{
+    geo::CountryVisits, links::NewDestination, series::Range, utm::UtmTemplate,
+    visitors::LinkVisitors,
+};
+
+use super::{
+    DatabaseLog, DatabaseStats, LinkChanges, LinkStore, NewLinkRow, Rules, StoreResult, Target,
+    TemplateVisits, Visit,
+};
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub capacity: usize,
+    /// How long a destination is trusted
+    pub ttl: Duration,
+    /// How long an unknown code is remembered as unknown
+    pub negative_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            ttl: Duration::from_secs(300),
+            negative_ttl: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Reads CACHE_SIZE, CACHE_TTL_SECS and CACHE_NEGATIVE_TTL_SECS.
+    /// A CACHE_SIZE of 0 turns the cache off.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let default = Self::default();
+        let secs = |key: &str, default: Duration| {
+            var(key)
+                .and_then(|v| v.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+        Self {
+            capacity: var("CACHE_SIZE")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default.capacity),
+            ttl: secs("CACHE_TTL_SECS", default.ttl),
+            negative_ttl: secs("CACHE_NEGATIVE_TTL_SECS", default.negative_ttl),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+struct Entry {
+    /// None if the code doesn't exist
+    target: Option<Target>,
+    expires: Instant,
+}
+
+/// Which forgets a read has seen, see CachedStore::generation
+type Generation = (u64, u64);
+
+struct Entries {
+    lru: LruCache<String, Entry>,
+    /// Bumped for a code every time it's forgotten
+    generations: HashMap<String, u64>,
+    /// Bumped every time everything is forgotten
+    epoch: u64,
+}
+
+impl Entries {
+    fn generation(&self, code: &str) -> Generation {
+        (
+            self.epoch,
+            self.generations.get(code).copied().unwrap_or_default(),
+        )
+    }
+}
+
+pub struct CachedStore {
+    inner: Arc<dyn LinkStore + Send + Sync>,
+    config: CacheConfig,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CachedStore {
+    /// Returns None if the config turns the cache off
+    pub fn new(inner: Arc<dyn LinkStore + Send + Sync>, config: CacheConfig) -> Option<Self> {
+        let capacity = NonZeroUsize::new(config.capacity)?;
+        Some(Self {
+            inner,
+            config,
+            entries: Mutex::new(Entries {
+                lru: LruCache::new(capacity),
+                generations: HashMap::new(),
+                epoch: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().map(|e| e.lru.len()).unwrap_or(0),
+        }
+    }
+
+    /// The cached target, or the generation to remember a fresh read with
+    fn lookup(&self, code: &str) -> Result<Option<Target>, Option<Generation>> {
+        let Ok(mut entries) = self.entries.lock() else {
+            return Err(None);
+        };
+        match entries.lru.get(code) {
+            Some(entry) if entry.expires > Instant::now() => return Ok(entry.target.clone()),
+            Some(_) => {
+                entries.lru.pop(code);
+            }
+            None => {}
+        }
+        Err(Some(entries.generation(code)))
+    }
+
+    /// Keeps a target read at the given generation, unless the code was forgotten since then.
+    /// Otherwise a read from before an update could be kept after the update.
+    fn remember(&self, code: &str, target: Option<Target>, seen: Generation) {
+        let ttl = if target.is_some() {
+            self.config.ttl
+        } else {
+            self.config.negative_ttl
+        };
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.generation(code) != seen {
+                return;
+            }
+            entries.lru.put(
+                code.to_string(),
+                Entry {
+                    target,
+                    expires: Instant::now() + ttl,
+                },
+            );
+        }
+    }
+
+    fn forget(&self, code: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.lru.pop(code);
+            *entries.generations.entry(code.to_string()).or_default() += 1;
+        }
+    }
+
+    fn forget_all(&self) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.lru.clear();
+            // Every code is newer than any read in flight now
+            entries.generations.clear();
+            entries.epoch += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl LinkStore for CachedStore {
+    async fn get_target(&self, code: &str) -> StoreResult<Option<Target>> {
+        let seen = match self.lookup(code) {
+            Ok(target) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(target);
+            }
+            Err(seen) => seen,
+        };
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let target = self.inner.get_target(code).await?;
+        if let Some(seen) = seen {
+            self.remember(code, target.clone(), seen);
+        }
+        Ok(target)
+    }
+
+    async fn get_link(&self, code: &str) -> StoreResult<Option<DatabaseStats>> {
+        self.inner.get_link(code).await
+    }
+
+    async fn list_links(&self) -> StoreResult<Vec<DatabaseStats>> {
+        self.inner.list_links().await
+    }
+
+    async fn count_links(&self) -> StoreResult<usize> {
+        self.inner.count_links().await
+    }
+
+    async fn insert_link_row(&self, code: &str, link: &NewLinkRow, now: &str) -> StoreResult<()> {
+        // The code might be remembered as unknown
+        let res = self.inner.insert_link_row(code, link, now).await;
+        self.forget(code);
+        res
+    }
+
+    async fn update_link_row(
+        &self,
+        code: &str,
+        changes: &LinkChanges,
+        now: &str,
+    ) -> StoreResult<()> {
+        let res = self.inner.update_link_row(code, changes, now).await;
+        self.forget(code);
+        res
+    }
+
+    async fn touch_link(&self, code: &str, now: &str) -> StoreResult<()> {
+        self.inner.touch_link(code, now).await
+    }
+
+    async fn update_url(&self, code: &str, url: &str) -> StoreResult<()> {
+        let res = self.inner.update_url(code, url).await;
+        self.forget(code);
+        res
+    }
+
+    async fn update_comment(&self, code: &str, comment: &str) -> StoreResult<()> {
+        self.inner.update_comment(code, comment).await
+    }
+
+    async fn update_rules(&self, code: &str, rules: &Rules) -> StoreResult<()> {
+        let res = self.inner.update_rules(code, rules).await;
+        self.forget(code);
+        res
+    }
+
+    async fn claim_visit(&self, code: &str) -> StoreResult<bool> {
+        // Always the real count, a cached one could let an extra visit through
+        self.inner.claim_visit(code).await
+    }
+
+    async fn set_destinations(
+        &self,
+        code: &str,
+        destinations: &[NewDestination],
+    ) -> StoreResult<()> {
+        let res = self.inner.set_destinations(code, destinations).await;
+        self.forget(code);
+        res
+    }
+
+    async fn remove_link(&self, code: &str) -> StoreResult<()> {
+        let res = self.inner.remove_link(code).await;
+        self.forget(code);
+        res
+    }
+
+    async fn claim_attempt(
+        &self,
+        code: &str,
+        ip: &str,
+        now: &str,
+        since: &str,
+        max_attempts: u64,
+    ) -> StoreResult<bool> {
+        self.inner
+            .claim_attempt(code, ip, now, since, max_attempts)
+            .await
+    }
+
+    async fn release_attempt(&self, code: &str, ip: &str) -> StoreResult<()> {
+        self.inner.release_attempt(code, ip).await
+    }
+
+    async fn log_visit(&self, visit: Visit) -> StoreResult<()> {
+        self.inner.log_visit(visit).await
+    }
+
+    async fn log_visits(&self, visits: Vec<Visit>) -> StoreResult<()> {
+        self.inner.log_visits(visits).await
+    }
+
+    async fn get_logs(&self, code: &str) -> StoreResult<Vec<DatabaseLog>> {
+        self.inner.get_logs(code).await
+    }
+
+    async fn get_template(&self, name: &str) -> StoreResult<Option<UtmTemplate>> {
+        self.inner.get_template(name).await
+    }
+
+    async fn list_templates(&self) -> StoreResult<Vec<UtmTemplate>> {
+        self.inner.list_templates().await
+    }
+
+    async fn save_template(&self, template: &UtmTemplate) -> StoreResult<()> {
+        self.inner.save_template(template).await
+    }
+
+    async fn remove_template(&self, name: &str) -> StoreResult<()> {
+        // Any number of links could have been using it
+        let res = self.inner.remove_template(name).await;
+        self.forget_all();
+        res
+    }
+
+    async fn template_visits(&self, include_bots: bool) -> StoreResult<Vec<TemplateVisits>> {
+        self.inner.template_visits(include_bots).await
+    }
+
+    async fn country_visits(
+        &self,
+        code: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<CountryVisits>> {
+        self.inner.country_visits(code, include_bots).await
+    }
+
+    async fn unique_visitors(
+        &self,
+        code: Option<&str>,
+        since: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<LinkVisitors>> {
+        self.inner.unique_visitors(code, since, include_bots).await
+    }
+
+    async fn count_visits(
+        &self,
+        code: Option<&str>,
+        ranges: &[Range],
+        include_bots: bool,
+    ) -> StoreResult<Vec<usize>> {
+        self.inner.count_visits(code, ranges, include_bots).await
+    }
+
+    async fn day_salt(&self, day: &str, new_salt: &str) -> StoreResult<String> {
+        self.inner.day_salt(day, new_salt).await
+    }
+
+    async fn insert_token(&self, token: &str, expiration: &str) -> StoreResult<()> {
+        self.inner.insert_token(token, expiration).await
+    }
+
+    async fn token_expiration(&self, token: &str) -> StoreResult<Option<String>> {
+        self.inner.token_expiration(token).await
+    }
+
+    async fn remove_token(&self, token: &str) -> StoreResult<()> {
+        self.inner.remove_token(token).await
+    }
+
+    async fn remove_expired_tokens(&self, now: &str) -> StoreResult<()> {
+        self.inner.remove_expired_tokens(now).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::memory::MemoryStore;
+
+    fn cached(config: CacheConfig) -> (Arc<MemoryStore>, CachedStore) {
+        let inner = Arc::new(MemoryStore::new());
+        let store = CachedStore::new(inner.clone(), config).unwrap();
+        (inner, store)
+    }
+
+    #[tokio::test]
+    async fn conformance() {
+        let (_, store) = cached(CacheConfig::default());
+        super::super::check_store(&store).await;
+    }
+
+    const NOW: &str = "2024-05-01T00:00:00Z";
+
+    async fn url(store: &CachedStore, code: &str) -> Option<String> {
+        store.get_target(code).await.unwrap().map(|t| t.url)
+    }
+
+    #[tokio::test]
+    async fn cache() {
+        let (inner, store) = cached(CacheConfig::default());
+
+        let (a, link) = (Some("https://a.com/".to_string()), "https://a.com/");
+        store.insert_link("a", link, NOW).await.unwrap();
+        assert_eq!(url(&store, "a").await, a);
+        assert_eq!(url(&store, "a").await, a);
+        assert_eq!(url(&store, "b").await, None);
+        assert_eq!(url(&store, "b").await, None);
+        let stats = store.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+
+        // Changes that skip the cache aren't seen until the entry expires
+        inner.update_url("a", "https://stale.com/").await.unwrap();
+        inner.insert_link("b", link, NOW).await.unwrap();
+        assert_eq!(url(&store, "a").await, a);
+        assert_eq!(url(&store, "b").await, None);
+
+        // Changes through the cache are seen right away
+        store.update_url("a", "https://c.com/").await.unwrap();
+        assert_eq!(url(&store, "a").await, Some("https://c.com/".to_string()));
+        let rules = Rules {
+            expires_at: Some("2024-05-01T12:00:00Z".to_string()),
+            ..Default::default()
+        };
+        store.update_rules("a", &rules).await.unwrap();
+        assert_eq!(store.get_target("a").await.unwrap().unwrap().rules, rules);
+        store.remove_link("a").await.unwrap();
+        assert_eq!(url(&store, "a").await, None);
+        store.insert_link("a", link, NOW).await.unwrap();
+        assert_eq!(url(&store, "a").await, a);
+    }
+
+    #[tokio::test]
+    async fn update_during_miss() {
+        let (inner, store) = cached(CacheConfig::default());
+        store.insert_link("a", "https://a.com/", NOW).await.unwrap();
+
+        // A miss reads the old URL, then an update lands before it's remembered
+        let seen = store.lookup("a").unwrap_err().unwrap();
+        let old = inner.get_target("a").await.unwrap();
+        store.update_url("a", "https://b.com/").await.unwrap();
+        store.remember("a", old.clone(), seen);
+        assert_eq!(url(&store, "a").await, Some("https://b.com/".to_string()));
+
+        // Same for a template change, which forgets everything
+        store.forget("a");
+        let seen = store.lookup("a").unwrap_err().unwrap();
+        store.forget_all();
+        store.remember("a", old, seen);
+        assert_eq!(url(&store, "a").await, Some("https://b.com/".to_string()));
+    }
+
+    #[tokio::test]
+    async fn expiry() {
+        let (inner, store) = cached(CacheConfig {
+            capacity: 1,
+            ttl: Duration::ZERO,
+            negative_ttl: Duration::ZERO,
+        });
+        inner.insert_link("a", "https://a.com/", NOW).await.unwrap();
+        store.get_target("a").await.unwrap();
+        store.get_target("a").await.unwrap();
+        assert_eq!(store.stats().hits, 0);
+
+        assert!(CachedStore::new(
+            inner,
+            CacheConfig {
+                capacity: 0,
+                ..Default::default()
+            }
+        )
+        .is_none());
+    }
+}
@@ -0,0 +1,889 @@
+// Jackson Coxson
+// Cloudflare D1 backend for the worker
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use riplakish_core::{
+    agents::{Browser, ClickKind, ClientInfo, Device, Os},
+    geo::{CountryVisits, Location},
+    links::NewDestination,
+    series::{self, Range},
+    targeting,
+    utm::{UtmParams, UtmTemplate},
+    visitors::LinkVisitors,
+};
+use serde::Deserialize;
+use worker::{wasm_bindgen::JsValue, D1Database, D1PreparedStatement};
+
+use super::{
+    DatabaseLog, DatabaseStats, Destination, DestinationStats, LinkChanges, LinkStore, NewLinkRow,
+    Rules, StoreError, StoreResult, Target, TemplateVisits, Visit,
+};
+
+pub struct D1Store {
+    db: D1Database,
+}
+
+#[derive(Deserialize)]
+struct Salt {
+    salt: String,
+}
+
+#[derive(Deserialize)]
+struct RangeVisits {
+    visits: usize,
+}
+
+#[derive(Deserialize)]
+struct Stat {
+    url: String,
+    redirect: String,
+    log_count: u32,
+    bot_count: u32,
+    preview_count: u32,
+    unique_count: u32,
+    comment: Option<String>,
+    uses: u64,
+    expires_at: Option<String>,
+    max_visits: Option<u64>,
+    not_before: Option<String>,
+    password_hash: Option<String>,
+    redirect_status: Option<u16>,
+    forward_query: u8,
+    forward_path: u8,
+    utm_template: Option<String>,
+    sticky: u8,
+    targeting: Option<String>,
+    created_at: Option<String>,
+    updated_at: Option<String>,
+}
+
+impl From<Stat> for DatabaseStats {
+    fn from(value: Stat) -> Self {
+        Self {
+            url: value.url,
+            code: value.redirect,
+            comment: value.comment.unwrap_or_default(),
+            created_at: value.created_at,
+            updated_at: value.updated_at,
+            visits: value.log_count as usize,
+            bot_visits: value.bot_count as usize,
+            preview_visits: value.preview_count as usize,
+            unique_visitors: value.unique_count as usize,
+            uses: value.uses,
+            rules: Rules {
+                expires_at: value.expires_at,
+                max_visits: value.max_visits,
+                not_before: value.not_before,
+                password_hash: value.password_hash,
+                redirect_status: value.redirect_status,
+                forward_query: value.forward_query != 0,
+                forward_path: value.forward_path != 0,
+                utm_template: value.utm_template,
+                sticky: value.sticky != 0,
+                targeting: targeting::decode(value.targeting.as_deref()),
+            },
+            destinations: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TargetRow {
+    url: String,
+    expires_at: Option<String>,
+    max_visits: Option<u64>,
+    not_before: Option<String>,
+    password_hash: Option<String>,
+    redirect_status: Option<u16>,
+    forward_query: u8,
+    forward_path: u8,
+    utm_template: Option<String>,
+    sticky: u8,
+    targeting: Option<String>,
+}
+
+impl From<TargetRow> for Target {
+    fn from(value: TargetRow) -> Self {
+        Self {
+            url: value.url,
+            rules: Rules {
+                expires_at: value.expires_at,
+                max_visits: value.max_visits,
+                not_before: value.not_before,
+                password_hash: value.password_hash,
+                redirect_status: value.redirect_status,
+                forward_query: value.forward_query != 0,
+                forward_path: value.forward_path != 0,
+                utm_template: value.utm_template,
+                sticky: value.sticky != 0,
+                targeting: targeting::decode(value.targeting.as_deref()),
+            },
+            destinations: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct DestinationRow {
+    redirect: String,
+    id: u64,
+    url: String,
+    weight: u32,
+    visits: usize,
+}
+
+impl From<DestinationRow> for DestinationStats {
+    fn from(value: DestinationRow) -> Self {
+        Self {
+            destination: Destination {
+                id: value.id,
+                url: value.url,
+                weight: value.weight,
+            },
+            visits: value.visits,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TemplateRow {
+    name: String,
+    source: Option<String>,
+    medium: Option<String>,
+    campaign: Option<String>,
+    term: Option<String>,
+    content: Option<String>,
+}
+
+impl From<TemplateRow> for UtmTemplate {
+    fn from(value: TemplateRow) -> Self {
+        Self {
+            name: value.name,
+            params: UtmParams {
+                source: value.source,
+                medium: value.medium,
+                campaign: value.campaign,
+                term: value.term,
+                content: value.content,
+            },
+        }
+    }
+}
+
+/// D1 hands back numbers as floats, which serde can't read into a flattened Location
+#[derive(Deserialize)]
+struct LogRow {
+    timestamp: String,
+    ip: String,
+    url: String,
+    destination: Option<u64>,
+    country: Option<String>,
+    region: Option<String>,
+    city: Option<String>,
+    asn: Option<u32>,
+    referrer: Option<String>,
+    user_agent: Option<String>,
+    accept_language: Option<String>,
+    host: Option<String>,
+    browser: Option<Browser>,
+    os: Option<Os>,
+    device: Option<Device>,
+    kind: ClickKind,
+    visitor: Option<String>,
+}
+
+impl From<LogRow> for DatabaseLog {
+    fn from(value: LogRow) -> Self {
+        Self {
+            timestamp: value.timestamp,
+            ip: value.ip,
+            url: value.url,
+            destination: value.destination,
+            location: Location {
+                country: value.country,
+                region: value.region,
+                city: value.city,
+                asn: value.asn,
+            },
+            client: ClientInfo {
+                referrer: value.referrer,
+                user_agent: value.user_agent,
+                accept_language: value.accept_language,
+                host: value.host,
+                browser: value.browser,
+                os: value.os,
+                device: value.device,
+            },
+            kind: value.kind,
+            visitor: value.visitor,
+        }
+    }
+}
+
+/// A parameter that may be NULL
+fn nullable(value: Option<impl Into<JsValue>>) -> JsValue {
+    value.map(Into::into).unwrap_or(JsValue::NULL)
+}
+
+/// The rule columns in the order update_rules and insert_link_row write them
+fn rule_params(rules: &Rules) -> Vec<JsValue> {
+    vec![
+        nullable(rules.expires_at.as_deref()),
+        nullable(rules.max_visits.map(|m| m as f64)),
+        nullable(rules.not_before.as_deref()),
+        nullable(rules.password_hash.as_deref()),
+        nullable(rules.redirect_status),
+        (rules.forward_query as u8).into(),
+        (rules.forward_path as u8).into(),
+        nullable(rules.utm_template.as_deref()),
+        (rules.sticky as u8).into(),
+        nullable(targeting::encode(&rules.targeting)),
+    ]
+}
+
+fn store_error(value: worker::Error) -> StoreError {
+    let message = value.to_string();
+    if message.contains("UNIQUE constraint failed") {
+        StoreError::Conflict
+    } else {
+        StoreError::Backend(message)
+    }
+}
+
+impl D1Store {
+    pub fn new(db: D1Database) -> Self {
+        Self { db }
+    }
+
+    fn prepare(&self, query: &str, params: &[&str]) -> StoreResult<D1PreparedStatement> {
+        let params = params.iter().map(|p| (*p).into()).collect::<Vec<_>>();
+        self.db.prepare(query).bind(&params).map_err(store_error)
+    }
+
+    /// Adds a destination to a link, if the link exists
+    fn insert_destination(
+        &self,
+        code: &str,
+        destination: &NewDestination,
+    ) -> StoreResult<D1PreparedStatement> {
+        self.db
+            .prepare(
+                "INSERT INTO destinations (redirect, url, weight) SELECT ?1, ?2, ?3
+                WHERE EXISTS (SELECT 1 FROM redirects WHERE redirect = ?1)",
+            )
+            .bind(&[
+                code.into(),
+                destination.url.as_str().into(),
+                destination.weight.into(),
+            ])
+            .map_err(store_error)
+    }
+
+    /// Replaces every rule on a link
+    fn rules_statement(&self, code: &str, rules: &Rules) -> StoreResult<D1PreparedStatement> {
+        self.db
+            .prepare(
+                "UPDATE redirects SET expires_at = ?, max_visits = ?, not_before = ?, password_hash = ?,
+                redirect_status = ?, forward_query = ?, forward_path = ?, utm_template = ?,
+                sticky = ?, targeting = ? WHERE redirect = ?",
+            )
+            .bind(&[rule_params(rules), vec![code.into()]].concat())
+            .map_err(store_error)
+    }
+
+    async fn execute(&self, query: &str, params: &[&str]) -> StoreResult<()> {
+        self.prepare(query, params)?
+            .run()
+            .await
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    /// Destinations with their visits for one link, or every link, grouped by code
+    async fn destination_stats(
+        &self,
+        code: Option<&str>,
+    ) -> StoreResult<HashMap<String, Vec<DestinationStats>>> {
+        let query = self
+            .db
+            .prepare(
+                "SELECT d.redirect, d.id, d.url, d.weight, COUNT(l.id) AS visits
+                FROM destinations d
+                LEFT JOIN log l ON l.destination = d.id AND l.kind = 'human'
+                WHERE ?1 IS NULL OR d.redirect = ?1
+                GROUP BY d.id ORDER BY d.id",
+            )
+            .bind(&[nullable(code)])
+            .map_err(store_error)?;
+        let mut res = HashMap::<String, Vec<DestinationStats>>::new();
+        for row in query
+            .all()
+            .await
+            .map_err(store_error)?
+            .results::<DestinationRow>()
+            .map_err(store_error)?
+        {
+            res.entry(row.redirect.clone())
+                .or_default()
+                .push(row.into());
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait(?Send)]
+impl LinkStore for D1Store {
+    async fn get_target(&self, code: &str) -> StoreResult<Option<Target>> {
+        // Both in one round trip, this is on every redirect
+        let results = self
+            .db
+            .batch(vec![
+                self.prepare(
+                    "SELECT url, expires_at, max_visits, not_before, password_hash, redirect_status,
+                        forward_query, forward_path, utm_template, sticky, targeting
+                    FROM redirects WHERE redirect = ?",
+                    &[code],
+                )?,
+                self.prepare(
+                    "SELECT id, url, weight FROM destinations WHERE redirect = ? ORDER BY id",
+                    &[code],
+                )?,
+            ])
+            .await
+            .map_err(store_error)?;
+        let [target, destinations] = &results[..] else {
+            return Err(StoreError::Backend("D1 batch came back short".to_string()));
+        };
+        let target = match target
+            .results::<TargetRow>()
+            .map_err(store_error)?
+            .into_iter()
+            .next()
+        {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        Ok(Some(Target {
+            destinations: destinations.results::<Destination>().map_err(store_error)?,
+            ..target.into()
+        }))
+    }
+
+    async fn get_link(&self, code: &str) -> StoreResult<Option<DatabaseStats>> {
+        let query = self.prepare(
+            "SELECT r.url, r.redirect,
+                COUNT(CASE WHEN l.kind = 'human' THEN 1 END) AS log_count,
+                COUNT(CASE WHEN l.kind = 'bot' THEN 1 END) AS bot_count,
+                COUNT(CASE WHEN l.kind = 'preview' THEN 1 END) AS preview_count,
+                COUNT(DISTINCT CASE WHEN l.kind = 'human' THEN l.visitor END) AS unique_count,
+                r.comment, r.uses,
+                r.expires_at, r.max_visits, r.not_before, r.password_hash, r.redirect_status,
+                r.forward_query, r.forward_path, r.utm_template, r.sticky, r.targeting,
+                r.created_at, r.updated_at
+            FROM redirects r
+            LEFT JOIN log l ON r.redirect = l.redirect
+            WHERE r.redirect = ?
+            GROUP BY r.url, r.redirect;",
+            &[code],
+        )?;
+        let mut link = match query.first::<Stat>(None).await.map_err(store_error)? {
+            Some(stat) => DatabaseStats::from(stat),
+            None => return Ok(None),
+        };
+        link.destinations = self
+            .destination_stats(Some(code))
+            .await?
+            .remove(code)
+            .unwrap_or_default();
+        Ok(Some(link))
+    }
+
+    async fn list_links(&self) -> StoreResult<Vec<DatabaseStats>> {
+        let query = self.prepare(
+            "SELECT r.url, r.redirect,
+                COUNT(CASE WHEN l.kind = 'human' THEN 1 END) AS log_count,
+                COUNT(CASE WHEN l.kind = 'bot' THEN 1 END) AS bot_count,
+                COUNT(CASE WHEN l.kind = 'preview' THEN 1 END) AS preview_count,
+                COUNT(DISTINCT CASE WHEN l.kind = 'human' THEN l.visitor END) AS unique_count,
+                r.comment, r.uses,
+                r.expires_at, r.max_visits, r.not_before, r.password_hash, r.redirect_status,
+                r.forward_query, r.forward_path, r.utm_template, r.sticky, r.targeting,
+                r.created_at, r.updated_at
+            FROM redirects r
+            LEFT JOIN log l ON r.redirect = l.redirect
+            GROUP BY r.url, r.redirect;",
+            &[],
+        )?;
+        let result = query.all().await.map_err(store_error)?;
+        let mut destinations = self.destination_stats(None).await?;
+        Ok(result
+            .results::<Stat>()
+            .map_err(store_error)?
+            .into_iter()
+            .map(|stat| DatabaseStats {
+                destinations: destinations.remove(&stat.redirect).unwrap_or_default(),
+                ..stat.into()
+            })
+            .collect())
+    }
+
+    async fn count_links(&self) -> StoreResult<usize> {
+        let query = self.prepare("SELECT COUNT(*) AS count FROM redirects", &[])?;
+        Ok(query
+            .first::<u32>(Some("count"))
+            .await
+            .map_err(store_error)?
+            .unwrap_or(0) as usize)
+    }
+
+    async fn insert_link_row(&self, code: &str, link: &NewLinkRow, now: &str) -> StoreResult<()> {
+        let mut params = vec![
+            link.url.as_str().into(),
+            code.into(),
+            nullable(link.comment.as_deref()),
+            now.into(),
+            now.into(),
+        ];
+        params.extend(rule_params(&link.rules));
+        // A batch runs as one transaction
+        let mut statements = vec![self
+            .db
+            .prepare(
+                "INSERT INTO redirects (url, redirect, comment, created_at, updated_at,
+                expires_at, max_visits, not_before, password_hash, redirect_status,
+                forward_query, forward_path, utm_template, sticky, targeting)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&params)
+            .map_err(store_error)?];
+        for destination in &link.destinations {
+            statements.push(self.insert_destination(code, destination)?);
+        }
+        self.db.batch(statements).await.map_err(store_error)?;
+        Ok(())
+    }
+
+    async fn update_link_row(
+        &self,
+        code: &str,
+        changes: &LinkChanges,
+        now: &str,
+    ) -> StoreResult<()> {
+        // A batch runs as one transaction
+        let mut statements = vec![];
+        if let Some(url) = &changes.url {
+            statements.push(self.prepare(
+                "UPDATE redirects SET url = ? WHERE redirect = ?",
+                &[url, code],
+            )?);
+        }
+        if let Some(comment) = &changes.comment {
+            statements.push(self.prepare(
+                "UPDATE redirects SET comment = ? WHERE redirect = ?",
+                &[comment, code],
+            )?);
+        }
+        if let Some(rules) = &changes.rules {
+            statements.push(self.rules_statement(code, rules)?);
+        }
+        if let Some(destinations) = &changes.destinations {
+            statements.push(self.prepare("DELETE FROM destinations WHERE redirect = ?", &[code])?);
+            for destination in destinations {
+                statements.push(self.insert_destination(code, destination)?);
+            }
+        }
+        statements.push(self.prepare(
+            "UPDATE redirects SET updated_at = ? WHERE redirect = ?",
+            &[now, code],
+        )?);
+        self.db.batch(statements).await.map_err(store_error)?;
+        Ok(())
+    }
+
+    async fn touch_link(&self, code: &str, now: &str) -> StoreResult<()> {
+        self.execute(
+            "UPDATE redirects SET updated_at = ? WHERE redirect = ?",
+            &[now, code],
+        )
+        .await
+    }
+
+    async fn update_url(&self, code: &str, url: &str) -> StoreResult<()> {
+        self.execute(
+            "UPDATE redirects SET url = ? WHERE redirect = ?",
+            &[url, code],
+        )
+        .await
+    }
+
+    async fn update_comment(&self, code: &str, comment: &str) -> StoreResult<()> {
+        self.execute(
+            "UPDATE redirects SET comment = ? WHERE redirect = ?",
+            &[comment, code],
+        )
+        .await
+    }
+
+    async fn update_rules(&self, code: &str, rules: &Rules) -> StoreResult<()> {
+        self.rules_statement(code, rules)?
+            .run()
+            .await
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    async fn claim_visit(&self, code: &str) -> StoreResult<bool> {
+        // One statement, so concurrent visits can't both take the last use
+        let query = self.prepare(
+            "UPDATE redirects SET uses = uses + 1
+            WHERE redirect = ? AND (max_visits IS NULL OR uses < max_visits)
+            RETURNING uses",
+            &[code],
+        )?;
+        Ok(query
+            .first::<u64>(Some("uses"))
+            .await
+            .map_err(store_error)?
+            .is_some())
+    }
+
+    async fn set_destinations(
+        &self,
+        code: &str,
+        destinations: &[NewDestination],
+    ) -> StoreResult<()> {
+        // A batch runs as one transaction
+        let mut statements =
+            vec![self.prepare("DELETE FROM destinations WHERE redirect = ?", &[code])?];
+        for destination in destinations {
+            statements.push(self.insert_destination(code, destination)?);
+        }
+        self.db.batch(statements).await.map_err(store_error)?;
+        Ok(())
+    }
+
+    async fn remove_link(&self, code: &str) -> StoreResult<()> {
+        self.db
+            .batch(vec![
+                self.prepare("DELETE FROM destinations WHERE redirect = ?", &[code])?,
+                self.prepare("DELETE FROM redirects WHERE redirect = ?", &[code])?,
+            ])
+            .await
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    async fn claim_attempt(
+        &self,
+        code: &str,
+        ip: &str,
+        now: &str,
+        since: &str,
+        max_attempts: u64,
+    ) -> StoreResult<bool> {
+        // One statement, so concurrent guesses can't all get in under the limit
+        let query = self.prepare(
+            "INSERT INTO password_attempts (redirect, ip, count, window_start) VALUES (?1, ?2, 1, ?3)
+            ON CONFLICT (redirect, ip) DO UPDATE SET
+            count = CASE WHEN window_start < ?4 THEN 1 ELSE count + 1 END,
+            window_start = CASE WHEN window_start < ?4 THEN excluded.window_start ELSE window_start END
+            WHERE window_start < ?4 OR count < CAST(?5 AS INTEGER)
+            RETURNING count",
+            &[code, ip, now, since, &max_attempts.to_string()],
+        )?;
+        Ok(query
+            .first::<u64>(Some("count"))
+            .await
+            .map_err(store_error)?
+            .is_some())
+    }
+
+    async fn release_attempt(&self, code: &str, ip: &str) -> StoreResult<()> {
+        self.execute(
+            "UPDATE password_attempts SET count = count - 1
+            WHERE redirect = ? AND ip = ? AND count > 0",
+            &[code, ip],
+        )
+        .await
+    }
+
+    async fn log_visit(&self, visit: Visit) -> StoreResult<()> {
+        self.db
+            .prepare(
+                "INSERT INTO log (redirect, ip, url, timestamp, utm_template, destination,
+                country, region, city, asn, referrer, user_agent, accept_language, host,
+                browser, os, device, kind, visitor, day)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )
+            .bind(&[
+                visit.code.into(),
+                visit.ip.into(),
+                visit.url.into(),
+                visit.timestamp.into(),
+                nullable(visit.utm_template),
+                nullable(visit.destination.map(|d| d as f64)),
+                nullable(visit.location.country),
+                nullable(visit.location.region),
+                nullable(visit.location.city),
+                nullable(visit.location.asn.map(f64::from)),
+                nullable(visit.client.referrer),
+                nullable(visit.client.user_agent),
+                nullable(visit.client.accept_language),
+                nullable(visit.client.host),
+                nullable(visit.client.browser.map(|b| b.as_str())),
+                nullable(visit.client.os.map(|o| o.as_str())),
+                nullable(visit.client.device.map(|d| d.as_str())),
+                visit.kind.as_str().into(),
+                nullable(visit.visitor.as_ref().map(|v| v.hash.as_str())),
+                nullable(visit.visitor.as_ref().map(|v| v.day.as_str())),
+            ])
+            .map_err(store_error)?
+            .run()
+            .await
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    async fn get_logs(&self, code: &str) -> StoreResult<Vec<DatabaseLog>> {
+        let query = self.prepare(
+            "SELECT timestamp, ip, url, destination, country, region, city, asn,
+            referrer, user_agent, accept_language, host, browser, os, device, kind,
+            visitor FROM log WHERE redirect = ? ORDER BY timestamp, rowid",
+            &[code],
+        )?;
+        Ok(query
+            .all()
+            .await
+            .map_err(store_error)?
+            .results::<LogRow>()
+            .map_err(store_error)?
+            .into_iter()
+            .map(DatabaseLog::from)
+            .collect())
+    }
+
+    async fn get_template(&self, name: &str) -> StoreResult<Option<UtmTemplate>> {
+        let query = self.prepare(
+            "SELECT name, source, medium, campaign, term, content FROM utm_templates WHERE name = ?",
+            &[name],
+        )?;
+        Ok(query
+            .first::<TemplateRow>(None)
+            .await
+            .map_err(store_error)?
+            .map(UtmTemplate::from))
+    }
+
+    async fn list_templates(&self) -> StoreResult<Vec<UtmTemplate>> {
+        let query = self.prepare(
+            "SELECT name, source, medium, campaign, term, content FROM utm_templates ORDER BY name",
+            &[],
+        )?;
+        Ok(query
+            .all()
+            .await
+            .map_err(store_error)?
+            .results::<TemplateRow>()
+            .map_err(store_error)?
+            .into_iter()
+            .map(UtmTemplate::from)
+            .collect())
+    }
+
+    async fn save_template(&self, template: &UtmTemplate) -> StoreResult<()> {
+        let params = &template.params;
+        self.db
+            .prepare(
+                "INSERT INTO utm_templates (name, source, medium, campaign, term, content)
+                VALUES (?, ?, ?, ?, ?, ?)
+                ON CONFLICT (name) DO UPDATE SET source = excluded.source,
+                medium = excluded.medium, campaign = excluded.campaign,
+                term = excluded.term, content = excluded.content",
+            )
+            .bind(&[
+                template.name.as_str().into(),
+                nullable(params.source.as_deref()),
+                nullable(params.medium.as_deref()),
+                nullable(params.campaign.as_deref()),
+                nullable(params.term.as_deref()),
+                nullable(params.content.as_deref()),
+            ])
+            .map_err(store_error)?
+            .run()
+            .await
+            .map_err(store_error)?;
+        Ok(())
+    }
+
+    async fn remove_template(&self, name: &str) -> StoreResult<()> {
+        self.execute(
+            "UPDATE redirects SET utm_template = NULL WHERE utm_template = ?",
+            &[name],
+        )
+        .await?;
+        self.execute("DELETE FROM utm_templates WHERE name = ?", &[name])
+            .await
+    }
+
+    async fn template_visits(&self, include_bots: bool) -> StoreResult<Vec<TemplateVisits>> {
+        let query = self
+            .db
+            .prepare(
+                "SELECT utm_template AS template, COUNT(*) AS visits FROM log
+                WHERE utm_template IS NOT NULL AND (?1 IS NULL OR kind = ?1)
+                GROUP BY utm_template ORDER BY utm_template",
+            )
+            .bind(&[nullable(
+                (!include_bots).then_some(ClickKind::Human.as_str()),
+            )])
+            .map_err(store_error)?;
+        query
+            .all()
+            .await
+            .map_err(store_error)?
+            .results::<TemplateVisits>()
+            .map_err(store_error)
+    }
+
+    async fn country_visits(
+        &self,
+        code: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<CountryVisits>> {
+        Ok(self
+            .db
+            .prepare(
+                "SELECT country, COUNT(*) AS visits FROM log
+                WHERE (?1 IS NULL OR redirect = ?1) AND (?2 IS NULL OR kind = ?2)
+                GROUP BY country ORDER BY country",
+            )
+            .bind(&[
+                nullable(code),
+                nullable((!include_bots).then_some(ClickKind::Human.as_str())),
+            ])
+            .map_err(store_error)?
+            .all()
+            .await
+            .map_err(store_error)?
+            .results::<CountryVisits>()
+            .map_err(store_error)?)
+    }
+
+    async fn unique_visitors(
+        &self,
+        code: Option<&str>,
+        since: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<LinkVisitors>> {
+        Ok(self
+            .db
+            .prepare(
+                "SELECT redirect AS code, COUNT(DISTINCT visitor) AS visitors FROM log
+                WHERE visitor IS NOT NULL AND (?1 IS NULL OR redirect = ?1)
+                AND (?2 IS NULL OR day >= ?2) AND (?3 IS NULL OR kind = ?3)
+                GROUP BY redirect ORDER BY redirect",
+            )
+            .bind(&[
+                nullable(code),
+                nullable(since),
+                nullable((!include_bots).then_some(ClickKind::Human.as_str())),
+            ])
+            .map_err(store_error)?
+            .all()
+            .await
+            .map_err(store_error)?
+            .results::<LinkVisitors>()
+            .map_err(store_error)?)
+    }
+
+    async fn count_visits(
+        &self,
+        code: Option<&str>,
+        ranges: &[Range],
+        include_bots: bool,
+    ) -> StoreResult<Vec<usize>> {
+        let rows = self
+            .db
+            .prepare(
+                "SELECT (SELECT COUNT(*) FROM log l
+                WHERE l.timestamp >= json_extract(r.value, '$.start')
+                AND l.timestamp < json_extract(r.value, '$.end')
+                AND (?2 IS NULL OR l.redirect = ?2) AND (?3 IS NULL OR l.kind = ?3)) AS visits
+                FROM json_each(?1) r ORDER BY r.key",
+            )
+            .bind(&[
+                series::encode_ranges(ranges).into(),
+                nullable(code),
+                nullable((!include_bots).then_some(ClickKind::Human.as_str())),
+            ])
+            .map_err(store_error)?
+            .all()
+            .await
+            .map_err(store_error)?
+            .results::<RangeVisits>()
+            .map_err(store_error)?;
+        Ok(rows.into_iter().map(|r| r.visits).collect())
+    }
+
+    async fn day_salt(&self, day: &str, new_salt: &str) -> StoreResult<String> {
+        // Whoever saved the day's salt already deleted the earlier ones, so reading is enough
+        let saved = self
+            .prepare("SELECT salt FROM visitor_salts WHERE day = ?", &[day])?
+            .first::<String>(Some("salt"))
+            .await
+            .map_err(store_error)?;
+        if let Some(salt) = saved {
+            return Ok(salt);
+        }
+        // A batch runs as one transaction
+        let results = self
+            .db
+            .batch(vec![
+                self.prepare("DELETE FROM visitor_salts WHERE day < ?", &[day])?,
+                self.prepare(
+                    "INSERT OR IGNORE INTO visitor_salts (day, salt) VALUES (?, ?)",
+                    &[day, new_salt],
+                )?,
+                self.prepare("SELECT salt FROM visitor_salts WHERE day = ?", &[day])?,
+            ])
+            .await
+            .map_err(store_error)?;
+        let [_, _, salt] = &results[..] else {
+            return Err(StoreError::Backend("D1 batch came back short".to_string()));
+        };
+        salt.results::<Salt>()
+            .map_err(store_error)?
+            .into_iter()
+            .next()
+            .map(|s| s.salt)
+            .ok_or_else(|| StoreError::Backend(format!("no visitor salt for {day}")))
+    }
+
+    async fn insert_token(&self, token: &str, expiration: &str) -> StoreResult<()> {
+        self.execute(
+            "INSERT INTO tokens (token, expiration) VALUES (?, ?)",
+            &[token, expiration],
+        )
+        .await
+    }
+
+    async fn token_expiration(&self, token: &str) -> StoreResult<Option<String>> {
+        let query = self.prepare("SELECT expiration FROM tokens WHERE token = ?", &[token])?;
+        query
+            .first::<String>(Some("expiration"))
+            .await
+            .map_err(store_error)
+    }
+
+    async fn remove_token(&self, token: &str) -> StoreResult<()> {
+        self.execute("DELETE FROM tokens WHERE token = ?", &[token])
+            .await
+    }
+
+    async fn remove_expired_tokens(&self, now: &str) -> StoreResult<()> {
+        self.execute("DELETE FROM tokens WHERE expiration < ?", &[now])
+            .await
+    }
+}
@@ -0,0 +1,15 @@
+// Jackson Coxson
+// Platform specific storage backends, the LinkStore trait itself lives in riplakish-core
+
+pub use riplakish_core::store::*;
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod bench;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod cache;
+#[cfg(target_arch = "wasm32")]
+pub mod d1;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod pool;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod sqlite;
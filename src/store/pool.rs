@@ -0,0 +1,121 @@
+// Jackson Coxson
+// A few long-lived SQLite connections, each on its own thread and each keeping its prepared statements.
+// Opening a connection for every request was slow and had concurrent writers fighting over the file.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    panic::AssertUnwindSafe,
+    sync::{mpsc, Arc, Mutex},
+};
+
+use log::error;
+use sqlite::{Connection, OpenFlags, Statement};
+
+/// How long a connection waits on a locked database before giving up
+const BUSY_TIMEOUT_MS: usize = 5000;
+
+type Job = Box<dyn for<'c> FnOnce(&mut Handle<'c>) + Send>;
+
+/// What a job gets to work with: the connection and its statement cache
+pub struct Handle<'c> {
+    pub connection: &'c Connection,
+    statements: HashMap<&'static str, Statement<'c>>,
+}
+
+impl<'c> Handle<'c> {
+    /// The prepared statement for a query, reset and ready to be bound
+    pub fn prepare(&mut self, query: &'static str) -> sqlite::Result<&mut Statement<'c>> {
+        let connection = self.connection;
+        let statement = match self.statements.entry(query) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => e.insert(connection.prepare(query)?),
+        };
+        statement.reset()?;
+        Ok(statement)
+    }
+
+    /// Unfinished statements keep a read transaction open, so reset everything between jobs
+    fn release(&mut self) {
+        for statement in self.statements.values_mut() {
+            let _ = statement.reset();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Pool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl Pool {
+    pub fn new(filename: &str, size: usize) -> sqlite::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..size.max(1) {
+            let connection = open(filename)?;
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("sqlite-{i}"))
+                .spawn(move || {
+                    let mut handle = Handle {
+                        connection: &connection,
+                        statements: HashMap::new(),
+                    };
+                    loop {
+                        // The lock is only held while waiting, not while working
+                        let job = match receiver.lock() {
+                            Ok(r) => r.recv(),
+                            Err(_) => return,
+                        };
+                        let job = match job {
+                            Ok(j) => j,
+                            // Every Pool has been dropped
+                            Err(_) => return,
+                        };
+                        if std::panic::catch_unwind(AssertUnwindSafe(|| job(&mut handle))).is_err()
+                        {
+                            error!("Database job panicked");
+                        }
+                        handle.release();
+                    }
+                })
+                .expect("Failed to start a database thread");
+        }
+
+        Ok(Self { jobs })
+    }
+
+    /// Runs database work on one of the pool's connections
+    pub async fn run<T, F>(&self, f: F) -> sqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: for<'c> FnOnce(&mut Handle<'c>) -> sqlite::Result<T> + Send + 'static,
+    {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let job: Job = Box::new(move |handle| {
+            let _ = sender.send(f(handle));
+        });
+        self.jobs.send(job).map_err(|_| closed())?;
+        receiver.await.map_err(|_| closed())?
+    }
+}
+
+/// Opens a connection in WAL mode so readers don't wait on the visit log being written
+fn open(filename: &str) -> sqlite::Result<Connection> {
+    let flags = OpenFlags::new()
+        .with_create()
+        .with_read_write()
+        .with_no_mutex();
+    let mut connection = Connection::open_with_flags(filename, flags)?;
+    connection.set_busy_timeout(BUSY_TIMEOUT_MS)?;
+    connection.execute("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")?;
+    Ok(connection)
+}
+
+fn closed() -> sqlite::Error {
+    sqlite::Error {
+        code: None,
+        message: Some("The database pool has shut down".to_string()),
+    }
+}
@@ -0,0 +1,996 @@
+// Jackson Coxson
+
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use log::{error, info};
+use riplakish_core::{
+    agents::{ClickKind, ClientInfo},
+    geo::{CountryVisits, Location},
+    links::NewDestination,
+    migrations::{self, Migration},
+    series::{self, Range},
+    targeting,
+    utm::{UtmParams, UtmTemplate},
+    visitors::LinkVisitors,
+};
+use sqlite::{Connection, State, Statement};
+
+use super::{
+    pool::{Handle, Pool},
+    DatabaseLog, DatabaseStats, Destination, DestinationStats, LinkChanges, LinkStore, NewLinkRow,
+    Rules, StoreError, StoreResult, Target, TemplateVisits, Visit,
+};
+
+#[derive(Clone)]
+pub struct SqliteStore {
+    pool: Pool,
+}
+
+fn store_error(value: sqlite::Error) -> StoreError {
+    let unique_violation = value
+        .message
+        .as_deref()
+        .map(|m| m.contains("UNIQUE constraint failed"))
+        .unwrap_or(false);
+    if unique_violation {
+        StoreError::Conflict
+    } else {
+        StoreError::Backend(format!("{:?}", value))
+    }
+}
+
+impl SqliteStore {
+    pub fn new(filename: String, pool_size: usize) -> Self {
+        let connection = sqlite::open(&filename).expect("Failed to read to database");
+        if let Err(e) = migrate(&connection, false) {
+            panic!("Failed to migrate the database: {:?}", e);
+        }
+
+        let pool = Pool::new(&filename, pool_size).expect("Failed to open the database pool");
+        Self { pool }
+    }
+
+    /// Runs database work on the pool, off of the async runtime
+    async fn run<T, F>(&self, f: F) -> StoreResult<T>
+    where
+        T: Send + 'static,
+        F: for<'c> FnOnce(&mut Handle<'c>) -> sqlite::Result<T> + Send + 'static,
+    {
+        self.pool.run(f).await.map_err(store_error)
+    }
+}
+
+/// The version the database is at, 0 if it has never been migrated
+fn schema_version(connection: &Connection) -> sqlite::Result<u32> {
+    let mut statement = connection
+        .prepare("SELECT name FROM sqlite_master WHERE type='table' AND name='schema_version';")?;
+    if let State::Done = statement.next()? {
+        return Ok(0);
+    }
+    let mut statement = connection.prepare(migrations::CURRENT_VERSION)?;
+    statement.next()?;
+    Ok(statement.read::<i64, _>(0)? as u32)
+}
+
+/// Applies every pending migration, each in its own transaction.
+/// With dry_run the SQL is printed instead. Returns the migrations that were pending.
+pub fn migrate(connection: &Connection, dry_run: bool) -> sqlite::Result<&'static [Migration]> {
+    let pending = migrations::pending(schema_version(connection)?);
+    for migration in pending {
+        if dry_run {
+            println!("-- {}\n{}", migration.file_name(), migration.script());
+            continue;
+        }
+        info!("Applying migration {}", migration.file_name());
+        if let Err(e) = connection.execute(format!("BEGIN;\n{}COMMIT;", migration.script())) {
+            error!("Migration {} failed: {:?}", migration.file_name(), e);
+            let _ = connection.execute("ROLLBACK;");
+            return Err(e);
+        }
+    }
+    Ok(pending)
+}
+
+/// Reads the rule columns, starting at the given column
+fn read_rules(statement: &Statement, start: usize) -> sqlite::Result<Rules> {
+    Ok(Rules {
+        expires_at: statement.read::<Option<String>, _>(start)?,
+        max_visits: statement
+            .read::<Option<i64>, _>(start + 1)?
+            .map(|m| m as u64),
+        not_before: statement.read::<Option<String>, _>(start + 2)?,
+        password_hash: statement.read::<Option<String>, _>(start + 3)?,
+        redirect_status: statement
+            .read::<Option<i64>, _>(start + 4)?
+            .map(|s| s as u16),
+        forward_query: statement.read::<i64, _>(start + 5)? != 0,
+        forward_path: statement.read::<i64, _>(start + 6)? != 0,
+        utm_template: statement.read::<Option<String>, _>(start + 7)?,
+        sticky: statement.read::<i64, _>(start + 8)? != 0,
+        targeting: targeting::decode(statement.read::<Option<String>, _>(start + 9)?.as_deref()),
+    })
+}
+
+/// Binds the rule columns in the order read_rules reads them, starting at the given parameter
+fn bind_rules(statement: &mut Statement, start: usize, rules: &Rules) -> sqlite::Result<()> {
+    statement.bind((start, rules.expires_at.as_deref()))?;
+    statement.bind((start + 1, rules.max_visits.map(|m| m as i64)))?;
+    statement.bind((start + 2, rules.not_before.as_deref()))?;
+    statement.bind((start + 3, rules.password_hash.as_deref()))?;
+    statement.bind((start + 4, rules.redirect_status.map(i64::from)))?;
+    statement.bind((start + 5, rules.forward_query as i64))?;
+    statement.bind((start + 6, rules.forward_path as i64))?;
+    statement.bind((start + 7, rules.utm_template.as_deref()))?;
+    statement.bind((start + 8, rules.sticky as i64))?;
+    statement.bind((start + 9, targeting::encode(&rules.targeting).as_deref()))?;
+    Ok(())
+}
+
+/// Reads a row of SELECT url, redirect, log_count, bot_count, preview_count, unique_count,
+/// comment, uses, the rule columns, created_at, updated_at
+fn read_stats(statement: &Statement) -> sqlite::Result<DatabaseStats> {
+    Ok(DatabaseStats {
+        url: statement.read::<String, _>(0)?,
+        code: statement.read::<String, _>(1)?,
+        visits: statement.read::<i64, _>(2)? as usize,
+        bot_visits: statement.read::<i64, _>(3)? as usize,
+        preview_visits: statement.read::<i64, _>(4)? as usize,
+        unique_visitors: statement.read::<i64, _>(5)? as usize,
+        comment: statement.read::<Option<String>, _>(6)?.unwrap_or_default(),
+        created_at: statement.read::<Option<String>, _>(18)?,
+        updated_at: statement.read::<Option<String>, _>(19)?,
+        uses: statement.read::<i64, _>(7)? as u64,
+        rules: read_rules(statement, 8)?,
+        destinations: Vec::new(),
+    })
+}
+
+/// Reads a row of SELECT id, url, weight
+fn read_destination(statement: &Statement) -> sqlite::Result<Destination> {
+    Ok(Destination {
+        id: statement.read::<i64, _>(0)? as u64,
+        url: statement.read::<String, _>(1)?,
+        weight: statement.read::<i64, _>(2)? as u32,
+    })
+}
+
+fn destinations(handle: &mut Handle, code: &str) -> sqlite::Result<Vec<Destination>> {
+    let statement = handle
+        .prepare("SELECT id, url, weight FROM destinations WHERE redirect = ? ORDER BY id;")?;
+    statement.bind((1, code))?;
+    let mut res = Vec::new();
+    while let State::Row = statement.next()? {
+        res.push(read_destination(statement)?);
+    }
+    Ok(res)
+}
+
+fn destination_stats(handle: &mut Handle, code: &str) -> sqlite::Result<Vec<DestinationStats>> {
+    let statement = handle.prepare(
+        "SELECT d.id, d.url, d.weight, COUNT(l.id) FROM destinations d
+            LEFT JOIN log l ON l.destination = d.id AND l.kind = 'human'
+            WHERE d.redirect = ?
+            GROUP BY d.id ORDER BY d.id;",
+    )?;
+    statement.bind((1, code))?;
+    let mut res = Vec::new();
+    while let State::Row = statement.next()? {
+        res.push(DestinationStats {
+            destination: read_destination(statement)?,
+            visits: statement.read::<i64, _>(3)? as usize,
+        });
+    }
+    Ok(res)
+}
+
+/// Reads a row of SELECT name, source, medium, campaign, term, content
+fn read_template(statement: &Statement) -> sqlite::Result<UtmTemplate> {
+    let param = |i| statement.read::<Option<String>, _>(i);
+    Ok(UtmTemplate {
+        name: statement.read::<String, _>(0)?,
+        params: UtmParams {
+            source: param(1)?,
+            medium: param(2)?,
+            campaign: param(3)?,
+            term: param(4)?,
+            content: param(5)?,
+        },
+    })
+}
+
+/// A column holding one of the agents enums, unknown names are read as None
+fn read_name<T: FromStr>(statement: &Statement, index: usize) -> sqlite::Result<Option<T>> {
+    Ok(statement
+        .read::<Option<String>, _>(index)?
+        .and_then(|name| name.parse().ok()))
+}
+
+fn insert_visit(handle: &mut Handle, visit: &Visit) -> sqlite::Result<()> {
+    let statement = handle.prepare(
+        "INSERT INTO log (redirect, ip, url, timestamp, utm_template, destination,
+            country, region, city, asn, referrer, user_agent, accept_language, host,
+            browser, os, device, kind, visitor, day)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+    )?;
+    statement.bind(&[&*visit.code, &visit.ip, &visit.url, &visit.timestamp][..])?;
+    statement.bind((5, visit.utm_template.as_deref()))?;
+    statement.bind((6, visit.destination.map(|d| d as i64)))?;
+    let location = &visit.location;
+    statement.bind((7, location.country.as_deref()))?;
+    statement.bind((8, location.region.as_deref()))?;
+    statement.bind((9, location.city.as_deref()))?;
+    statement.bind((10, location.asn.map(i64::from)))?;
+    let client = &visit.client;
+    statement.bind((11, client.referrer.as_deref()))?;
+    statement.bind((12, client.user_agent.as_deref()))?;
+    statement.bind((13, client.accept_language.as_deref()))?;
+    statement.bind((14, client.host.as_deref()))?;
+    statement.bind((15, client.browser.map(|b| b.as_str())))?;
+    statement.bind((16, client.os.map(|o| o.as_str())))?;
+    statement.bind((17, client.device.map(|d| d.as_str())))?;
+    statement.bind((18, visit.kind.as_str()))?;
+    let visitor = visit.visitor.as_ref();
+    statement.bind((19, visitor.map(|v| v.hash.as_str())))?;
+    statement.bind((20, visitor.map(|v| v.day.as_str())))?;
+    while let State::Row = statement.next()? {}
+    Ok(())
+}
+
+/// Adds destinations to a link, if the link exists
+fn insert_destinations(
+    handle: &mut Handle,
+    code: &str,
+    destinations: &[NewDestination],
+) -> sqlite::Result<()> {
+    for destination in destinations {
+        let statement = handle.prepare(
+            "INSERT INTO destinations (redirect, url, weight) SELECT ?1, ?2, ?3
+                WHERE EXISTS (SELECT 1 FROM redirects WHERE redirect = ?1);",
+        )?;
+        statement.bind((1, code))?;
+        statement.bind((2, destination.url.as_str()))?;
+        statement.bind((3, i64::from(destination.weight)))?;
+        while let State::Row = statement.next()? {}
+    }
+    Ok(())
+}
+
+/// Runs a statement that doesn't return anything
+fn execute(handle: &mut Handle, query: &'static str, params: &[&str]) -> sqlite::Result<()> {
+    let statement = handle.prepare(query)?;
+    for (i, param) in params.iter().enumerate() {
+        statement.bind((i + 1, *param))?;
+    }
+    while let State::Row = statement.next()? {}
+    Ok(())
+}
+
+/// Replaces every rule on a link
+fn write_rules(handle: &mut Handle, code: &str, rules: &Rules) -> sqlite::Result<()> {
+    let statement = handle.prepare(
+        "UPDATE redirects SET expires_at = ?, max_visits = ?, not_before = ?, password_hash = ?,
+            redirect_status = ?, forward_query = ?, forward_path = ?, utm_template = ?,
+            sticky = ?, targeting = ? WHERE redirect = ?;",
+    )?;
+    bind_rules(statement, 1, rules)?;
+    statement.bind((11, code))?;
+    while let State::Row = statement.next()? {}
+    Ok(())
+}
+
+/// Runs f in a transaction, rolling back if it fails
+fn transaction<T>(
+    handle: &mut Handle,
+    f: impl FnOnce(&mut Handle) -> sqlite::Result<T>,
+) -> sqlite::Result<T> {
+    handle.connection.execute("BEGIN;")?;
+    match f(handle) {
+        Ok(res) => {
+            handle.connection.execute("COMMIT;")?;
+            Ok(res)
+        }
+        Err(e) => {
+            let _ = handle.connection.execute("ROLLBACK;");
+            Err(e)
+        }
+    }
+}
+
+#[async_trait]
+impl LinkStore for SqliteStore {
+    async fn get_target(&self, code: &str) -> StoreResult<Option<Target>> {
+        let code = code.to_string();
+        self.run(move |handle| {
+            let statement = handle.prepare(
+                "SELECT url, expires_at, max_visits, not_before, password_hash, redirect_status,
+                    forward_query, forward_path, utm_template, sticky, targeting
+                    FROM redirects WHERE redirect = ?",
+            )?;
+            statement.bind((1, code.as_str()))?;
+            if let State::Row = statement.next()? {
+                let (url, rules) = (statement.read::<String, _>(0)?, read_rules(statement, 1)?);
+                Ok(Some(Target {
+                    url,
+                    rules,
+                    destinations: destinations(handle, &code)?,
+                }))
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+    }
+
+    async fn get_link(&self, code: &str) -> StoreResult<Option<DatabaseStats>> {
+        let code = code.to_string();
+        self.run(move |handle| {
+            let query = "SELECT r.url, r.redirect,
+                                COUNT(CASE WHEN l.kind = 'human' THEN 1 END) AS log_count,
+                                COUNT(CASE WHEN l.kind = 'bot' THEN 1 END) AS bot_count,
+                                COUNT(CASE WHEN l.kind = 'preview' THEN 1 END) AS preview_count,
+                                COUNT(DISTINCT CASE WHEN l.kind = 'human' THEN l.visitor END)
+                                    AS unique_count,
+                                r.comment, r.uses,
+                                r.expires_at, r.max_visits, r.not_before, r.password_hash, r.redirect_status,
+                                r.forward_query, r.forward_path, r.utm_template, r.sticky,
+                                r.targeting, r.created_at, r.updated_at
+                            FROM redirects r
+                            LEFT JOIN log l ON r.redirect = l.redirect
+                            WHERE r.redirect = ?
+                            GROUP BY r.url, r.redirect;";
+            let statement = handle.prepare(query)?;
+            statement.bind((1, code.as_str()))?;
+            if let State::Row = statement.next()? {
+                let mut link = read_stats(statement)?;
+                link.destinations = destination_stats(handle, &code)?;
+                Ok(Some(link))
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+    }
+
+    async fn list_links(&self) -> StoreResult<Vec<DatabaseStats>> {
+        self.run(|handle| {
+            let query = "SELECT r.url, r.redirect,
+                                COUNT(CASE WHEN l.kind = 'human' THEN 1 END) AS log_count,
+                                COUNT(CASE WHEN l.kind = 'bot' THEN 1 END) AS bot_count,
+                                COUNT(CASE WHEN l.kind = 'preview' THEN 1 END) AS preview_count,
+                                COUNT(DISTINCT CASE WHEN l.kind = 'human' THEN l.visitor END)
+                                    AS unique_count,
+                                r.comment, r.uses,
+                                r.expires_at, r.max_visits, r.not_before, r.password_hash, r.redirect_status,
+                                r.forward_query, r.forward_path, r.utm_template, r.sticky,
+                                r.targeting, r.created_at, r.updated_at
+                            FROM redirects r
+                            LEFT JOIN log l ON r.redirect = l.redirect
+                            GROUP BY r.url, r.redirect;";
+            let statement = handle.prepare(query)?;
+            let mut res = Vec::new();
+            while let State::Row = statement.next()? {
+                res.push(read_stats(statement)?);
+            }
+            for link in &mut res {
+                link.destinations = destination_stats(handle, &link.code)?;
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn count_links(&self) -> StoreResult<usize> {
+        self.run(|handle| {
+            let statement = handle.prepare("SELECT COUNT(*) FROM redirects")?;
+            statement.next()?;
+            Ok(statement.read::<i64, _>(0)? as usize)
+        })
+        .await
+    }
+
+    async fn insert_link_row(&self, code: &str, link: &NewLinkRow, now: &str) -> StoreResult<()> {
+        let (code, link, now) = (code.to_string(), link.clone(), now.to_string());
+        self.run(move |handle| {
+            transaction(handle, |handle| {
+                let statement = handle.prepare(
+                    "INSERT INTO redirects (url, redirect, comment, created_at, updated_at,
+                        expires_at, max_visits, not_before, password_hash, redirect_status,
+                        forward_query, forward_path, utm_template, sticky, targeting)
+                        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+                )?;
+                statement.bind(&[&*link.url, &*code][..])?;
+                statement.bind((3, link.comment.as_deref()))?;
+                statement.bind((4, now.as_str()))?;
+                statement.bind((5, now.as_str()))?;
+                bind_rules(statement, 6, &link.rules)?;
+                while let State::Row = statement.next()? {}
+                insert_destinations(handle, &code, &link.destinations)
+            })
+        })
+        .await
+    }
+
+    async fn update_link_row(
+        &self,
+        code: &str,
+        changes: &LinkChanges,
+        now: &str,
+    ) -> StoreResult<()> {
+        let (code, changes, now) = (code.to_string(), changes.clone(), now.to_string());
+        self.run(move |handle| {
+            transaction(handle, |handle| {
+                if let Some(url) = &changes.url {
+                    execute(
+                        handle,
+                        "UPDATE redirects SET url = ? WHERE redirect = ?;",
+                        &[url, &code],
+                    )?;
+                }
+                if let Some(comment) = &changes.comment {
+                    execute(
+                        handle,
+                        "UPDATE redirects SET comment = ? WHERE redirect = ?;",
+                        &[comment, &code],
+                    )?;
+                }
+                if let Some(rules) = &changes.rules {
+                    write_rules(handle, &code, rules)?;
+                }
+                if let Some(destinations) = &changes.destinations {
+                    execute(
+                        handle,
+                        "DELETE FROM destinations WHERE redirect = ?;",
+                        &[&code],
+                    )?;
+                    insert_destinations(handle, &code, destinations)?;
+                }
+                execute(
+                    handle,
+                    "UPDATE redirects SET updated_at = ? WHERE redirect = ?;",
+                    &[&now, &code],
+                )
+            })
+        })
+        .await
+    }
+
+    async fn touch_link(&self, code: &str, now: &str) -> StoreResult<()> {
+        let (code, now) = (code.to_string(), now.to_string());
+        self.run(move |handle| {
+            execute(
+                handle,
+                "UPDATE redirects SET updated_at = ? WHERE redirect = ?;",
+                &[&now, &code],
+            )
+        })
+        .await
+    }
+
+    async fn update_url(&self, code: &str, url: &str) -> StoreResult<()> {
+        let (code, url) = (code.to_string(), url.to_string());
+        self.run(move |handle| {
+            execute(
+                handle,
+                "UPDATE redirects SET url = ? WHERE redirect = ?;",
+                &[&url, &code],
+            )
+        })
+        .await
+    }
+
+    async fn update_comment(&self, code: &str, comment: &str) -> StoreResult<()> {
+        let (code, comment) = (code.to_string(), comment.to_string());
+        self.run(move |handle| {
+            execute(
+                handle,
+                "UPDATE redirects SET comment = ? WHERE redirect = ?;",
+                &[&comment, &code],
+            )
+        })
+        .await
+    }
+
+    async fn update_rules(&self, code: &str, rules: &Rules) -> StoreResult<()> {
+        let (code, rules) = (code.to_string(), rules.clone());
+        self.run(move |handle| write_rules(handle, &code, &rules))
+            .await
+    }
+
+    async fn claim_visit(&self, code: &str) -> StoreResult<bool> {
+        let code = code.to_string();
+        self.run(move |handle| {
+            // One statement, so concurrent visits can't both take the last use
+            let statement = handle.prepare(
+                "UPDATE redirects SET uses = uses + 1
+                    WHERE redirect = ? AND (max_visits IS NULL OR uses < max_visits)
+                    RETURNING uses;",
+            )?;
+            statement.bind((1, code.as_str()))?;
+            let claimed = matches!(statement.next()?, State::Row);
+            // The update isn't finished until the statement is
+            while let State::Row = statement.next()? {}
+            Ok(claimed)
+        })
+        .await
+    }
+
+    async fn set_destinations(
+        &self,
+        code: &str,
+        destinations: &[NewDestination],
+    ) -> StoreResult<()> {
+        let (code, destinations) = (code.to_string(), destinations.to_vec());
+        self.run(move |handle| {
+            transaction(handle, |handle| {
+                execute(
+                    handle,
+                    "DELETE FROM destinations WHERE redirect = ?;",
+                    &[&code],
+                )?;
+                insert_destinations(handle, &code, &destinations)
+            })
+        })
+        .await
+    }
+
+    async fn remove_link(&self, code: &str) -> StoreResult<()> {
+        let code = code.to_string();
+        self.run(move |handle| {
+            transaction(handle, |handle| {
+                execute(
+                    handle,
+                    "DELETE FROM destinations WHERE redirect = ?;",
+                    &[&code],
+                )?;
+                execute(
+                    handle,
+                    "DELETE FROM redirects WHERE redirect = ?;",
+                    &[&code],
+                )
+            })
+        })
+        .await
+    }
+
+    async fn log_visit(&self, visit: Visit) -> StoreResult<()> {
+        self.run(move |handle| insert_visit(handle, &visit)).await
+    }
+
+    async fn log_visits(&self, visits: Vec<Visit>) -> StoreResult<()> {
+        self.run(move |handle| {
+            transaction(handle, |handle| {
+                visits
+                    .iter()
+                    .try_for_each(|visit| insert_visit(handle, visit))
+            })
+        })
+        .await
+    }
+
+    async fn claim_attempt(
+        &self,
+        code: &str,
+        ip: &str,
+        now: &str,
+        since: &str,
+        max_attempts: u64,
+    ) -> StoreResult<bool> {
+        let max_attempts = max_attempts.to_string();
+        let params = [code, ip, now, since, &max_attempts].map(str::to_string);
+        self.run(move |handle| {
+            // One statement, so concurrent guesses can't all get in under the limit
+            let statement = handle.prepare(
+                "INSERT INTO password_attempts (redirect, ip, count, window_start) VALUES (?1, ?2, 1, ?3)
+                    ON CONFLICT (redirect, ip) DO UPDATE SET
+                    count = CASE WHEN window_start < ?4 THEN 1 ELSE count + 1 END,
+                    window_start = CASE WHEN window_start < ?4 THEN excluded.window_start ELSE window_start END
+                    WHERE window_start < ?4 OR count < CAST(?5 AS INTEGER)
+                    RETURNING count;",
+            )?;
+            statement.bind(&params.each_ref().map(String::as_str)[..])?;
+            let claimed = matches!(statement.next()?, State::Row);
+            while let State::Row = statement.next()? {}
+            Ok(claimed)
+        })
+        .await
+    }
+
+    async fn release_attempt(&self, code: &str, ip: &str) -> StoreResult<()> {
+        let (code, ip) = (code.to_string(), ip.to_string());
+        self.run(move |handle| {
+            execute(
+                handle,
+                "UPDATE password_attempts SET count = count - 1
+                    WHERE redirect = ? AND ip = ? AND count > 0;",
+                &[&code, &ip],
+            )
+        })
+        .await
+    }
+
+    async fn get_logs(&self, code: &str) -> StoreResult<Vec<DatabaseLog>> {
+        let code = code.to_string();
+        self.run(move |handle| {
+            let statement = handle.prepare(
+                "SELECT timestamp, ip, url, destination, country, region, city, asn,
+                    referrer, user_agent, accept_language, host, browser, os, device, kind,
+                    visitor FROM log WHERE redirect = ? ORDER BY timestamp, rowid",
+            )?;
+            statement.bind((1, code.as_str()))?;
+            let mut res = Vec::new();
+            while let State::Row = statement.next()? {
+                res.push(DatabaseLog {
+                    timestamp: statement.read::<String, _>(0)?,
+                    ip: statement.read::<String, _>(1)?,
+                    url: statement.read::<String, _>(2)?,
+                    destination: statement.read::<Option<i64>, _>(3)?.map(|d| d as u64),
+                    location: Location {
+                        country: statement.read::<Option<String>, _>(4)?,
+                        region: statement.read::<Option<String>, _>(5)?,
+                        city: statement.read::<Option<String>, _>(6)?,
+                        asn: statement.read::<Option<i64>, _>(7)?.map(|a| a as u32),
+                    },
+                    client: ClientInfo {
+                        referrer: statement.read::<Option<String>, _>(8)?,
+                        user_agent: statement.read::<Option<String>, _>(9)?,
+                        accept_language: statement.read::<Option<String>, _>(10)?,
+                        host: statement.read::<Option<String>, _>(11)?,
+                        browser: read_name(statement, 12)?,
+                        os: read_name(statement, 13)?,
+                        device: read_name(statement, 14)?,
+                    },
+                    kind: read_name(statement, 15)?.unwrap_or(ClickKind::Human),
+                    visitor: statement.read::<Option<String>, _>(16)?,
+                });
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn get_template(&self, name: &str) -> StoreResult<Option<UtmTemplate>> {
+        let name = name.to_string();
+        self.run(move |handle| {
+            let statement = handle.prepare(
+                "SELECT name, source, medium, campaign, term, content
+                    FROM utm_templates WHERE name = ?;",
+            )?;
+            statement.bind((1, name.as_str()))?;
+            if let State::Row = statement.next()? {
+                Ok(Some(read_template(statement)?))
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+    }
+
+    async fn list_templates(&self) -> StoreResult<Vec<UtmTemplate>> {
+        self.run(move |handle| {
+            let statement = handle.prepare(
+                "SELECT name, source, medium, campaign, term, content
+                    FROM utm_templates ORDER BY name;",
+            )?;
+            let mut res = Vec::new();
+            while let State::Row = statement.next()? {
+                res.push(read_template(statement)?);
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn save_template(&self, template: &UtmTemplate) -> StoreResult<()> {
+        let template = template.clone();
+        self.run(move |handle| {
+            let statement = handle.prepare(
+                "INSERT INTO utm_templates (name, source, medium, campaign, term, content)
+                    VALUES (?, ?, ?, ?, ?, ?)
+                    ON CONFLICT (name) DO UPDATE SET source = excluded.source,
+                    medium = excluded.medium, campaign = excluded.campaign,
+                    term = excluded.term, content = excluded.content;",
+            )?;
+            let params = &template.params;
+            statement.bind((1, template.name.as_str()))?;
+            statement.bind((2, params.source.as_deref()))?;
+            statement.bind((3, params.medium.as_deref()))?;
+            statement.bind((4, params.campaign.as_deref()))?;
+            statement.bind((5, params.term.as_deref()))?;
+            statement.bind((6, params.content.as_deref()))?;
+            while let State::Row = statement.next()? {}
+            Ok(())
+        })
+        .await
+    }
+
+    async fn remove_template(&self, name: &str) -> StoreResult<()> {
+        let name = name.to_string();
+        self.run(move |handle| {
+            transaction(handle, |handle| {
+                execute(
+                    handle,
+                    "UPDATE redirects SET utm_template = NULL WHERE utm_template = ?;",
+                    &[&name],
+                )?;
+                execute(
+                    handle,
+                    "DELETE FROM utm_templates WHERE name = ?;",
+                    &[&name],
+                )
+            })
+        })
+        .await
+    }
+
+    async fn template_visits(&self, include_bots: bool) -> StoreResult<Vec<TemplateVisits>> {
+        self.run(move |handle| {
+            let statement = handle.prepare(
+                "SELECT utm_template, COUNT(*) FROM log
+                    WHERE utm_template IS NOT NULL AND (?1 IS NULL OR kind = ?1)
+                    GROUP BY utm_template ORDER BY utm_template;",
+            )?;
+            statement.bind((1, (!include_bots).then_some(ClickKind::Human.as_str())))?;
+            let mut res = Vec::new();
+            while let State::Row = statement.next()? {
+                res.push(TemplateVisits {
+                    template: statement.read::<String, _>(0)?,
+                    visits: statement.read::<i64, _>(1)? as usize,
+                });
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn country_visits(
+        &self,
+        code: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<CountryVisits>> {
+        let code = code.map(str::to_string);
+        self.run(move |handle| {
+            let statement = handle.prepare(
+                "SELECT country, COUNT(*) FROM log
+                    WHERE (?1 IS NULL OR redirect = ?1) AND (?2 IS NULL OR kind = ?2)
+                    GROUP BY country ORDER BY country;",
+            )?;
+            statement.bind((1, code.as_deref()))?;
+            statement.bind((2, (!include_bots).then_some(ClickKind::Human.as_str())))?;
+            let mut res = Vec::new();
+            while let State::Row = statement.next()? {
+                res.push(CountryVisits {
+                    country: statement.read::<Option<String>, _>(0)?,
+                    visits: statement.read::<i64, _>(1)? as usize,
+                });
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn unique_visitors(
+        &self,
+        code: Option<&str>,
+        since: Option<&str>,
+        include_bots: bool,
+    ) -> StoreResult<Vec<LinkVisitors>> {
+        let (code, since) = (code.map(str::to_string), since.map(str::to_string));
+        self.run(move |handle| {
+            let statement = handle.prepare(
+                "SELECT redirect, COUNT(DISTINCT visitor) FROM log
+                    WHERE visitor IS NOT NULL AND (?1 IS NULL OR redirect = ?1)
+                    AND (?2 IS NULL OR day >= ?2) AND (?3 IS NULL OR kind = ?3)
+                    GROUP BY redirect ORDER BY redirect;",
+            )?;
+            statement.bind((1, code.as_deref()))?;
+            statement.bind((2, since.as_deref()))?;
+            statement.bind((3, (!include_bots).then_some(ClickKind::Human.as_str())))?;
+            let mut res = Vec::new();
+            while let State::Row = statement.next()? {
+                res.push(LinkVisitors {
+                    code: statement.read::<String, _>(0)?,
+                    visitors: statement.read::<i64, _>(1)? as usize,
+                });
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn count_visits(
+        &self,
+        code: Option<&str>,
+        ranges: &[Range],
+        include_bots: bool,
+    ) -> StoreResult<Vec<usize>> {
+        let (code, ranges) = (code.map(str::to_string), series::encode_ranges(ranges));
+        self.run(move |handle| {
+            // One count per range, each using the (redirect, timestamp) or timestamp index
+            let statement = handle.prepare(
+                "SELECT (SELECT COUNT(*) FROM log l
+                    WHERE l.timestamp >= json_extract(r.value, '$.start')
+                    AND l.timestamp < json_extract(r.value, '$.end')
+                    AND (?2 IS NULL OR l.redirect = ?2) AND (?3 IS NULL OR l.kind = ?3))
+                    FROM json_each(?1) r ORDER BY r.key;",
+            )?;
+            statement.bind((1, ranges.as_str()))?;
+            statement.bind((2, code.as_deref()))?;
+            statement.bind((3, (!include_bots).then_some(ClickKind::Human.as_str())))?;
+            let mut res = Vec::new();
+            while let State::Row = statement.next()? {
+                res.push(statement.read::<i64, _>(0)? as usize);
+            }
+            Ok(res)
+        })
+        .await
+    }
+
+    async fn day_salt(&self, day: &str, new_salt: &str) -> StoreResult<String> {
+        let (day, new_salt) = (day.to_string(), new_salt.to_string());
+        self.run(move |handle| {
+            transaction(handle, |handle| {
+                execute(handle, "DELETE FROM visitor_salts WHERE day < ?;", &[&day])?;
+                execute(
+                    handle,
+                    "INSERT OR IGNORE INTO visitor_salts (day, salt) VALUES (?, ?);",
+                    &[&day, &new_salt],
+                )?;
+                let statement = handle.prepare("SELECT salt FROM visitor_salts WHERE day = ?;")?;
+                statement.bind((1, day.as_str()))?;
+                statement.next()?;
+                let salt = statement.read::<String, _>(0)?;
+                while let State::Row = statement.next()? {}
+                Ok(salt)
+            })
+        })
+        .await
+    }
+
+    async fn insert_token(&self, token: &str, expiration: &str) -> StoreResult<()> {
+        let (token, expiration) = (token.to_string(), expiration.to_string());
+        self.run(move |handle| {
+            execute(
+                handle,
+                "INSERT INTO tokens (token, expiration) VALUES (?, ?);",
+                &[&token, &expiration],
+            )
+        })
+        .await
+    }
+
+    async fn token_expiration(&self, token: &str) -> StoreResult<Option<String>> {
+        let token = token.to_string();
+        self.run(move |handle| {
+            let statement = handle.prepare("SELECT expiration FROM tokens WHERE token = ?;")?;
+            statement.bind((1, token.as_str()))?;
+            if let State::Row = statement.next()? {
+                Ok(Some(statement.read::<String, _>(0)?))
+            } else {
+                Ok(None)
+            }
+        })
+        .await
+    }
+
+    async fn remove_token(&self, token: &str) -> StoreResult<()> {
+        let token = token.to_string();
+        self.run(move |handle| execute(handle, "DELETE FROM tokens WHERE token = ?;", &[&token]))
+            .await
+    }
+
+    async fn remove_expired_tokens(&self, now: &str) -> StoreResult<()> {
+        let now = now.to_string();
+        self.run(move |handle| execute(handle, "DELETE FROM tokens WHERE expiration < ?;", &[&now]))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sqlite() {
+        let path = std::env::temp_dir().join(format!("riplakish-{}.db", rand::random::<u32>()));
+        let store = SqliteStore::new(path.to_string_lossy().to_string(), 2);
+        super::super::check_store(&store).await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn partial_links() {
+        let path = std::env::temp_dir().join(format!("riplakish-{}.db", rand::random::<u32>()));
+        let filename = path.to_string_lossy().to_string();
+        let store = SqliteStore::new(filename.clone(), 1);
+        // Make the rules and the destinations fail to write
+        sqlite::open(&filename)
+            .unwrap()
+            .execute(
+                "CREATE TRIGGER no_limits BEFORE INSERT ON redirects WHEN NEW.max_visits IS NOT NULL
+                    BEGIN SELECT RAISE(ABORT, 'no limits'); END;
+                CREATE TRIGGER no_splits BEFORE INSERT ON destinations
+                    BEGIN SELECT RAISE(ABORT, 'no splits'); END;",
+            )
+            .unwrap();
+
+        let limited = NewLinkRow {
+            url: "https://a.com/".to_string(),
+            rules: Rules {
+                max_visits: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let split = NewLinkRow {
+            url: "https://a.com/".to_string(),
+            destinations: vec![NewDestination {
+                url: "https://b.com/".to_string(),
+                weight: 1,
+            }],
+            ..Default::default()
+        };
+        const NOW: &str = "2024-05-01T00:00:00Z";
+        assert!(store.insert_link_row("a", &limited, NOW).await.is_err());
+        assert!(store.insert_link_row("b", &split, NOW).await.is_err());
+        // Neither left a link without its rules or destinations behind
+        assert_eq!(store.count_links().await, Ok(0));
+        assert_eq!(store.get_target("b").await, Ok(None));
+
+        // Nor does an update, the new URL goes with the destinations that failed
+        store.insert_link("c", "https://a.com/", NOW).await.unwrap();
+        let changes = LinkChanges {
+            url: Some("https://c.com/".to_string()),
+            destinations: Some(split.destinations),
+            ..Default::default()
+        };
+        assert!(store.update_link_row("c", &changes, NOW).await.is_err());
+        assert_eq!(
+            store.get_target("c").await.unwrap().map(|t| t.url),
+            Some("https://a.com/".to_string())
+        );
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn migrations() {
+        let connection = sqlite::open(":memory:").unwrap();
+        // A database from before migrations, with a duplicate code
+        connection
+            .execute(
+                "CREATE TABLE redirects (id INTEGER PRIMARY KEY AUTOINCREMENT, url TEXT, redirect TEXT, comment TEXT);
+                INSERT INTO redirects (url, redirect)
+                    VALUES ('https://a.com', 'a'), ('https://b.com', 'a'), ('https://c.com', 'c');",
+            )
+            .unwrap();
+        let all = migrations::MIGRATIONS.len();
+        assert_eq!(migrate(&connection, true).unwrap().len(), all);
+        assert_eq!(schema_version(&connection).unwrap(), 0);
+        assert_eq!(migrate(&connection, false).unwrap().len(), all);
+        assert_eq!(
+            schema_version(&connection).unwrap(),
+            migrations::MIGRATIONS.len() as u32
+        );
+        assert!(migrate(&connection, false).unwrap().is_empty());
+
+        // Visitors always got the first link with a code, so that's the one kept
+        let mut urls = vec![];
+        connection
+            .iterate("SELECT url FROM redirects ORDER BY id;", |row| {
+                urls.extend(row.iter().filter_map(|(_, url)| url.map(str::to_string)));
+                true
+            })
+            .unwrap();
+        assert_eq!(urls, ["https://a.com", "https://c.com"]);
+
+        // A migration that fails leaves the database at the last one that worked
+        let connection = sqlite::open(":memory:").unwrap();
+        connection
+            .execute(
+                "CREATE TABLE redirects (id INTEGER PRIMARY KEY AUTOINCREMENT, url TEXT, redirect TEXT, comment TEXT, expires_at TEXT);",
+            )
+            .unwrap();
+        assert!(migrate(&connection, false).is_err());
+        assert_eq!(schema_version(&connection).unwrap(), 2);
+    }
+}